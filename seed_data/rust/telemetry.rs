@@ -1,32 +1,795 @@
 use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::num::NonZeroUsize;
+use std::pin::{pin, Pin};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, PoisonError};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::{Instant, SystemTime};
 
+use serde::{Deserialize, Serialize};
+
+use metrics::StageMetrics;
+
+mod clock;
+pub mod codec;
+pub mod config;
+pub mod dedup;
+pub mod fields;
+pub mod jsonl;
+pub mod limit;
+pub mod metrics;
+pub mod otlp;
+pub mod plugin;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+pub mod redact;
+pub mod reload;
+pub mod runtime;
+pub mod sample;
+#[cfg(feature = "script")]
+pub mod script;
+pub mod sink;
+pub mod source;
+mod value;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+pub use clock::{Clock, EventId, ManualClock, SystemClock};
+pub use metrics::{PipelineStats, StageStats};
+pub use sink::Sink;
+pub use value::Value;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+pub type Payload = HashMap<String, Value>;
+
+/// Serialized with timestamps as Unix nanoseconds. Only `kind` is required
+/// on input; a missing id or timestamp is filled in from the system clock.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TelemetryEvent {
+    #[serde(default = "fresh_id")]
+    pub id: EventId,
     pub kind: String,
-    pub payload: HashMap<String, String>,
+    /// When the event happened, as reported by whoever created it.
+    #[serde(with = "clock::unix_nanos", default = "SystemTime::now")]
+    pub timestamp: SystemTime,
+    /// When a [`Pipeline`] first received the event.
+    #[serde(with = "clock::unix_nanos::option", default)]
+    pub ingested_at: Option<SystemTime>,
+    /// `null` entries are read as absent.
+    #[serde(default, deserialize_with = "value::deserialize_payload")]
+    pub payload: Payload,
+}
+
+fn fresh_id() -> EventId {
+    SystemClock.next_id()
+}
+
+impl TelemetryEvent {
+    pub fn new(kind: impl Into<String>) -> Self {
+        Self::new_with_clock(kind, &SystemClock)
+    }
+
+    pub fn new_with_clock(kind: impl Into<String>, clock: &dyn Clock) -> Self {
+        Self {
+            id: clock.next_id(),
+            kind: kind.into(),
+            timestamp: clock.now(),
+            ingested_at: None,
+            payload: Payload::new(),
+        }
+    }
+
+    pub fn with_payload(mut self, payload: Payload) -> Self {
+        self.payload = payload;
+        self
+    }
+
+    /// Builds an event from the old all-strings payload; every entry becomes
+    /// a [`Value::String`] so nothing is reinterpreted on the way in.
+    pub fn from_string_map(kind: impl Into<String>, payload: HashMap<String, String>) -> Self {
+        let payload = payload.into_iter().map(|(k, v)| (k, Value::String(v))).collect();
+        Self::new(kind).with_payload(payload)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.payload.get(key)
+    }
+
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.get(key).and_then(Value::as_str)
+    }
+
+    pub fn insert(&mut self, key: impl Into<String>, value: impl Into<Value>) -> Option<Value> {
+        self.payload.insert(key.into(), value.into())
+    }
+}
+
+pub trait Processor: Send + Sync {
+    fn name(&self) -> &str;
+    fn process(&self, input: TelemetryEvent) -> Result<Output, ProcessError>;
+
+    /// Processes a whole batch at once. The default walks the batch through
+    /// [`Processor::process`]; override it when the work can be amortized,
+    /// e.g. one lookup round-trip for every key in the batch.
+    fn process_batch(
+        &self,
+        input: Vec<TelemetryEvent>,
+    ) -> Result<Vec<TelemetryEvent>, ProcessError> {
+        let mut out = Vec::with_capacity(input.len());
+        for evt in input {
+            let kind = evt.kind.clone();
+            self.process(evt).map_err(|e| e.with_kind(kind))?.append_to(&mut out);
+        }
+        Ok(out)
+    }
 }
 
-pub trait Processor {
+/// A processor whose work may suspend, e.g. on a cache lookup or a socket write.
+pub trait AsyncProcessor: Send + Sync {
     fn name(&self) -> &str;
-    fn process(&self, input: TelemetryEvent) -> TelemetryEvent;
+    fn process<'a>(&'a self, input: TelemetryEvent) -> BoxFuture<'a, Result<Output, ProcessError>>;
+}
+
+/// What a processor hands on for a single input event: nothing (the event
+/// is filtered out), the event itself, or several events split from it.
+pub enum Output {
+    Drop,
+    One(TelemetryEvent),
+    Many(Vec<TelemetryEvent>),
+}
+
+impl Output {
+    fn append_to(self, out: &mut Vec<TelemetryEvent>) {
+        match self {
+            Output::Drop => {}
+            Output::One(evt) => out.push(evt),
+            Output::Many(evts) => out.extend(evts),
+        }
+    }
+}
+
+impl From<TelemetryEvent> for Output {
+    fn from(evt: TelemetryEvent) -> Self {
+        Output::One(evt)
+    }
+}
+
+impl From<Option<TelemetryEvent>> for Output {
+    fn from(evt: Option<TelemetryEvent>) -> Self {
+        evt.map_or(Output::Drop, Output::One)
+    }
+}
+
+impl From<Vec<TelemetryEvent>> for Output {
+    fn from(evts: Vec<TelemetryEvent>) -> Self {
+        Output::Many(evts)
+    }
+}
+
+/// Failure reported by a single processor.
+#[derive(Debug)]
+pub struct ProcessError {
+    message: String,
+    kind: Option<String>,
+    source: Option<Box<dyn Error + Send + Sync>>,
+}
+
+impl ProcessError {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self { message: message.into(), kind: None, source: None }
+    }
+
+    pub fn with_source(
+        message: impl Into<String>,
+        source: impl Into<Box<dyn Error + Send + Sync>>,
+    ) -> Self {
+        Self { message: message.into(), kind: None, source: Some(source.into()) }
+    }
+
+    /// Names the kind of the event that failed. Only needed from
+    /// [`Processor::process_batch`], where the pipeline cannot tell which
+    /// event of the batch was at fault.
+    pub fn with_kind(mut self, kind: impl Into<String>) -> Self {
+        self.kind.get_or_insert_with(|| kind.into());
+        self
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn kind(&self) -> Option<&str> {
+        self.kind.as_deref()
+    }
+}
+
+impl fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for ProcessError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source.as_deref().map(|e| e as &(dyn Error + 'static))
+    }
+}
+
+/// A [`ProcessError`] annotated with where in the pipeline it happened.
+#[derive(Debug)]
+pub struct PipelineError {
+    /// Name of the failing processor or sink.
+    pub processor: String,
+    /// Position of the failing stage; sinks count on from the last
+    /// processor.
+    pub index: usize,
+    /// Kind of the failing event, or [`BATCH_KIND`] when a batch of several
+    /// events failed as a whole without naming one.
+    pub kind: String,
+    pub source: ProcessError,
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "stage `{}` (#{}) failed on `{}` event: {}",
+            self.processor, self.index, self.kind, self.source
+        )
+    }
+}
+
+impl Error for PipelineError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Whether [`Pipeline::run_parallel`] keeps results in input order or hands
+/// them back as workers finish.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputOrder {
+    Ordered,
+    Unordered,
+}
+
+#[derive(Debug, Clone)]
+pub struct ParallelOptions {
+    pub workers: NonZeroUsize,
+    /// Events handed to a worker at a time, each chunk passing the
+    /// processors as one batch, as in [`Pipeline::run_batch`].
+    pub chunk_size: NonZeroUsize,
+    pub order: OutputOrder,
+}
+
+impl Default for ParallelOptions {
+    fn default() -> Self {
+        Self {
+            workers: thread::available_parallelism().unwrap_or(NonZeroUsize::MIN),
+            chunk_size: NonZeroUsize::new(256).unwrap(),
+            order: OutputOrder::Ordered,
+        }
+    }
+}
+
+pub const BATCH_KIND: &str = "*";
+
+enum Stage {
+    Sync(Box<dyn Processor>),
+    Async(Box<dyn AsyncProcessor>),
+}
+
+impl Stage {
+    fn name(&self) -> &str {
+        match self {
+            Stage::Sync(p) => p.name(),
+            Stage::Async(p) => p.name(),
+        }
+    }
+
+    fn process_batch(
+        &self,
+        input: Vec<TelemetryEvent>,
+    ) -> Result<Vec<TelemetryEvent>, ProcessError> {
+        match self {
+            Stage::Sync(p) => p.process_batch(input),
+            Stage::Async(p) => {
+                let mut out = Vec::with_capacity(input.len());
+                for evt in input {
+                    let kind = evt.kind.clone();
+                    block_on(p.process(evt)).map_err(|e| e.with_kind(kind))?.append_to(&mut out);
+                }
+                Ok(out)
+            }
+        }
+    }
+
+    fn process_observed(
+        &self,
+        metrics: &StageMetrics,
+        input: Vec<TelemetryEvent>,
+    ) -> Result<Vec<TelemetryEvent>, ProcessError> {
+        let (events_in, start) = (input.len(), Instant::now());
+        let out = self.process_batch(input);
+        metrics.record(events_in, out.as_ref().ok().map(Vec::len), start.elapsed());
+        out
+    }
+
+    fn fail(&self, index: usize, kind: String, source: ProcessError) -> PipelineError {
+        let kind = source.kind.clone().unwrap_or(kind);
+        PipelineError { processor: self.name().to_owned(), index, kind, source }
+    }
 }
 
 pub struct Pipeline {
-    processors: Vec<Box<dyn Processor>>,
+    processors: Vec<Stage>,
+    sinks: Vec<Mutex<Box<dyn Sink>>>,
+    /// One entry per processor, then one per sink.
+    metrics: Vec<Arc<StageMetrics>>,
+    clock: Arc<dyn Clock>,
 }
 
 impl Pipeline {
     pub fn new() -> Self {
-        Self { processors: Vec::new() }
+        Self::with_clock(Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self { processors: Vec::new(), sinks: Vec::new(), metrics: Vec::new(), clock }
+    }
+
+    pub fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    fn ingest(&self, evt: &mut TelemetryEvent) {
+        evt.ingested_at.get_or_insert_with(|| self.clock.now());
     }
 
     pub fn add(&mut self, processor: Box<dyn Processor>) {
-        self.processors.push(processor);
+        self.add_stage(Stage::Sync(processor));
+    }
+
+    pub fn add_async(&mut self, processor: Box<dyn AsyncProcessor>) {
+        self.add_stage(Stage::Async(processor));
+    }
+
+    fn add_stage(&mut self, stage: Stage) {
+        let metrics = Arc::new(StageMetrics::new(stage.name()));
+        self.metrics.insert(self.processors.len(), metrics);
+        self.processors.push(stage);
+    }
+
+    /// Adds a terminal stage. Every run writes its surviving events to each
+    /// sink in turn before returning them.
+    pub fn add_sink(&mut self, sink: Box<dyn Sink>) {
+        self.metrics.push(Arc::new(StageMetrics::new(sink.name())));
+        self.sinks.push(Mutex::new(sink));
+    }
+
+    /// A snapshot of every stage's counters since the pipeline was built.
+    pub fn stats(&self) -> PipelineStats {
+        PipelineStats::collect(self.metrics.iter().map(|m| &**m))
+    }
+
+    pub fn flush(&self) -> Result<(), PipelineError> {
+        self.each_sink(&[], |sink| sink.flush())
+    }
+
+    /// Flushes and closes every sink. Runs after this fail once they reach
+    /// a sink.
+    pub fn close(&self) -> Result<(), PipelineError> {
+        self.each_sink(&[], |sink| sink.close())
+    }
+
+    fn deliver(&self, events: &[TelemetryEvent]) -> Result<(), PipelineError> {
+        if events.is_empty() {
+            return Ok(());
+        }
+        self.each_sink(events, |sink| sink.write(events))
+    }
+
+    fn each_sink(
+        &self,
+        events: &[TelemetryEvent],
+        mut op: impl FnMut(&mut dyn Sink) -> io::Result<()>,
+    ) -> Result<(), PipelineError> {
+        for (j, sink) in self.sinks.iter().enumerate() {
+            let index = self.processors.len() + j;
+            let mut sink = sink.lock().unwrap_or_else(PoisonError::into_inner);
+            let start = Instant::now();
+            let res = op(&mut **sink);
+            if !events.is_empty() {
+                let written = res.as_ref().ok().map(|_| events.len());
+                self.metrics[index].record(events.len(), written, start.elapsed());
+            }
+            res.map_err(|e| PipelineError {
+                processor: sink.name().to_owned(),
+                index,
+                kind: match events {
+                    [only] => only.kind.clone(),
+                    _ => BATCH_KIND.to_owned(),
+                },
+                source: ProcessError::with_source("sink failed", e),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Runs every stage on the calling thread. Async stages are polled to
+    /// completion in place, so processors that rely on a runtime's reactor
+    /// should be driven through [`Pipeline::run_async`] instead.
+    ///
+    /// Each event a stage emits is fed through the remaining stages, so the
+    /// result holds whatever survived the whole chain, in emission order.
+    pub fn run(&self, evt: TelemetryEvent) -> Result<Vec<TelemetryEvent>, PipelineError> {
+        self.run_batch(vec![evt])
+    }
+
+    /// Runs a batch stage by stage: every sync processor sees the whole batch
+    /// through [`Processor::process_batch`] before the next one starts.
+    pub fn run_batch<I>(&self, events: I) -> Result<Vec<TelemetryEvent>, PipelineError>
+    where
+        I: IntoIterator<Item = TelemetryEvent>,
+    {
+        let events = self.process_chunk(events.into_iter().collect())?;
+        self.deliver(&events)?;
+        Ok(events)
+    }
+
+    /// Everything [`Pipeline::run_batch`] does short of writing to the sinks.
+    fn process_chunk(
+        &self,
+        mut events: Vec<TelemetryEvent>,
+    ) -> Result<Vec<TelemetryEvent>, PipelineError> {
+        events.iter_mut().for_each(|evt| self.ingest(evt));
+        for (i, p) in self.processors.iter().enumerate() {
+            if events.is_empty() {
+                break;
+            }
+            let kind = match &events[..] {
+                [only] => only.kind.clone(),
+                _ => BATCH_KIND.to_owned(),
+            };
+            events =
+                p.process_observed(&self.metrics[i], events).map_err(|e| p.fail(i, kind, e))?;
+        }
+        Ok(events)
+    }
+
+    /// Spreads `events` over a pool of scoped worker threads. Every event
+    /// still passes the processors in order; only distinct events run
+    /// concurrently. The first failure stops workers from picking up more
+    /// chunks and is returned once the in-flight ones finish.
+    ///
+    /// Sinks see the reassembled result in one write, in the same order as
+    /// the returned events, and nothing at all when a chunk failed.
+    pub fn run_parallel(
+        &self,
+        events: Vec<TelemetryEvent>,
+        opts: &ParallelOptions,
+    ) -> Result<Vec<TelemetryEvent>, PipelineError> {
+        let mut chunks = Vec::new();
+        let mut rest = events.into_iter().peekable();
+        while rest.peek().is_some() {
+            chunks.push(rest.by_ref().take(opts.chunk_size.get()).collect::<Vec<_>>());
+        }
+        let total = chunks.len();
+        let workers = opts.workers.get().min(total);
+        let jobs = Mutex::new(chunks.into_iter().enumerate());
+        let failed = AtomicBool::new(false);
+        let (tx, rx) = mpsc::channel();
+
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                let tx = tx.clone();
+                let (jobs, failed) = (&jobs, &failed);
+                scope.spawn(move || {
+                    while !failed.load(Ordering::Relaxed) {
+                        let Some((idx, chunk)) = jobs.lock().unwrap().next() else { break };
+                        let res = self.process_chunk(chunk);
+                        if res.is_err() {
+                            failed.store(true, Ordering::Relaxed);
+                        }
+                        if tx.send((idx, res)).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        drop(tx);
+
+        let mut slots: Vec<Option<Vec<TelemetryEvent>>> = Vec::new();
+        let mut out = Vec::new();
+        if opts.order == OutputOrder::Ordered {
+            slots.resize_with(total, || None);
+        }
+        for (idx, res) in rx {
+            match opts.order {
+                OutputOrder::Ordered => slots[idx] = Some(res?),
+                OutputOrder::Unordered => out.extend(res?),
+            }
+        }
+        out.extend(slots.into_iter().flatten().flatten());
+        self.deliver(&out)?;
+        Ok(out)
+    }
+
+    pub async fn run_async(
+        &self,
+        mut evt: TelemetryEvent,
+    ) -> Result<Vec<TelemetryEvent>, PipelineError> {
+        self.ingest(&mut evt);
+        let mut events = vec![evt];
+        for (i, p) in self.processors.iter().enumerate() {
+            let mut next = Vec::with_capacity(events.len());
+            for evt in events {
+                let (kind, start, before) = (evt.kind.clone(), Instant::now(), next.len());
+                let out = match p {
+                    Stage::Sync(p) => p.process(evt),
+                    Stage::Async(p) => p.process(evt).await,
+                };
+                let out = out.map(|out| out.append_to(&mut next));
+                self.metrics[i].record(
+                    1,
+                    out.is_ok().then(|| next.len() - before),
+                    start.elapsed(),
+                );
+                out.map_err(|e| p.fail(i, kind, e))?;
+            }
+            if next.is_empty() {
+                return Ok(next);
+            }
+            events = next;
+        }
+        self.deliver(&events)?;
+        Ok(events)
+    }
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+fn block_on<F: Future>(fut: F) -> F::Output {
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut fut = pin!(fut);
+    loop {
+        match fut.as_mut().poll(&mut cx) {
+            Poll::Ready(out) => return out,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    /// Duplicates every event, drops `drop` events and fails on `bad` ones.
+    struct Split;
+
+    impl Processor for Split {
+        fn name(&self) -> &str {
+            "split"
+        }
+
+        fn process(&self, input: TelemetryEvent) -> Result<Output, ProcessError> {
+            match input.kind.as_str() {
+                "bad" => Err(ProcessError::new("bad event")),
+                "drop" => Ok(Output::Drop),
+                _ => Ok(vec![input.clone(), input].into()),
+            }
+        }
     }
 
-    pub fn run(&self, mut evt: TelemetryEvent) -> TelemetryEvent {
-        for p in &self.processors {
-            evt = p.process(evt);
+    /// Counts the events it has seen into a `seq` field.
+    struct Number(AtomicUsize);
+
+    impl AsyncProcessor for Number {
+        fn name(&self) -> &str {
+            "number"
+        }
+
+        fn process<'a>(
+            &'a self,
+            mut input: TelemetryEvent,
+        ) -> BoxFuture<'a, Result<Output, ProcessError>> {
+            Box::pin(async move {
+                let seq = self.0.fetch_add(1, Ordering::Relaxed);
+                input.insert("seq", seq as i64);
+                Ok(input.into())
+            })
+        }
+    }
+
+    /// Fails a whole batch without saying which event was at fault.
+    struct RejectBatches;
+
+    impl Processor for RejectBatches {
+        fn name(&self) -> &str {
+            "reject"
+        }
+
+        fn process(&self, input: TelemetryEvent) -> Result<Output, ProcessError> {
+            Ok(input.into())
+        }
+
+        fn process_batch(
+            &self,
+            _: Vec<TelemetryEvent>,
+        ) -> Result<Vec<TelemetryEvent>, ProcessError> {
+            Err(ProcessError::new("rejected"))
+        }
+    }
+
+    /// Records the kinds in each write, failing them all once `fail` is set.
+    struct Collect {
+        writes: Arc<Mutex<Vec<Vec<String>>>>,
+        fail: bool,
+    }
+
+    impl Sink for Collect {
+        fn name(&self) -> &str {
+            "collect"
+        }
+
+        fn write(&mut self, events: &[TelemetryEvent]) -> io::Result<()> {
+            if self.fail {
+                return Err(io::Error::other("sink down"));
+            }
+            self.writes.lock().unwrap().push(events.iter().map(|e| e.kind.clone()).collect());
+            Ok(())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn close(&mut self) -> io::Result<()> {
+            Ok(())
         }
-        evt
+    }
+
+    fn kinds(events: &[TelemetryEvent]) -> Vec<&str> {
+        events.iter().map(|e| e.kind.as_str()).collect()
+    }
+
+    fn collecting(pipeline: &mut Pipeline) -> Arc<Mutex<Vec<Vec<String>>>> {
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        pipeline.add_sink(Box::new(Collect { writes: writes.clone(), fail: false }));
+        writes
+    }
+
+    #[test]
+    fn outputs_drop_and_split_events() {
+        let mut p = Pipeline::new();
+        p.add(Box::new(Split));
+        p.add(Box::new(Split));
+        assert_eq!(kinds(&p.run(TelemetryEvent::new("a")).unwrap()), ["a"; 4]);
+        assert!(p.run(TelemetryEvent::new("drop")).unwrap().is_empty());
+
+        let batch = ["a", "drop", "b"].map(TelemetryEvent::new);
+        assert_eq!(kinds(&p.run_batch(batch).unwrap()), ["a", "a", "a", "a", "b", "b", "b", "b"]);
+        assert!(p.run(TelemetryEvent::new("x")).unwrap().iter().all(|e| e.ingested_at.is_some()));
+    }
+
+    #[test]
+    fn sync_and_async_stages_mix() {
+        let mut p = Pipeline::new();
+        p.add(Box::new(Split));
+        p.add_async(Box::new(Number(AtomicUsize::new(0))));
+        p.add(Box::new(Split));
+        let seqs = |events: Vec<TelemetryEvent>| -> Vec<i64> {
+            events.iter().map(|e| e.get("seq").and_then(Value::as_i64).unwrap()).collect()
+        };
+        assert_eq!(seqs(p.run(TelemetryEvent::new("a")).unwrap()), [0, 0, 1, 1]);
+        assert_eq!(seqs(block_on(p.run_async(TelemetryEvent::new("a"))).unwrap()), [2, 2, 3, 3]);
+        let batch = ["a", "drop", "b"].map(TelemetryEvent::new);
+        assert_eq!(seqs(p.run_batch(batch).unwrap()), [4, 4, 5, 5, 6, 6, 7, 7]);
+    }
+
+    #[test]
+    fn errors_name_the_stage_and_event() {
+        let mut p = Pipeline::new();
+        p.add_async(Box::new(Number(AtomicUsize::new(0))));
+        p.add(Box::new(Split));
+        let err = p.run(TelemetryEvent::new("bad")).err().unwrap();
+        assert_eq!((err.processor.as_str(), err.index, err.kind.as_str()), ("split", 1, "bad"));
+        assert_eq!(err.to_string(), "stage `split` (#1) failed on `bad` event: bad event");
+
+        let err = block_on(p.run_async(TelemetryEvent::new("bad"))).err().unwrap();
+        assert_eq!((err.index, err.kind.as_str()), (1, "bad"));
+
+        // The default `process_batch` names the event that failed.
+        let err = p.run_batch(["a", "bad", "c"].map(TelemetryEvent::new)).err().unwrap();
+        assert_eq!((err.index, err.kind.as_str()), (1, "bad"));
+
+        p.add(Box::new(RejectBatches));
+        let err = p.run_batch(["a", "b"].map(TelemetryEvent::new)).err().unwrap();
+        assert_eq!(
+            (err.processor.as_str(), err.index, err.kind.as_str()),
+            ("reject", 2, BATCH_KIND)
+        );
+
+        let mut p = Pipeline::new();
+        p.add(Box::new(Split));
+        p.add_sink(Box::new(Collect { writes: Default::default(), fail: true }));
+        let err = p.run(TelemetryEvent::new("a")).err().unwrap();
+        assert_eq!(
+            (err.processor.as_str(), err.index, err.kind.as_str()),
+            ("collect", 1, BATCH_KIND)
+        );
+        assert!(err.source().is_some());
+    }
+
+    #[test]
+    fn default_process_batch_walks_the_batch() {
+        let out = Split.process_batch(["a", "drop", "b"].map(TelemetryEvent::new).into()).unwrap();
+        assert_eq!(kinds(&out), ["a", "a", "b", "b"]);
+        let err = Split.process_batch(["a", "bad"].map(TelemetryEvent::new).into()).err().unwrap();
+        assert_eq!((err.message(), err.kind()), ("bad event", Some("bad")));
+        // A kind set by the processor itself is kept.
+        let err = ProcessError::new("x").with_kind("first").with_kind("second");
+        assert_eq!(err.kind(), Some("first"));
+    }
+
+    #[test]
+    fn parallel_runs_keep_or_relax_order_and_deliver_once() {
+        let mut p = Pipeline::new();
+        p.add(Box::new(Split));
+        let writes = collecting(&mut p);
+        let events = || (0..1000).map(|i| TelemetryEvent::new(i.to_string())).collect::<Vec<_>>();
+        let expected: Vec<String> =
+            (0..1000).flat_map(|i| [i.to_string(), i.to_string()]).collect();
+        let mut opts = ParallelOptions {
+            workers: NonZeroUsize::new(4).unwrap(),
+            chunk_size: NonZeroUsize::new(7).unwrap(),
+            order: OutputOrder::Ordered,
+        };
+
+        let out = p.run_parallel(events(), &opts).unwrap();
+        assert_eq!(kinds(&out), expected);
+        assert_eq!(writes.lock().unwrap().concat(), expected);
+        assert_eq!(writes.lock().unwrap().len(), 1);
+
+        writes.lock().unwrap().clear();
+        opts.order = OutputOrder::Unordered;
+        let out = p.run_parallel(events(), &opts).unwrap();
+        let written = writes.lock().unwrap().concat();
+        assert_eq!(kinds(&out), written);
+        let mut sorted = written;
+        sorted.sort();
+        let mut want = expected;
+        want.sort();
+        assert_eq!(sorted, want);
+
+        writes.lock().unwrap().clear();
+        let mut events = events();
+        events[500] = TelemetryEvent::new("bad");
+        let err = p.run_parallel(events, &opts).err().unwrap();
+        assert_eq!((err.index, err.kind.as_str()), (0, "bad"));
+        assert!(writes.lock().unwrap().is_empty());
+        assert!(p.run_parallel(Vec::new(), &opts).unwrap().is_empty());
     }
 }
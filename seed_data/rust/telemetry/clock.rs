@@ -0,0 +1,213 @@
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// Unique event identifier, rendered as 32 hex digits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct EventId(u128);
+
+impl EventId {
+    pub const fn from_u128(raw: u128) -> Self {
+        Self(raw)
+    }
+
+    pub const fn as_u128(self) -> u128 {
+        self.0
+    }
+}
+
+impl fmt::Display for EventId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:032x}", self.0)
+    }
+}
+
+impl Serialize for EventId {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for EventId {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let hex = String::deserialize(d)?;
+        u128::from_str_radix(&hex, 16)
+            .map(EventId)
+            .map_err(|_| de::Error::invalid_value(de::Unexpected::Str(&hex), &"32 hex digits"))
+    }
+}
+
+/// Serde adapter writing a [`SystemTime`] as integer nanoseconds since the
+/// Unix epoch.
+pub(crate) mod unix_nanos {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(t: &SystemTime, s: S) -> Result<S::Ok, S::Error> {
+        let nanos = t.duration_since(UNIX_EPOCH).map_err(serde::ser::Error::custom)?.as_nanos();
+        s.serialize_u64(u64::try_from(nanos).map_err(serde::ser::Error::custom)?)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<SystemTime, D::Error> {
+        Ok(UNIX_EPOCH + Duration::from_nanos(u64::deserialize(d)?))
+    }
+
+    pub mod option {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(t: &Option<SystemTime>, s: S) -> Result<S::Ok, S::Error> {
+            match t {
+                Some(t) => s.serialize_some(&Nanos(*t)),
+                None => s.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            d: D,
+        ) -> Result<Option<SystemTime>, D::Error> {
+            Ok(Option::<Nanos>::deserialize(d)?.map(|n| n.0))
+        }
+
+        struct Nanos(SystemTime);
+
+        impl Serialize for Nanos {
+            fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+                super::serialize(&self.0, s)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for Nanos {
+            fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+                super::deserialize(d).map(Nanos)
+            }
+        }
+    }
+}
+
+/// Source of timestamps and event ids. Everything that stamps events takes
+/// one, so tests can swap in a [`ManualClock`].
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+
+    /// Time-ordered id: milliseconds since the epoch in the top 48 bits,
+    /// then a per-process random tag and a process-wide counter.
+    fn next_id(&self) -> EventId {
+        static SEQ: AtomicU64 = AtomicU64::new(0);
+        static TAG: OnceLock<u16> = OnceLock::new();
+        let tag = *TAG.get_or_init(|| RandomState::new().hash_one(std::process::id()) as u16);
+        let millis = self.now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        let seq = SEQ.fetch_add(1, Ordering::Relaxed);
+        EventId((millis & 0xffff_ffff_ffff) << 80 | u128::from(tag) << 64 | u128::from(seq))
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when told to and hands out ids 1, 2, 3, ...
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<SystemTime>,
+    seq: AtomicU64,
+}
+
+impl ManualClock {
+    pub fn new(start: SystemTime) -> Self {
+        Self { now: Mutex::new(start), seq: AtomicU64::new(0) }
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new(UNIX_EPOCH)
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+
+    fn next_id(&self) -> EventId {
+        EventId(u128::from(self.seq.fetch_add(1, Ordering::Relaxed) + 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::TelemetryEvent;
+
+    #[test]
+    fn manual_clock_is_deterministic() {
+        let clock = ManualClock::default();
+        let ids: Vec<_> = (0..3).map(|_| clock.next_id().as_u128()).collect();
+        assert_eq!(ids, [1, 2, 3]);
+
+        assert_eq!(clock.now(), UNIX_EPOCH);
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(clock.now(), UNIX_EPOCH + Duration::from_secs(5));
+        clock.set(UNIX_EPOCH + Duration::from_secs(1));
+        assert_eq!(clock.now(), UNIX_EPOCH + Duration::from_secs(1));
+
+        let evt = TelemetryEvent::new_with_clock("k", &clock);
+        assert_eq!(evt.id, EventId::from_u128(4));
+        assert_eq!(evt.timestamp, UNIX_EPOCH + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn system_ids_are_unique_and_time_ordered() {
+        let clock = ManualClock::new(UNIX_EPOCH + Duration::from_millis(7));
+        let early = Clock::next_id(&SystemClockAt(&clock));
+        clock.advance(Duration::from_millis(1));
+        let late = Clock::next_id(&SystemClockAt(&clock));
+        assert!(early < late);
+        assert_eq!(early.as_u128() >> 80, 7);
+
+        let ids: std::collections::HashSet<_> = (0..1000).map(|_| SystemClock.next_id()).collect();
+        assert_eq!(ids.len(), 1000);
+    }
+
+    /// Uses the default [`Clock::next_id`] with a controlled `now`.
+    struct SystemClockAt<'a>(&'a ManualClock);
+
+    impl Clock for SystemClockAt<'_> {
+        fn now(&self) -> SystemTime {
+            self.0.now()
+        }
+    }
+
+    #[test]
+    fn ids_and_timestamps_round_trip() {
+        let id = EventId::from_u128(0xab);
+        assert_eq!(id.to_string(), format!("{:032x}", 0xab));
+        let json = serde_json::to_string(&id).unwrap();
+        assert_eq!(serde_json::from_str::<EventId>(&json).unwrap(), id);
+        assert!(serde_json::from_str::<EventId>("\"not hex\"").is_err());
+
+        let clock = ManualClock::new(UNIX_EPOCH + Duration::from_nanos(1_500));
+        let evt = TelemetryEvent::new_with_clock("k", &clock);
+        let json = serde_json::to_value(&evt).unwrap();
+        assert_eq!(json["timestamp"], 1_500);
+        assert_eq!(json["ingested_at"], serde_json::Value::Null);
+        assert_eq!(serde_json::from_value::<TelemetryEvent>(json).unwrap(), evt);
+    }
+}
@@ -0,0 +1,368 @@
+//! Binary encodings of [`TelemetryEvent`] and a length-prefixed framing to
+//! carry them over byte streams.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+use super::TelemetryEvent;
+
+/// Frames larger than this are rejected unless a reader is told otherwise.
+pub const DEFAULT_MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum CodecError {
+    Io(io::Error),
+    Encode { codec: &'static str, message: String },
+    Decode { codec: &'static str, message: String },
+    FrameTooLarge { len: usize, max: usize },
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Io(e) => write!(f, "i/o error: {e}"),
+            CodecError::Encode { codec, message } => write!(f, "{codec} encode failed: {message}"),
+            CodecError::Decode { codec, message } => write!(f, "{codec} decode failed: {message}"),
+            CodecError::FrameTooLarge { len, max } => {
+                write!(f, "frame of {len} bytes exceeds the {max} byte limit")
+            }
+        }
+    }
+}
+
+impl Error for CodecError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CodecError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CodecError {
+    fn from(e: io::Error) -> Self {
+        CodecError::Io(e)
+    }
+}
+
+/// How a byte stream is cut into records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// One record per `\n`-terminated line; blank lines are ignored.
+    Lines,
+    /// A 4-byte big-endian length before each record, as written by
+    /// [`FramedWriter`].
+    LengthPrefixed,
+}
+
+/// Turns a single event into bytes and back. Framing is left to the caller,
+/// see [`FramedWriter`] and [`FramedReader`].
+pub trait EventCodec: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn encode(&self, evt: &TelemetryEvent, out: &mut Vec<u8>) -> Result<(), CodecError>;
+    fn decode(&self, bytes: &[u8]) -> Result<TelemetryEvent, CodecError>;
+}
+
+macro_rules! forward_codec {
+    ($($ty:ty),*) => {$(
+        impl<C: EventCodec + ?Sized> EventCodec for $ty {
+            fn name(&self) -> &'static str {
+                (**self).name()
+            }
+
+            fn encode(&self, evt: &TelemetryEvent, out: &mut Vec<u8>) -> Result<(), CodecError> {
+                (**self).encode(evt, out)
+            }
+
+            fn decode(&self, bytes: &[u8]) -> Result<TelemetryEvent, CodecError> {
+                (**self).decode(bytes)
+            }
+        }
+    )*};
+}
+
+forward_codec!(&C, Box<C>, Arc<C>);
+
+/// MessagePack with structs written as maps, so field order does not matter
+/// to other implementations.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MessagePack;
+
+impl EventCodec for MessagePack {
+    fn name(&self) -> &'static str {
+        "msgpack"
+    }
+
+    fn encode(&self, evt: &TelemetryEvent, out: &mut Vec<u8>) -> Result<(), CodecError> {
+        rmp_serde::encode::write_named(out, evt)
+            .map_err(|e| CodecError::Encode { codec: self.name(), message: e.to_string() })
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<TelemetryEvent, CodecError> {
+        rmp_serde::from_slice(bytes)
+            .map_err(|e| CodecError::Decode { codec: self.name(), message: e.to_string() })
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Cbor;
+
+impl EventCodec for Cbor {
+    fn name(&self) -> &'static str {
+        "cbor"
+    }
+
+    fn encode(&self, evt: &TelemetryEvent, out: &mut Vec<u8>) -> Result<(), CodecError> {
+        ciborium::into_writer(evt, out)
+            .map_err(|e| CodecError::Encode { codec: self.name(), message: e.to_string() })
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<TelemetryEvent, CodecError> {
+        ciborium::from_reader(bytes)
+            .map_err(|e| CodecError::Decode { codec: self.name(), message: e.to_string() })
+    }
+}
+
+/// The JSON Lines encoding as a codec. JSON has no byte strings, so
+/// [`Value::Bytes`](super::Value::Bytes) comes back as a list of integers.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Json;
+
+impl EventCodec for Json {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn encode(&self, evt: &TelemetryEvent, out: &mut Vec<u8>) -> Result<(), CodecError> {
+        serde_json::to_writer(out, evt)
+            .map_err(|e| CodecError::Encode { codec: self.name(), message: e.to_string() })
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<TelemetryEvent, CodecError> {
+        serde_json::from_slice(bytes)
+            .map_err(|e| CodecError::Decode { codec: self.name(), message: e.to_string() })
+    }
+}
+
+/// Writes each event as a 4-byte big-endian length followed by its encoding.
+pub struct FramedWriter<W, C> {
+    out: W,
+    codec: C,
+    buf: Vec<u8>,
+}
+
+impl<W: Write, C: EventCodec> FramedWriter<W, C> {
+    pub fn new(out: W, codec: C) -> Self {
+        Self { out, codec, buf: Vec::new() }
+    }
+
+    pub fn write(&mut self, evt: &TelemetryEvent) -> Result<(), CodecError> {
+        self.buf.clear();
+        self.codec.encode(evt, &mut self.buf)?;
+        let len = u32::try_from(self.buf.len()).map_err(|_| CodecError::FrameTooLarge {
+            len: self.buf.len(),
+            max: u32::MAX as usize,
+        })?;
+        self.out.write_all(&len.to_be_bytes())?;
+        self.out.write_all(&self.buf)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+/// Reads frames written by [`FramedWriter`]. End of input on a frame
+/// boundary ends the iteration; anywhere else it is an error. A frame that
+/// fails to decode is reported and skipped, while I/O errors and oversized
+/// frames end the iteration since the stream can no longer be trusted.
+pub struct FramedReader<R, C> {
+    input: R,
+    codec: C,
+    max_frame_len: usize,
+    buf: Vec<u8>,
+    done: bool,
+}
+
+impl<R: Read, C: EventCodec> FramedReader<R, C> {
+    pub fn new(input: R, codec: C) -> Self {
+        Self { input, codec, max_frame_len: DEFAULT_MAX_FRAME_LEN, buf: Vec::new(), done: false }
+    }
+
+    pub fn max_frame_len(mut self, max: usize) -> Self {
+        self.max_frame_len = max;
+        self
+    }
+
+    fn read_frame(&mut self) -> Result<bool, CodecError> {
+        let mut len = [0u8; 4];
+        let mut filled = 0;
+        while filled < len.len() {
+            match self.input.read(&mut len[filled..]) {
+                Ok(0) if filled == 0 => return Ok(false),
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        if len > self.max_frame_len {
+            return Err(CodecError::FrameTooLarge { len, max: self.max_frame_len });
+        }
+        self.buf.resize(len, 0);
+        self.input.read_exact(&mut self.buf)?;
+        Ok(true)
+    }
+}
+
+impl<R: Read, C: EventCodec> Iterator for FramedReader<R, C> {
+    type Item = Result<TelemetryEvent, CodecError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.read_frame() {
+            Ok(true) => Some(self.codec.decode(&self.buf)),
+            Ok(false) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+    use crate::telemetry::{Clock, ManualClock, Payload, Value};
+
+    /// Codecs that must be lossless. [`Json`] is left out since it cannot
+    /// carry bytes.
+    fn codecs() -> Vec<Box<dyn EventCodec>> {
+        vec![Box::new(MessagePack), Box::new(Cbor)]
+    }
+
+    fn shapes() -> Vec<(&'static str, Value)> {
+        let nested: HashMap<String, Value> = [
+            (
+                "inner".to_owned(),
+                Value::List(vec![Value::Int(1), "two".into(), Value::Bool(false)]),
+            ),
+            ("deeper".to_owned(), Value::Map([("x".to_owned(), Value::Float(-0.25))].into())),
+        ]
+        .into();
+        vec![
+            ("string", Value::from("hello")),
+            ("empty_string", Value::from("")),
+            ("unicode", Value::from("héllo ✓")),
+            ("int", Value::Int(42)),
+            ("negative", Value::Int(-7)),
+            ("int_min", Value::Int(i64::MIN)),
+            ("int_max", Value::Int(i64::MAX)),
+            ("float", Value::Float(3.5)),
+            ("whole_float", Value::Float(2.0)),
+            ("bool", Value::Bool(true)),
+            ("bytes", Value::Bytes(vec![0, 1, 0xfe, 0xff])),
+            ("empty_bytes", Value::Bytes(Vec::new())),
+            ("list", Value::List(vec![Value::Int(1), Value::Bytes(vec![9])])),
+            ("empty_list", Value::List(Vec::new())),
+            ("map", Value::Map(nested)),
+            ("empty_map", Value::Map(HashMap::new())),
+        ]
+    }
+
+    fn event(payload: Payload) -> TelemetryEvent {
+        let clock = ManualClock::new(UNIX_EPOCH + Duration::from_nanos(1_700_000_000_123_456_789));
+        let mut evt = TelemetryEvent::new_with_clock("conformance", &clock).with_payload(payload);
+        clock.advance(Duration::from_millis(5));
+        evt.ingested_at = Some(clock.now());
+        evt
+    }
+
+    #[test]
+    fn every_shape_round_trips_through_every_codec() {
+        for codec in codecs() {
+            for (name, value) in shapes() {
+                let evt = event([(name.to_owned(), value)].into());
+                let mut bytes = Vec::new();
+                codec.encode(&evt, &mut bytes).unwrap();
+                let back = codec.decode(&bytes).unwrap();
+                assert_eq!(back, evt, "{} lost `{name}`", codec.name());
+            }
+        }
+    }
+
+    #[test]
+    fn all_shapes_in_one_payload_round_trip() {
+        let evt = event(shapes().into_iter().map(|(k, v)| (k.to_owned(), v)).collect());
+        for codec in codecs() {
+            let mut bytes = Vec::new();
+            codec.encode(&evt, &mut bytes).unwrap();
+            assert_eq!(codec.decode(&bytes).unwrap(), evt, "{}", codec.name());
+        }
+    }
+
+    #[test]
+    fn framed_stream_round_trips() {
+        let events: Vec<_> =
+            shapes().into_iter().map(|(k, v)| event([(k.to_owned(), v)].into())).collect();
+        for codec in codecs() {
+            let mut writer = FramedWriter::new(Vec::new(), &*codec);
+            events.iter().for_each(|evt| writer.write(evt).unwrap());
+            let bytes = writer.into_inner();
+            let back: Vec<_> =
+                FramedReader::new(&bytes[..], &*codec).collect::<Result<_, _>>().unwrap();
+            assert_eq!(back, events, "{}", codec.name());
+        }
+    }
+
+    #[test]
+    fn truncated_frame_is_an_error() {
+        for codec in codecs() {
+            let mut writer = FramedWriter::new(Vec::new(), &*codec);
+            writer.write(&event(Payload::new())).unwrap();
+            let bytes = writer.into_inner();
+            let mut reader = FramedReader::new(&bytes[..bytes.len() - 1], &*codec);
+            assert!(matches!(reader.next(), Some(Err(CodecError::Io(_)))), "{}", codec.name());
+            assert!(reader.next().is_none());
+        }
+    }
+
+    #[test]
+    fn oversized_frame_is_rejected() {
+        let mut writer = FramedWriter::new(Vec::new(), MessagePack);
+        writer.write(&event(Payload::new())).unwrap();
+        let bytes = writer.into_inner();
+        let mut reader = FramedReader::new(&bytes[..], MessagePack).max_frame_len(8);
+        assert!(matches!(reader.next(), Some(Err(CodecError::FrameTooLarge { max: 8, .. }))));
+    }
+
+    #[test]
+    fn undecodable_frame_is_skipped() {
+        let mut bytes = 3u32.to_be_bytes().to_vec();
+        bytes.extend_from_slice(b"bad");
+        let mut writer = FramedWriter::new(bytes, Cbor);
+        writer.write(&event(Payload::new())).unwrap();
+        let bytes = writer.into_inner();
+        let mut reader = FramedReader::new(&bytes[..], Cbor);
+        assert!(matches!(reader.next(), Some(Err(CodecError::Decode { codec: "cbor", .. }))));
+        assert!(matches!(reader.next(), Some(Ok(_))));
+        assert!(reader.next().is_none());
+    }
+}
@@ -0,0 +1,1280 @@
+//! Builds a [`Pipeline`] and its sources from a TOML or YAML file.
+//!
+//! ```toml
+//! [[sources]]
+//! type = "tcp"
+//! addr = "0.0.0.0:${INGEST_PORT:-7400}"
+//! codec = "msgpack"
+//! framing = "length_prefixed"
+//!
+//! [[processors]]
+//! type = "otlp_http"
+//! endpoint = "${OTLP_ENDPOINT}"
+//!
+//! [[sinks]]
+//! type = "rolling_file"
+//! path = "/var/log/events.jsonl"
+//! max_bytes = 104857600
+//! ```
+//!
+//! Every entry names a factory in a [`Registry`] with `type`; the rest of the
+//! entry is that factory's to read. Strings may refer to the environment as
+//! `${VAR}` or `${VAR:-default}`, and `$$` stands for a literal `$`. Errors
+//! carry the path of the key at fault, such as `sinks[1].max_bytes`.
+
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::codec::{Cbor, EventCodec, Framing, Json, MessagePack};
+use super::dedup::{
+    Dedup, Identity, Window, DEFAULT_MAX_ENTRIES, MAX_BLOOM_EXPECTED, MIN_FALSE_POSITIVE_RATE,
+};
+use super::fields::{
+    Coerce, CopyFields, DropFields, FieldType, KindTemplate, MoveFields, OnConflict, RenameFields,
+    SetDefaults,
+};
+use super::limit::{Limit, Overflow, RateLimiter};
+use super::otlp::{OtlpEncoding, OtlpHttpExporter};
+use super::plugin::PluginProcessor;
+use super::redact::{Detector, Policy, Redactor};
+use super::sample::{DynamicSampler, KeySampler, RandomSampler};
+use super::sink::{FileSink, RollingFileSink, SocketSink, StdoutSink};
+use super::source::{FileTailSource, ReaderSource, Source, TcpSource, UdpSource};
+use super::{Pipeline, Processor, Sink, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    /// Picks the format from a `.toml`, `.yaml` or `.yml` extension.
+    pub fn from_path(path: &Path) -> Option<Format> {
+        match path.extension()?.to_str()? {
+            "toml" => Some(Format::Toml),
+            "yaml" | "yml" => Some(Format::Yaml),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Format::Toml => "TOML",
+            Format::Yaml => "YAML",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    UnknownFormat(PathBuf),
+    Parse {
+        format: Format,
+        message: String,
+    },
+    Missing {
+        key: String,
+    },
+    Invalid {
+        key: String,
+        message: String,
+    },
+    UnknownType {
+        key: String,
+        name: String,
+    },
+    Env {
+        key: String,
+        var: String,
+    },
+    /// A factory read its settings but could not build the component.
+    Build {
+        key: String,
+        source: Box<dyn Error + Send + Sync>,
+    },
+}
+
+impl ConfigError {
+    /// The offending key, when the error is about one.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            ConfigError::Missing { key }
+            | ConfigError::Invalid { key, .. }
+            | ConfigError::UnknownType { key, .. }
+            | ConfigError::Env { key, .. }
+            | ConfigError::Build { key, .. } => Some(key),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, source } => {
+                write!(f, "cannot read {}: {source}", path.display())
+            }
+            ConfigError::UnknownFormat(path) => {
+                write!(f, "cannot tell the format of {}; use .toml, .yaml or .yml", path.display())
+            }
+            ConfigError::Parse { format, message } => write!(f, "invalid {format}: {message}"),
+            ConfigError::Missing { key } => write!(f, "missing required key `{key}`"),
+            ConfigError::Invalid { key, message } => write!(f, "`{key}`: {message}"),
+            ConfigError::UnknownType { key, name } => {
+                write!(f, "`{key}`: no factory registered for `{name}`")
+            }
+            ConfigError::Env { key, var } => {
+                write!(f, "`{key}`: environment variable `{var}` is not set")
+            }
+            ConfigError::Build { key, source } => write!(f, "`{key}`: {source}"),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::Io { source, .. } => Some(source),
+            ConfigError::Build { source, .. } => Some(&**source),
+            _ => None,
+        }
+    }
+}
+
+/// One `sources`, `processors` or `sinks` entry as a factory sees it.
+/// Keys a factory never reads are reported as unknown once it returns, so
+/// typos do not go unnoticed.
+pub struct Section<'a> {
+    key: String,
+    map: &'a HashMap<String, Value>,
+    read: RefCell<BTreeSet<&'a str>>,
+    registry: &'a Registry,
+}
+
+impl<'a> Section<'a> {
+    fn new(key: String, map: &'a HashMap<String, Value>, registry: &'a Registry) -> Self {
+        Self { key, map, read: RefCell::new(BTreeSet::from(["type"])), registry }
+    }
+
+    /// Where this entry sits in the file, e.g. `processors[2]`.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// The full path of one of this entry's fields, for error messages.
+    pub fn path(&self, field: &str) -> String {
+        child(&self.key, field)
+    }
+
+    pub fn get(&self, field: &str) -> Option<&'a Value> {
+        let (name, value) = self.map.get_key_value(field)?;
+        self.read.borrow_mut().insert(name);
+        Some(value)
+    }
+
+    pub fn invalid(&self, field: &str, message: impl Into<String>) -> ConfigError {
+        ConfigError::Invalid { key: self.path(field), message: message.into() }
+    }
+
+    /// Wraps a constructor's error so it points at this entry.
+    pub fn build_error(&self, err: impl Into<Box<dyn Error + Send + Sync>>) -> ConfigError {
+        ConfigError::Build { key: self.key.clone(), source: err.into() }
+    }
+
+    fn typed<T>(
+        &self,
+        field: &str,
+        expected: &str,
+        read: impl FnOnce(&'a Value) -> Option<T>,
+    ) -> Result<Option<T>, ConfigError> {
+        let Some(v) = self.get(field) else { return Ok(None) };
+        let found = v.type_name();
+        read(v)
+            .map(Some)
+            .ok_or_else(|| self.invalid(field, format!("expected {expected}, found {found}")))
+    }
+
+    fn required<T>(&self, field: &str, value: Option<T>) -> Result<T, ConfigError> {
+        value.ok_or_else(|| ConfigError::Missing { key: self.path(field) })
+    }
+
+    pub fn opt_str(&self, field: &str) -> Result<Option<&'a str>, ConfigError> {
+        self.typed(field, "a string", Value::as_str)
+    }
+
+    pub fn str(&self, field: &str) -> Result<&'a str, ConfigError> {
+        let value = self.opt_str(field)?;
+        self.required(field, value)
+    }
+
+    pub fn opt_u64(&self, field: &str) -> Result<Option<u64>, ConfigError> {
+        self.typed(field, "a non-negative integer", |v| v.as_i64().and_then(|n| n.try_into().ok()))
+    }
+
+    /// A float; integers are accepted too.
+    pub fn opt_f64(&self, field: &str) -> Result<Option<f64>, ConfigError> {
+        self.typed(field, "a number", Value::as_f64)
+    }
+
+    pub fn opt_bool(&self, field: &str) -> Result<Option<bool>, ConfigError> {
+        self.typed(field, "a boolean", Value::as_bool)
+    }
+
+    pub fn map(&self, field: &str) -> Result<&'a HashMap<String, Value>, ConfigError> {
+        let value = self.typed(field, "a table", Value::as_map)?;
+        self.required(field, value)
+    }
+
+    /// A required table whose values are all strings, in key order.
+    pub fn str_map(&self, field: &str) -> Result<Vec<(&'a str, &'a str)>, ConfigError> {
+        let mut entries = Vec::new();
+        for (k, v) in self.map(field)? {
+            let v = v.as_str().ok_or_else(|| {
+                self.invalid(
+                    &child(field, k),
+                    format!("expected a string, found {}", v.type_name()),
+                )
+            })?;
+            entries.push((k.as_str(), v));
+        }
+        entries.sort();
+        Ok(entries)
+    }
+
+    /// A required list of strings.
+    pub fn str_list(&self, field: &str) -> Result<Vec<&'a str>, ConfigError> {
+        let items = self.typed(field, "a list", Value::as_list)?;
+        let items = self.required(field, items)?;
+        let mut out = Vec::with_capacity(items.len());
+        for (i, v) in items.iter().enumerate() {
+            let found = v.type_name();
+            let s = v.as_str().ok_or_else(|| {
+                self.invalid(&format!("{field}[{i}]"), format!("expected a string, found {found}"))
+            })?;
+            out.push(s);
+        }
+        Ok(out)
+    }
+
+    /// The `on_conflict` field: `overwrite` (the default) or `keep`.
+    pub fn on_conflict(&self) -> Result<OnConflict, ConfigError> {
+        match self.opt_str("on_conflict")?.unwrap_or("overwrite") {
+            "overwrite" => Ok(OnConflict::Overwrite),
+            "keep" => Ok(OnConflict::Keep),
+            other => Err(self.invalid("on_conflict", format!("unknown policy `{other}`"))),
+        }
+    }
+
+    /// Reads the required table `field` with `read`, checking it for
+    /// unknown keys like a top-level entry.
+    pub fn table<T>(
+        &self,
+        field: &str,
+        read: impl FnOnce(&Section<'a>) -> Result<T, ConfigError>,
+    ) -> Result<T, ConfigError> {
+        let section = Section {
+            key: self.path(field),
+            map: self.map(field)?,
+            read: RefCell::default(),
+            registry: self.registry,
+        };
+        let out = read(&section)?;
+        section.check_unread()?;
+        Ok(out)
+    }
+
+    /// Builds the table `field` as a sink, with the factories of the
+    /// registry this entry is being built by.
+    pub fn sink(&self, field: &str) -> Result<Box<dyn Sink>, ConfigError> {
+        self.table(field, |t| build_entry(t, &self.registry.sinks))
+    }
+
+    /// Reads each table in the required list `field` with `read`, checking
+    /// the tables for unknown keys like top-level entries.
+    pub fn tables<T>(
+        &self,
+        field: &str,
+        mut read: impl FnMut(&Section<'a>) -> Result<T, ConfigError>,
+    ) -> Result<Vec<T>, ConfigError> {
+        let items = self.typed(field, "a list", Value::as_list)?;
+        let items = self.required(field, items)?;
+        let mut out = Vec::with_capacity(items.len());
+        for (i, item) in items.iter().enumerate() {
+            let key = format!("{field}[{i}]");
+            let map = item.as_map().ok_or_else(|| {
+                self.invalid(&key, format!("expected a table, found {}", item.type_name()))
+            })?;
+            let section = Section {
+                key: self.path(&key),
+                map,
+                read: RefCell::default(),
+                registry: self.registry,
+            };
+            out.push(read(&section)?);
+            section.check_unread()?;
+        }
+        Ok(out)
+    }
+
+    /// A whole number of milliseconds, or a string such as `"250ms"`,
+    /// `"30s"`, `"5m"` or `"1h"`.
+    pub fn opt_duration(&self, field: &str) -> Result<Option<Duration>, ConfigError> {
+        self.typed(field, "a duration", |v| match v {
+            Value::Int(ms) => u64::try_from(*ms).ok().map(Duration::from_millis),
+            Value::String(s) => parse_duration(s),
+            _ => None,
+        })
+    }
+
+    /// The `codec` field: `json` (the default), `msgpack` or `cbor`.
+    pub fn codec(&self) -> Result<Arc<dyn EventCodec>, ConfigError> {
+        match self.opt_str("codec")?.unwrap_or("json") {
+            "json" => Ok(Arc::new(Json)),
+            "msgpack" => Ok(Arc::new(MessagePack)),
+            "cbor" => Ok(Arc::new(Cbor)),
+            other => Err(self.invalid("codec", format!("unknown codec `{other}`"))),
+        }
+    }
+
+    /// The `framing` field: `lines` (the default) or `length_prefixed`.
+    pub fn framing(&self) -> Result<Framing, ConfigError> {
+        match self.opt_str("framing")?.unwrap_or("lines") {
+            "lines" => Ok(Framing::Lines),
+            "length_prefixed" => Ok(Framing::LengthPrefixed),
+            other => Err(self.invalid("framing", format!("unknown framing `{other}`"))),
+        }
+    }
+
+    fn check_unread(&self) -> Result<(), ConfigError> {
+        let read = self.read.borrow();
+        let mut unread: Vec<&String> =
+            self.map.keys().filter(|k| !read.contains(k.as_str())).collect();
+        unread.sort();
+        match unread.first() {
+            Some(k) => Err(self.invalid(k, "unknown key")),
+            None => Ok(()),
+        }
+    }
+}
+
+fn parse_duration(s: &str) -> Option<Duration> {
+    let split = s.find(|c: char| !c.is_ascii_digit())?;
+    let n: u64 = s[..split].parse().ok()?;
+    match &s[split..] {
+        "ms" => Some(Duration::from_millis(n)),
+        "s" => Some(Duration::from_secs(n)),
+        "m" => n.checked_mul(60).map(Duration::from_secs),
+        "h" => n.checked_mul(3600).map(Duration::from_secs),
+        _ => None,
+    }
+}
+
+/// The payload key `rate_limit` tags events with when `on_overflow` is
+/// `tag` and no `tag` is given.
+const DEFAULT_TAG: &str = "rate_limited";
+
+type Factory<T> = Box<dyn Fn(&Section) -> Result<T, ConfigError> + Send + Sync>;
+
+/// Factories by `type` name. [`Registry::new`] comes with the sources and
+/// sinks in this crate, the [field](super::fields),
+/// [redaction](super::redact), [sampling](super::sample),
+/// [rate limiting](super::limit) and [deduplication](super::dedup)
+/// processors and the OTLP exporter; add your own with the `register_*`
+/// methods. A `rate_limit` processor diverts to a table of its own under
+/// `divert`, which takes any sink the registry knows:
+///
+/// ```toml
+/// [[processors]]
+/// type = "rate_limit"
+/// per_second = 100
+/// on_overflow = "divert"
+/// divert = { type = "file", path = "/var/log/limited.jsonl" }
+/// ```
+pub struct Registry {
+    sources: HashMap<String, Factory<Box<dyn Source>>>,
+    processors: HashMap<String, Factory<Box<dyn Processor>>>,
+    sinks: HashMap<String, Factory<Box<dyn Sink>>>,
+}
+
+/// What a config file describes. Sources are not attached to the pipeline;
+/// drive them with [`Pipeline::run_source`].
+pub struct Loaded {
+    pub pipeline: Pipeline,
+    pub sources: Vec<Box<dyn Source>>,
+}
+
+impl Registry {
+    /// A registry with no factories at all.
+    pub fn empty() -> Self {
+        Self { sources: HashMap::new(), processors: HashMap::new(), sinks: HashMap::new() }
+    }
+
+    pub fn new() -> Self {
+        let mut r = Self::empty();
+        r.register_source("stdin", |s| Ok(Box::new(ReaderSource::stdin(s.codec()?, s.framing()?))));
+        r.register_source("file", |s| {
+            let mut src = FileTailSource::new(s.str("path")?, s.codec()?, s.framing()?);
+            if s.opt_bool("from_start")?.unwrap_or(false) {
+                src = src.from_start();
+            }
+            if let Some(every) = s.opt_duration("poll_interval")? {
+                src = src.poll_interval(every);
+            }
+            Ok(Box::new(src))
+        });
+        r.register_source("udp", |s| {
+            let addr = s.str("addr")?;
+            Ok(Box::new(UdpSource::bind(addr, s.codec()?).map_err(|e| s.build_error(e))?))
+        });
+        r.register_source("tcp", |s| {
+            let addr = s.str("addr")?;
+            let src =
+                TcpSource::bind(addr, s.codec()?, s.framing()?).map_err(|e| s.build_error(e))?;
+            Ok(Box::new(src))
+        });
+        #[cfg(unix)]
+        r.register_source("unix", |s| {
+            let path = s.str("path")?;
+            let src = super::source::UnixSource::bind(path, s.codec()?, s.framing()?)
+                .map_err(|e| s.build_error(e))?;
+            Ok(Box::new(src))
+        });
+
+        r.register_processor("rename", |s| {
+            Ok(Box::new(RenameFields::new(s.str_map("fields")?).on_conflict(s.on_conflict()?)))
+        });
+        r.register_processor("copy", |s| {
+            Ok(Box::new(CopyFields::new(s.str_map("fields")?).on_conflict(s.on_conflict()?)))
+        });
+        r.register_processor("move", |s| {
+            let p = MoveFields::new(s.str_list("fields")?, s.str("into")?);
+            Ok(Box::new(p.on_conflict(s.on_conflict()?)))
+        });
+        r.register_processor("drop", |s| Ok(Box::new(DropFields::new(s.str_list("fields")?))));
+        r.register_processor("defaults", |s| {
+            Ok(Box::new(SetDefaults::new(s.map("fields")?.iter().map(|(k, v)| (k, v.clone())))))
+        });
+        r.register_processor("coerce", |s| {
+            let mut fields = Vec::new();
+            for (key, ty) in s.str_map("fields")? {
+                let ty = FieldType::from_name(ty).ok_or_else(|| {
+                    s.invalid(&child("fields", key), format!("unknown type `{ty}`"))
+                })?;
+                fields.push((key, ty));
+            }
+            let p = Coerce::new(fields);
+            Ok(Box::new(if s.opt_bool("lenient")?.unwrap_or(false) { p.lenient() } else { p }))
+        });
+        r.register_processor("kind_template", |s| {
+            let t = s.str("template")?;
+            Ok(Box::new(KindTemplate::new(t).map_err(|e| s.invalid("template", e.message))?))
+        });
+
+        r.register_processor("redact", |s| {
+            let rules = s.tables("rules", |rule| {
+                let detect = rule.str("detect")?;
+                let detector = match detect {
+                    "email" => Detector::email(),
+                    "ip_address" => Detector::ip_address(),
+                    "token" => Detector::token(),
+                    "card_number" => Detector::card_number(),
+                    "keys" => Detector::keys(rule.str_list("keys")?),
+                    "values" => Detector::values(rule.str_list("values")?),
+                    "pattern" => Detector::pattern(rule.str("pattern")?)
+                        .map_err(|e| rule.invalid("pattern", e.to_string()))?,
+                    other => {
+                        return Err(rule.invalid("detect", format!("unknown detector `{other}`")))
+                    }
+                };
+                let policy = match rule.opt_str("policy")?.unwrap_or("mask") {
+                    "mask" => Policy::Mask,
+                    "hash" => Policy::hash(rule.str("salt")?),
+                    "drop" => Policy::Drop,
+                    other => {
+                        return Err(rule.invalid("policy", format!("unknown policy `{other}`")))
+                    }
+                };
+                let name = rule.opt_str("name")?.unwrap_or(detect).to_owned();
+                Ok((name, detector, policy))
+            })?;
+            let mut redactor = Redactor::new();
+            for (name, detector, policy) in rules {
+                redactor = redactor.rule(name, detector, policy);
+            }
+            if let Some(key) = s.opt_str("report_to")? {
+                redactor = redactor.report_to(key);
+            }
+            Ok(Box::new(redactor))
+        });
+
+        r.register_processor("sample", |s| {
+            let rate = s.opt_f64("rate")?;
+            let rate = s.required("rate", rate)?;
+            if !(0.0..=1.0).contains(&rate) {
+                return Err(s.invalid("rate", "must be between 0 and 1"));
+            }
+            Ok(match s.opt_str("key")? {
+                Some(key) => Box::new(KeySampler::new(key, rate)),
+                None => Box::new(RandomSampler::new(rate)),
+            })
+        });
+        r.register_processor("dynamic_sample", |s| {
+            let per_second = s.opt_f64("per_second")?;
+            let per_second = s.required("per_second", per_second)?;
+            if !(per_second >= 0.0 && per_second.is_finite()) {
+                return Err(s.invalid("per_second", "must be a non-negative number"));
+            }
+            let mut sampler = DynamicSampler::new(per_second);
+            if let Some(window) = s.opt_duration("window")? {
+                sampler = sampler.window(window);
+            }
+            Ok(Box::new(sampler))
+        });
+
+        r.register_processor("rate_limit", |s| {
+            let limit = |s: &Section, rate: f64| {
+                let limit = Limit::per_second(rate);
+                Ok::<_, ConfigError>(match s.opt_u64("burst")? {
+                    Some(burst) => limit.burst(burst.try_into().unwrap_or(u32::MAX)),
+                    None => limit,
+                })
+            };
+            let checked = |field: &str, rate: f64| match rate >= 0.0 && rate.is_finite() {
+                true => Ok(rate),
+                false => Err(s.invalid(field, "must be a non-negative number")),
+            };
+            let rate = s.opt_f64("per_second")?;
+            let rate = checked("per_second", s.required("per_second", rate)?)?;
+            let default = limit(s, rate)?;
+            let mut limiter = match s.opt_str("by_key")? {
+                Some(key) => RateLimiter::per_key(key, default),
+                None => RateLimiter::per_kind(default),
+            };
+            if s.get("quotas").is_some() {
+                for (bucket, rate) in s.map("quotas")? {
+                    let field = child("quotas", bucket);
+                    let rate = rate
+                        .as_f64()
+                        .ok_or_else(|| s.invalid(&field, "expected events per second"))?;
+                    limiter = limiter.quota(bucket, Limit::per_second(checked(&field, rate)?));
+                }
+            }
+            limiter = match s.opt_str("on_overflow")?.unwrap_or("drop") {
+                "drop" => limiter,
+                "tag" => limiter.on_overflow(Overflow::Tag(
+                    s.opt_str("tag")?.unwrap_or(DEFAULT_TAG).to_owned(),
+                )),
+                "divert" => limiter.on_overflow(Overflow::Divert(s.sink("divert")?)),
+                other => return Err(s.invalid("on_overflow", format!("unknown action `{other}`"))),
+            };
+            if let Some(every) = s.opt_duration("summary_every")? {
+                limiter = limiter.summary_every(Some(every).filter(|d| !d.is_zero()));
+            }
+            Ok(Box::new(limiter))
+        });
+
+        r.register_processor("dedup", |s| {
+            let identity = match s.get("fields") {
+                Some(_) => Identity::fields(s.str_list("fields")?),
+                None => Identity::Id,
+            };
+            let window = match (s.opt_duration("within")?, s.opt_u64("last")?) {
+                (Some(d), None) => Window::Time(d),
+                (None, Some(n)) => Window::Count(n as usize),
+                _ => return Err(s.invalid("within", "set exactly one of `within` and `last`")),
+            };
+            let dedup = match s.opt_str("memory")?.unwrap_or("exact") {
+                "exact" => {
+                    let max = s.opt_u64("max_entries")?.map_or(DEFAULT_MAX_ENTRIES, |m| m as usize);
+                    if matches!(window, Window::Count(n) if n > max) {
+                        let message = format!("must be at most max_entries ({max})");
+                        return Err(s.invalid("last", message));
+                    }
+                    Dedup::exact(identity, window).max_entries(max)
+                }
+                "bloom" => {
+                    let fp_rate = s.opt_f64("false_positive_rate")?.unwrap_or(0.001);
+                    if !(MIN_FALSE_POSITIVE_RATE..=0.5).contains(&fp_rate) {
+                        let message = format!("must be between {MIN_FALSE_POSITIVE_RATE} and 0.5");
+                        return Err(s.invalid("false_positive_rate", message));
+                    }
+                    let sized = |field: &str, n: usize| match n {
+                        1..=MAX_BLOOM_EXPECTED => Ok(n),
+                        _ => Err(s.invalid(
+                            field,
+                            format!("must be between 1 and {MAX_BLOOM_EXPECTED} for bloom memory"),
+                        )),
+                    };
+                    match window {
+                        Window::Count(n) => Dedup::bloom_last(identity, sized("last", n)?, fp_rate),
+                        Window::Time(d) => {
+                            let expected = s.opt_u64("expected")?;
+                            let expected = s.required("expected", expected)?;
+                            let expected =
+                                sized("expected", expected.try_into().unwrap_or(usize::MAX))?;
+                            Dedup::bloom_within(identity, d, expected, fp_rate)
+                        }
+                    }
+                }
+                other => return Err(s.invalid("memory", format!("unknown memory `{other}`"))),
+            };
+            Ok(Box::new(dedup))
+        });
+
+        r.register_processor("otlp_http", |s| {
+            let mut exporter =
+                OtlpHttpExporter::new(s.str("endpoint")?).map_err(|e| s.build_error(e))?;
+            match s.opt_str("encoding")? {
+                None | Some("protobuf") => {}
+                Some("json") => exporter = exporter.encoding(OtlpEncoding::Json),
+                Some(other) => {
+                    return Err(s.invalid("encoding", format!("unknown encoding `{other}`")))
+                }
+            }
+            if let Some(name) = s.opt_str("service_name")? {
+                exporter = exporter.service_name(name);
+            }
+            if let Some(timeout) = s.opt_duration("timeout")? {
+                exporter = exporter.timeout(timeout);
+            }
+            Ok(Box::new(exporter))
+        });
+
+        r.register_processor("plugin", |s| {
+            let path = s.str("path")?;
+            let config = match s.get("config") {
+                Some(v) => {
+                    serde_json::to_value(v).map_err(|e| s.invalid("config", e.to_string()))?
+                }
+                None => serde_json::Value::Null,
+            };
+            Ok(Box::new(PluginProcessor::load(path, &config).map_err(|e| s.build_error(e))?))
+        });
+
+        #[cfg(feature = "wasm")]
+        r.register_processor("wasm", |s| {
+            let path = s.str("path")?;
+            let mut p =
+                super::wasm::WasmProcessor::from_file(path).map_err(|e| s.build_error(e))?;
+            if let Some(fuel) = s.opt_u64("fuel")? {
+                p = p.fuel(fuel);
+            }
+            if let Some(bytes) = s.opt_u64("max_memory")? {
+                p = p.max_memory(bytes as usize);
+            }
+            Ok(Box::new(p))
+        });
+
+        #[cfg(feature = "script")]
+        r.register_processor("script", |s| {
+            use super::script::ScriptProcessor;
+
+            let p = match (s.opt_str("source")?, s.opt_str("path")?) {
+                (Some(source), None) => ScriptProcessor::new(s.key(), source),
+                (None, Some(path)) => ScriptProcessor::from_file(path),
+                _ => return Err(s.invalid("source", "set exactly one of `source` and `path`")),
+            };
+            let mut p = p.map_err(|e| s.build_error(e))?;
+            if let Some(ops) = s.opt_u64("max_operations")? {
+                p = p.max_operations(ops);
+            }
+            Ok(Box::new(p))
+        });
+
+        register_builtin_sinks(&mut r);
+        r
+    }
+
+    /// Registers a processor factory, replacing any previous one of the same
+    /// name.
+    pub fn register_processor(
+        &mut self,
+        name: &str,
+        factory: impl Fn(&Section) -> Result<Box<dyn Processor>, ConfigError> + Send + Sync + 'static,
+    ) {
+        self.processors.insert(name.to_owned(), Box::new(factory));
+    }
+
+    pub fn register_source(
+        &mut self,
+        name: &str,
+        factory: impl Fn(&Section) -> Result<Box<dyn Source>, ConfigError> + Send + Sync + 'static,
+    ) {
+        self.sources.insert(name.to_owned(), Box::new(factory));
+    }
+
+    pub fn register_sink(
+        &mut self,
+        name: &str,
+        factory: impl Fn(&Section) -> Result<Box<dyn Sink>, ConfigError> + Send + Sync + 'static,
+    ) {
+        self.sinks.insert(name.to_owned(), Box::new(factory));
+    }
+
+    /// Reads `path`, choosing the format by its extension.
+    pub fn load(&self, path: impl AsRef<Path>) -> Result<Loaded, ConfigError> {
+        let (text, format) = read(path.as_ref())?;
+        self.build(&text, format)
+    }
+
+    /// Like [`load`](Self::load) but leaves the `sources` entries unbuilt,
+    /// for rebuilding a pipeline while the sources it was fed by keep their
+    /// sockets and files. They are still checked to be a list.
+    pub fn load_pipeline(&self, path: impl AsRef<Path>) -> Result<Pipeline, ConfigError> {
+        let (text, format) = read(path.as_ref())?;
+        Ok(self.build_parts(&text, format, false)?.pipeline)
+    }
+
+    pub fn build(&self, text: &str, format: Format) -> Result<Loaded, ConfigError> {
+        self.build_parts(text, format, true)
+    }
+
+    fn build_parts(
+        &self,
+        text: &str,
+        format: Format,
+        with_sources: bool,
+    ) -> Result<Loaded, ConfigError> {
+        let root = parse(text, format, &|var| std::env::var(var).ok())?;
+        let Value::Map(root) = root else {
+            return Err(ConfigError::Parse { format, message: "top level must be a table".into() });
+        };
+        let root = Section::new(String::new(), &root, self);
+        let (sources, processors, sinks) =
+            (root.get("sources"), root.get("processors"), root.get("sinks"));
+        root.check_unread()?;
+
+        let sources = match with_sources {
+            true => entries("sources", sources, self, &self.sources)?,
+            false => list("sources", sources).map(|_| Vec::new())?,
+        };
+        let mut pipeline = Pipeline::new();
+        for p in entries("processors", processors, self, &self.processors)? {
+            pipeline.add(p);
+        }
+        for s in entries("sinks", sinks, self, &self.sinks)? {
+            pipeline.add_sink(s);
+        }
+        Ok(Loaded { pipeline, sources })
+    }
+}
+
+/// The sinks [`Registry::new`] comes with.
+fn register_builtin_sinks(r: &mut Registry) {
+    r.register_sink("stdout", |s| Ok(Box::new(StdoutSink::new(s.codec()?, s.framing()?))));
+    r.register_sink("file", |s| {
+        let path = s.str("path")?;
+        let sink =
+            FileSink::append(path, s.codec()?, s.framing()?).map_err(|e| s.build_error(e))?;
+        Ok(Box::new(sink))
+    });
+    r.register_sink("rolling_file", |s| {
+        let path = s.str("path")?;
+        let mut sink =
+            RollingFileSink::new(path, s.codec()?, s.framing()?).map_err(|e| s.build_error(e))?;
+        if let Some(max) = s.opt_u64("max_bytes")? {
+            sink = sink.max_bytes(max);
+        }
+        if let Some(max) = s.opt_duration("max_age")? {
+            sink = sink.max_age(max);
+        }
+        if let Some(keep) = s.opt_u64("keep")? {
+            sink = sink.keep(keep as usize);
+        }
+        Ok(Box::new(sink))
+    });
+    r.register_sink("tcp", |s| {
+        let addr = s.str("addr")?;
+        let sink = SocketSink::tcp(addr, s.codec()?, s.framing()?).map_err(|e| s.build_error(e))?;
+        Ok(Box::new(sink))
+    });
+    #[cfg(unix)]
+    r.register_sink("unix", |s| {
+        let path = s.str("path")?;
+        let sink =
+            SocketSink::unix(path, s.codec()?, s.framing()?).map_err(|e| s.build_error(e))?;
+        Ok(Box::new(sink))
+    });
+}
+
+fn read(path: &Path) -> Result<(String, Format), ConfigError> {
+    let format = Format::from_path(path).ok_or_else(|| ConfigError::UnknownFormat(path.into()))?;
+    let text =
+        fs::read_to_string(path).map_err(|source| ConfigError::Io { path: path.into(), source })?;
+    Ok((text, format))
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn list<'a>(field: &str, value: Option<&'a Value>) -> Result<&'a [Value], ConfigError> {
+    let Some(value) = value else { return Ok(&[]) };
+    value.as_list().ok_or_else(|| ConfigError::Invalid {
+        key: field.to_owned(),
+        message: format!("expected a list, found {}", value.type_name()),
+    })
+}
+
+/// Builds every entry of the `field` list with its registered factory.
+fn entries<T>(
+    field: &str,
+    value: Option<&Value>,
+    registry: &Registry,
+    factories: &HashMap<String, Factory<T>>,
+) -> Result<Vec<T>, ConfigError> {
+    let list = list(field, value)?;
+    let mut built = Vec::with_capacity(list.len());
+    for (i, entry) in list.iter().enumerate() {
+        let key = format!("{field}[{i}]");
+        let Some(map) = entry.as_map() else {
+            let message = format!("expected a table, found {}", entry.type_name());
+            return Err(ConfigError::Invalid { key, message });
+        };
+        let section = Section::new(key, map, registry);
+        built.push(build_entry(&section, factories)?);
+        section.check_unread()?;
+    }
+    Ok(built)
+}
+
+/// Builds one entry with the factory its `type` names.
+fn build_entry<T>(
+    section: &Section,
+    factories: &HashMap<String, Factory<T>>,
+) -> Result<T, ConfigError> {
+    let name = section.str("type")?;
+    let factory = factories.get(name).ok_or_else(|| ConfigError::UnknownType {
+        key: section.path("type"),
+        name: name.to_owned(),
+    })?;
+    factory(section)
+}
+
+type Env<'e> = &'e dyn Fn(&str) -> Option<String>;
+
+fn parse(text: &str, format: Format, env: Env) -> Result<Value, ConfigError> {
+    let message = |e: &dyn fmt::Display| ConfigError::Parse { format, message: e.to_string() };
+    match format {
+        Format::Toml => {
+            from_toml(text.parse::<toml::Table>().map_err(|e| message(&e))?.into(), "", env)
+        }
+        Format::Yaml => from_yaml(serde_yaml::from_str(text).map_err(|e| message(&e))?, "", env),
+    }
+}
+
+fn child(key: &str, field: &str) -> String {
+    if key.is_empty() {
+        field.to_owned()
+    } else {
+        format!("{key}.{field}")
+    }
+}
+
+fn from_toml(v: toml::Value, key: &str, env: Env) -> Result<Value, ConfigError> {
+    Ok(match v {
+        toml::Value::String(s) => Value::String(interpolate(&s, key, env)?),
+        toml::Value::Integer(n) => Value::Int(n),
+        toml::Value::Float(f) => Value::Float(f),
+        toml::Value::Boolean(b) => Value::Bool(b),
+        toml::Value::Datetime(d) => Value::String(d.to_string()),
+        toml::Value::Array(items) => Value::List(
+            items
+                .into_iter()
+                .enumerate()
+                .map(|(i, v)| from_toml(v, &format!("{key}[{i}]"), env))
+                .collect::<Result<_, _>>()?,
+        ),
+        toml::Value::Table(t) => Value::Map(
+            t.into_iter()
+                .map(|(k, v)| Ok((k.clone(), from_toml(v, &child(key, &k), env)?)))
+                .collect::<Result<_, ConfigError>>()?,
+        ),
+    })
+}
+
+fn from_yaml(v: serde_yaml::Value, key: &str, env: Env) -> Result<Value, ConfigError> {
+    use serde_yaml::Value as Yaml;
+
+    let invalid =
+        |message: &str| ConfigError::Invalid { key: key.to_owned(), message: message.into() };
+    Ok(match v {
+        Yaml::Null => return Err(invalid("null values are not supported")),
+        Yaml::Bool(b) => Value::Bool(b),
+        Yaml::Number(n) => match (n.as_i64(), n.as_f64()) {
+            (Some(i), _) => Value::Int(i),
+            (None, Some(f)) if !n.is_u64() => Value::Float(f),
+            _ => return Err(invalid("integer out of range")),
+        },
+        Yaml::String(s) => Value::String(interpolate(&s, key, env)?),
+        Yaml::Sequence(items) => Value::List(
+            items
+                .into_iter()
+                .enumerate()
+                .map(|(i, v)| from_yaml(v, &format!("{key}[{i}]"), env))
+                .collect::<Result<_, _>>()?,
+        ),
+        Yaml::Mapping(m) => {
+            let mut map = HashMap::with_capacity(m.len());
+            for (k, v) in m {
+                let Yaml::String(k) = k else { return Err(invalid("keys must be strings")) };
+                let v = from_yaml(v, &child(key, &k), env)?;
+                map.insert(k, v);
+            }
+            Value::Map(map)
+        }
+        Yaml::Tagged(tagged) => from_yaml(tagged.value, key, env)?,
+    })
+}
+
+/// Expands `${VAR}` and `${VAR:-default}`; `$$` is a literal `$`.
+fn interpolate(s: &str, key: &str, env: Env) -> Result<String, ConfigError> {
+    let invalid =
+        |message: &str| ConfigError::Invalid { key: key.to_owned(), message: message.into() };
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(at) = rest.find('$') {
+        out.push_str(&rest[..at]);
+        rest = &rest[at + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            out.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('{') {
+            let end = after.find('}').ok_or_else(|| invalid("unterminated `${`"))?;
+            let (var, default) = match after[..end].split_once(":-") {
+                Some((var, default)) => (var, Some(default)),
+                None => (&after[..end], None),
+            };
+            match (env(var), default) {
+                (Some(value), _) => out.push_str(&value),
+                (None, Some(default)) => out.push_str(default),
+                (None, None) => {
+                    return Err(ConfigError::Env { key: key.to_owned(), var: var.to_owned() })
+                }
+            }
+            rest = &after[end + 1..];
+        } else {
+            out.push('$');
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::{Output, ProcessError, TelemetryEvent};
+
+    struct SetKind(String);
+
+    impl Processor for SetKind {
+        fn name(&self) -> &str {
+            "set_kind"
+        }
+
+        fn process(&self, mut input: TelemetryEvent) -> Result<Output, ProcessError> {
+            input.kind = self.0.clone();
+            Ok(input.into())
+        }
+    }
+
+    fn registry() -> Registry {
+        let mut r = Registry::new();
+        r.register_processor("set_kind", |s| Ok(Box::new(SetKind(s.str("kind")?.to_owned()))));
+        r
+    }
+
+    fn env(var: &str) -> Option<String> {
+        (var == "HOME_DIR").then(|| "/home/ops".to_owned())
+    }
+
+    #[test]
+    fn toml_and_yaml_build_the_same_pipeline() {
+        let toml = r#"
+            [[processors]]
+            type = "set_kind"
+            kind = "renamed"
+        "#;
+        let yaml = "processors:\n  - type: set_kind\n    kind: renamed\n";
+        for (text, format) in [(toml, Format::Toml), (yaml, Format::Yaml)] {
+            let loaded = registry().build(text, format).unwrap();
+            assert!(loaded.sources.is_empty());
+            let out = loaded.pipeline.run(TelemetryEvent::new("a")).unwrap();
+            assert_eq!(out[0].kind, "renamed");
+        }
+    }
+
+    #[test]
+    fn errors_name_the_offending_key() {
+        let err = |text: &str| registry().build(text, Format::Yaml).err().unwrap();
+        let cases = [
+            ("processors:\n  - kind: x\n", "processors[0].type", "missing required key"),
+            (
+                "processors:\n  - type: nope\n",
+                "processors[0].type",
+                "no factory registered for `nope`",
+            ),
+            (
+                "processors:\n  - type: set_kind\n    kind: 3\n",
+                "processors[0].kind",
+                "expected a string",
+            ),
+            (
+                "processors:\n  - type: set_kind\n    kind: x\n    knd: y\n",
+                "processors[0].knd",
+                "unknown key",
+            ),
+            (
+                "sinks:\n  - type: file\n    path: /x\n    codec: xml\n",
+                "sinks[0].codec",
+                "unknown codec",
+            ),
+            ("sinks: {}\n", "sinks", "expected a list"),
+            ("sink: []\n", "sink", "unknown key"),
+        ];
+        for (text, key, message) in cases {
+            let e = err(text);
+            assert_eq!(e.key(), Some(key), "{text}");
+            assert!(e.to_string().contains(message), "{e}");
+        }
+        let e = registry().build("[[sinks]\n", Format::Toml).err().unwrap();
+        assert!(matches!(e, ConfigError::Parse { format: Format::Toml, .. }));
+    }
+
+    #[test]
+    fn environment_is_interpolated() {
+        let expand = |s: &str| interpolate(s, "k", &env);
+        assert_eq!(expand("${HOME_DIR}/log").unwrap(), "/home/ops/log");
+        assert_eq!(expand("${PORT:-7400}|$$|$x").unwrap(), "7400|$|$x");
+        let e = expand("${MISSING}").unwrap_err();
+        assert!(matches!(&e, ConfigError::Env { key, var } if key == "k" && var == "MISSING"));
+        assert!(expand("${HOME_DIR").is_err());
+
+        let parsed = parse("[a]\nb = [\"${NOPE}\"]\n", Format::Toml, &env).unwrap_err();
+        assert_eq!(parsed.key(), Some("a.b[0]"));
+    }
+
+    #[test]
+    fn builtin_field_processors() {
+        let yaml = r#"
+processors:
+  - type: rename
+    fields: { user: user_id }
+  - type: coerce
+    fields: { status: int }
+  - type: defaults
+    fields: { region: eu }
+  - type: move
+    fields: [user_id, region]
+    into: ctx
+  - type: kind_template
+    template: "{kind}.{status}"
+"#;
+        let pipeline = registry().build(yaml, Format::Yaml).unwrap().pipeline;
+        let mut evt = TelemetryEvent::new("http");
+        evt.insert("user", "u1");
+        evt.insert("status", "503");
+        let out = pipeline.run(evt).unwrap().remove(0);
+        assert_eq!(out.kind, "http.503");
+        let ctx = out.get("ctx").and_then(Value::as_map).unwrap();
+        assert_eq!((ctx["user_id"].as_str(), ctx["region"].as_str()), (Some("u1"), Some("eu")));
+
+        let err = |text: &str| registry().build(text, Format::Yaml).err().unwrap();
+        let e = err("processors:\n  - type: coerce\n    fields: { a: date }\n");
+        assert_eq!(e.key(), Some("processors[0].fields.a"));
+        let e = err("processors:\n  - type: drop\n    fields: [a, 1]\n");
+        assert_eq!(e.key(), Some("processors[0].fields[1]"));
+        let e = err("processors:\n  - type: copy\n    fields: {}\n    on_conflict: merge\n");
+        assert_eq!(e.key(), Some("processors[0].on_conflict"));
+    }
+
+    #[test]
+    fn redact_rules() {
+        let toml = r#"
+            [[processors]]
+            type = "redact"
+            rules = [
+                { detect = "keys", keys = ["token"], policy = "drop" },
+                { name = "mail", detect = "email", policy = "hash", salt = "s" },
+                { detect = "pattern", pattern = "acct-[0-9]+" },
+            ]
+        "#;
+        let pipeline = registry().build(toml, Format::Toml).unwrap().pipeline;
+        let mut evt = TelemetryEvent::new("x");
+        evt.insert("token", "t");
+        evt.insert("msg", "acct-42 by a@b.io");
+        let out = pipeline.run(evt).unwrap().remove(0);
+        assert_eq!(out.get("token"), None);
+        assert!(out.get_str("msg").unwrap().starts_with("[REDACTED] by sha256:"));
+
+        let e = registry()
+            .build("processors:\n  - type: redact\n    rules:\n      - detect: email\n        policy: hash\n", Format::Yaml)
+            .err()
+            .unwrap();
+        assert_eq!(e.key(), Some("processors[0].rules[0].salt"));
+        let e = registry()
+            .build("processors:\n  - type: redact\n    rules:\n      - detect: email\n        polcy: drop\n", Format::Yaml)
+            .err()
+            .unwrap();
+        assert_eq!(e.key(), Some("processors[0].rules[0].polcy"));
+    }
+
+    #[test]
+    fn sampling_rates_are_checked() {
+        let build = |text: &str| registry().build(text, Format::Yaml);
+        let out = build("processors:\n  - type: sample\n    rate: 1\n    key: user\n")
+            .unwrap()
+            .pipeline
+            .run(TelemetryEvent::new("x"))
+            .unwrap();
+        assert_eq!(out[0].get("sample_rate"), Some(&Value::Float(1.0)));
+        let e = build("processors:\n  - type: sample\n    rate: 1.5\n").err().unwrap();
+        assert_eq!(e.key(), Some("processors[0].rate"));
+        let e = build("processors:\n  - type: dynamic_sample\n    window: 5s\n").err().unwrap();
+        assert_eq!(e.key(), Some("processors[0].per_second"));
+        let e =
+            build("processors:\n  - type: dynamic_sample\n    per_second: .nan\n").err().unwrap();
+        assert_eq!(e.key(), Some("processors[0].per_second"));
+    }
+
+    #[test]
+    fn rate_limit_options() {
+        let toml = r#"
+            [[processors]]
+            type = "rate_limit"
+            per_second = 1
+            quotas = { bulk = 0 }
+            on_overflow = "tag"
+            summary_every = 0
+        "#;
+        let pipeline = registry().build(toml, Format::Toml).unwrap().pipeline;
+        let tagged = |kind: &str| {
+            let out = pipeline.run(TelemetryEvent::new(kind)).unwrap();
+            assert_eq!(out.len(), 1);
+            out[0].get("rate_limited").is_some()
+        };
+        assert_eq!(
+            (tagged("a"), tagged("a"), tagged("bulk"), tagged("bulk")),
+            (false, true, false, true)
+        );
+
+        let e = registry()
+            .build(
+                "processors:\n  - type: rate_limit\n    per_second: 1\n    quotas: { a: x }\n",
+                Format::Yaml,
+            )
+            .err()
+            .unwrap();
+        assert_eq!(e.key(), Some("processors[0].quotas.a"));
+        let e = registry()
+            .build(
+                "processors:\n  - type: rate_limit\n    per_second: 1\n    quotas: { a: -1 }\n",
+                Format::Yaml,
+            )
+            .err()
+            .unwrap();
+        assert_eq!(e.key(), Some("processors[0].quotas.a"));
+        let e = registry()
+            .build("processors:\n  - type: rate_limit\n    per_second: .nan\n", Format::Yaml)
+            .err()
+            .unwrap();
+        assert_eq!(e.key(), Some("processors[0].per_second"));
+
+        let dir = std::env::temp_dir().join(format!("divert-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("limited.jsonl");
+        let toml = format!(
+            "[[processors]]\ntype = \"rate_limit\"\nper_second = 1\non_overflow = \"divert\"\n\
+             divert = {{ type = \"limited\", path = \"{}\" }}\n",
+            path.display()
+        );
+        let mut custom = registry();
+        custom.register_sink("limited", |s| {
+            let sink = FileSink::append(s.str("path")?, Arc::new(Json), Framing::Lines)
+                .map_err(|e| s.build_error(e))?;
+            Ok(Box::new(sink))
+        });
+        let pipeline = custom.build(&toml, Format::Toml).unwrap().pipeline;
+        let out = pipeline.run_batch([TelemetryEvent::new("a"), TelemetryEvent::new("a")]).unwrap();
+        assert_eq!(out.len(), 1);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let e = registry()
+            .build(
+                "processors:\n  - type: rate_limit\n    per_second: 1\n    on_overflow: divert\n    divert: { type: stdout, bogus: 1 }\n",
+                Format::Yaml,
+            )
+            .err()
+            .unwrap();
+        assert_eq!(e.key(), Some("processors[0].divert.bogus"));
+    }
+
+    #[test]
+    fn dedup_options() {
+        let yaml = "processors:\n  - type: dedup\n    fields: [order]\n    within: 1m\n    memory: bloom\n    expected: 1000\n";
+        let pipeline = registry().build(yaml, Format::Yaml).unwrap().pipeline;
+        let order = |n: i64| {
+            let mut evt = TelemetryEvent::new("order");
+            evt.insert("order", n);
+            evt
+        };
+        let out = pipeline.run_batch([order(1), order(1), order(2)]).unwrap();
+        assert_eq!(out.len(), 2);
+
+        let err = |text: &str| registry().build(text, Format::Yaml).err().unwrap();
+        let e = err("processors:\n  - type: dedup\n    within: 1m\n    last: 10\n");
+        assert_eq!(e.key(), Some("processors[0].within"));
+        let e = err("processors:\n  - type: dedup\n    within: 1m\n    memory: bloom\n");
+        assert_eq!(e.key(), Some("processors[0].expected"));
+        let e = err("processors:\n  - type: dedup\n    within: 1m\n    memory: bloom\n    expected: 1000000000000\n");
+        assert_eq!(e.key(), Some("processors[0].expected"));
+        let e = err("processors:\n  - type: dedup\n    last: 10\n    max_entries: 5\n");
+        assert_eq!(e.key(), Some("processors[0].last"));
+        let e = err("processors:\n  - type: dedup\n    last: 0\n    memory: bloom\n");
+        assert_eq!(e.key(), Some("processors[0].last"));
+        let e = err("processors:\n  - type: dedup\n    last: 10\n    memory: bloom\n    false_positive_rate: 1e-300\n");
+        assert_eq!(e.key(), Some("processors[0].false_positive_rate"));
+    }
+
+    #[test]
+    fn builtin_sinks_and_durations() {
+        let dir = std::env::temp_dir().join(format!("config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.msgpack");
+        let text = format!(
+            "[[sinks]]\ntype = \"rolling_file\"\npath = \"{}\"\ncodec = \"msgpack\"\n\
+             framing = \"length_prefixed\"\nmax_age = \"5m\"\nkeep = 2\n",
+            path.display()
+        );
+        let loaded = registry().build(&text, Format::Toml).unwrap();
+        loaded.pipeline.run(TelemetryEvent::new("a")).unwrap();
+        loaded.pipeline.close().unwrap();
+        assert!(fs::metadata(&path).unwrap().len() > 4);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(parse_duration("250ms"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_duration("2h"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("5"), None);
+        assert_eq!(parse_duration("5d"), None);
+        assert_eq!(parse_duration("99999999999999999h"), None);
+        assert_eq!(parse_duration("999999999999999999m"), None);
+    }
+}
@@ -0,0 +1,397 @@
+//! Drops events already seen within a window, for upstreams that retry.
+//!
+//! Events are matched by a 64-bit fingerprint of their id, or of their kind
+//! and a chosen set of payload fields. Distinct events share a fingerprint
+//! with odds of about `n² / 2⁶⁵` among `n` remembered ones, around one in
+//! 37 million for a million events, so fingerprint collisions can be
+//! ignored next to the choice of memory:
+//!
+//! - [`Dedup::exact`] remembers fingerprints themselves. It never drops an
+//!   event that is not a duplicate, and uses about 40 bytes per remembered
+//!   event. Either window is capped at [`max_entries`](Dedup::max_entries)
+//!   events, past which the oldest are forgotten and their duplicates pass.
+//! - [`Dedup::bloom_last`] and [`Dedup::bloom_within`] keep two Bloom
+//!   filters sized for a chosen false positive rate `p`, at about
+//!   `1.44 · log2(1/p)` bits per event (14.4 bits at 0.1%). It drops a
+//!   distinct event with probability up to `2p`, as an event is checked
+//!   against both filters.
+
+use std::collections::{HashSet, VecDeque};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime};
+
+use super::{Clock, Output, ProcessError, Processor, SystemClock, TelemetryEvent, Value};
+
+pub const DEFAULT_MAX_ENTRIES: usize = 100_000;
+
+/// The most events a Bloom filter may be sized for from config, and the
+/// lowest false positive rate: together about 72 MB per filter.
+pub const MAX_BLOOM_EXPECTED: usize = 10_000_000;
+pub const MIN_FALSE_POSITIVE_RATE: f64 = 1e-6;
+
+/// What makes two events the same.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Identity {
+    /// The event id, for retries that resend the event unchanged.
+    Id,
+    /// The kind and these payload fields, for retries that mint a new id.
+    /// A missing field counts as a value of its own.
+    Fields(Vec<String>),
+}
+
+impl Identity {
+    pub fn fields(fields: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Identity::Fields(fields.into_iter().map(Into::into).collect())
+    }
+
+    fn fingerprint(&self, evt: &TelemetryEvent) -> u64 {
+        let mut h = DefaultHasher::new();
+        match self {
+            Identity::Id => evt.id.as_u128().hash(&mut h),
+            Identity::Fields(fields) => {
+                evt.kind.hash(&mut h);
+                for field in fields {
+                    match evt.get(field) {
+                        Some(v) => {
+                            1u8.hash(&mut h);
+                            hash_value(v, &mut h);
+                        }
+                        None => 0u8.hash(&mut h),
+                    }
+                }
+            }
+        }
+        h.finish()
+    }
+}
+
+/// Feeds `v` to `h` by structure, so values that print alike, such as
+/// `"1"` and `1` or `["a, b"]` and `["a", "b"]`, hash apart.
+fn hash_value(v: &Value, h: &mut impl Hasher) {
+    std::mem::discriminant(v).hash(h);
+    match v {
+        Value::String(s) => s.hash(h),
+        Value::Int(i) => i.hash(h),
+        Value::Float(f) => f.to_bits().hash(h),
+        Value::Bool(b) => b.hash(h),
+        Value::Bytes(b) => b.hash(h),
+        Value::List(items) => {
+            items.len().hash(h);
+            items.iter().for_each(|item| hash_value(item, h));
+        }
+        Value::Map(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
+            entries.len().hash(h);
+            for (k, v) in entries {
+                k.hash(h);
+                hash_value(v, h);
+            }
+        }
+    }
+}
+
+/// How far back duplicates are looked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Window {
+    /// The last this many distinct events.
+    Count(usize),
+    /// Events first seen less than this long ago.
+    Time(Duration),
+}
+
+struct Bloom {
+    bits: Vec<u64>,
+    hashes: u32,
+    len: usize,
+}
+
+impl Bloom {
+    /// The optimal size for `expected` entries: `m = -n·ln p / ln²2` bits
+    /// and `k = m/n · ln 2` hash functions.
+    fn new(expected: usize, fp_rate: f64) -> Self {
+        let n = expected.max(1) as f64;
+        let p = fp_rate.clamp(f64::MIN_POSITIVE, 0.5);
+        let m = (-n * p.ln() / (2f64.ln() * 2f64.ln())).ceil().max(64.0);
+        let hashes = (m / n * 2f64.ln()).round().clamp(1.0, 32.0) as u32;
+        Self { bits: vec![0; (m as usize).div_ceil(64)], hashes, len: 0 }
+    }
+
+    /// Bit positions by double hashing from one fingerprint. Takes no
+    /// `self` so the bits can be set while iterating.
+    fn positions(fp: u64, words: usize, hashes: u32) -> impl Iterator<Item = usize> {
+        let m = words as u64 * 64;
+        let step = fp.rotate_left(32).wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+        (0..u64::from(hashes)).map(move |i| (fp.wrapping_add(i.wrapping_mul(step)) % m) as usize)
+    }
+
+    fn contains(&self, fp: u64) -> bool {
+        Self::positions(fp, self.bits.len(), self.hashes)
+            .all(|i| self.bits[i / 64] & (1 << (i % 64)) != 0)
+    }
+
+    /// Adds `fp` and returns whether it was (probably) already there.
+    fn insert(&mut self, fp: u64) -> bool {
+        if self.contains(fp) {
+            return true;
+        }
+        for i in Self::positions(fp, self.bits.len(), self.hashes) {
+            self.bits[i / 64] |= 1 << (i % 64);
+        }
+        self.len += 1;
+        false
+    }
+
+    fn clear(&mut self) {
+        self.bits.fill(0);
+        self.len = 0;
+    }
+}
+
+enum Memory {
+    Exact {
+        seen: HashSet<u64>,
+        order: VecDeque<(u64, SystemTime)>,
+        max: usize,
+    },
+    /// `current` fills for one window, then becomes `previous`, so events
+    /// are remembered for between one and two windows.
+    Bloom {
+        current: Bloom,
+        previous: Bloom,
+        started: Option<SystemTime>,
+    },
+}
+
+impl Memory {
+    /// Records `fp` unless it is a duplicate, and returns whether it was.
+    fn check(&mut self, fp: u64, now: SystemTime, window: Window) -> bool {
+        let older = |t: SystemTime, d: Duration| now.duration_since(t).is_ok_and(|age| age >= d);
+        match self {
+            Memory::Exact { seen, order, max } => {
+                if let Window::Time(d) = window {
+                    while order.front().is_some_and(|&(_, t)| older(t, d)) {
+                        seen.remove(&order.pop_front().expect("front exists").0);
+                    }
+                }
+                if !seen.insert(fp) {
+                    return true;
+                }
+                order.push_back((fp, now));
+                let max = match window {
+                    Window::Count(n) => n.min(*max),
+                    Window::Time(_) => *max,
+                };
+                while order.len() > max {
+                    seen.remove(&order.pop_front().expect("longer than max").0);
+                }
+                false
+            }
+            Memory::Bloom { current, previous, started } => {
+                let started = started.get_or_insert(now);
+                let rotate = match window {
+                    Window::Count(n) => current.len >= n,
+                    Window::Time(d) => older(*started, d),
+                };
+                if rotate {
+                    std::mem::swap(current, previous);
+                    current.clear();
+                    if matches!(window, Window::Time(d) if older(*started, d * 2)) {
+                        previous.clear();
+                    }
+                    *started = now;
+                }
+                previous.contains(fp) || current.insert(fp)
+            }
+        }
+    }
+}
+
+/// Passes the first event of each identity and drops the rest within the
+/// window. See the [module docs](self) for what each kind of memory costs.
+pub struct Dedup {
+    identity: Identity,
+    window: Window,
+    memory: Mutex<Memory>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Dedup {
+    pub fn exact(identity: Identity, window: Window) -> Self {
+        let memory = Memory::Exact {
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+            max: DEFAULT_MAX_ENTRIES,
+        };
+        Self::with_memory(identity, window, memory)
+    }
+
+    /// Bloom filters remembering the last `n` distinct events.
+    pub fn bloom_last(identity: Identity, n: usize, fp_rate: f64) -> Self {
+        Self::bloom(identity, Window::Count(n), n, fp_rate)
+    }
+
+    /// Bloom filters remembering events seen within `window`, sized for
+    /// `expected` distinct events per window. More events than that raise
+    /// the false positive rate above `fp_rate`.
+    pub fn bloom_within(
+        identity: Identity,
+        window: Duration,
+        expected: usize,
+        fp_rate: f64,
+    ) -> Self {
+        Self::bloom(identity, Window::Time(window), expected, fp_rate)
+    }
+
+    fn bloom(identity: Identity, window: Window, expected: usize, fp_rate: f64) -> Self {
+        let memory = Memory::Bloom {
+            current: Bloom::new(expected, fp_rate),
+            previous: Bloom::new(expected, fp_rate),
+            started: None,
+        };
+        Self::with_memory(identity, window, memory)
+    }
+
+    fn with_memory(identity: Identity, window: Window, memory: Memory) -> Self {
+        Self { identity, window, memory: Mutex::new(memory), clock: Arc::new(SystemClock) }
+    }
+
+    /// Caps how many events exact memory remembers, whatever the window;
+    /// defaults to [`DEFAULT_MAX_ENTRIES`].
+    pub fn max_entries(mut self, max: usize) -> Self {
+        if let Memory::Exact { max: m, .. } =
+            self.memory.get_mut().unwrap_or_else(PoisonError::into_inner)
+        {
+            *m = max;
+        }
+        self
+    }
+
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Whether `evt` repeats one seen within the window. Counts as seeing it.
+    pub fn is_duplicate(&self, evt: &TelemetryEvent) -> bool {
+        let fp = self.identity.fingerprint(evt);
+        let now = self.clock.now();
+        self.memory.lock().unwrap_or_else(PoisonError::into_inner).check(fp, now, self.window)
+    }
+}
+
+impl Processor for Dedup {
+    fn name(&self) -> &str {
+        "dedup"
+    }
+
+    fn process(&self, input: TelemetryEvent) -> Result<Output, ProcessError> {
+        Ok(match self.is_duplicate(&input) {
+            true => Output::Drop,
+            false => input.into(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+    use crate::telemetry::ManualClock;
+
+    fn order(user: &str, order: i64) -> TelemetryEvent {
+        let mut evt = TelemetryEvent::new("order");
+        evt.insert("user", user);
+        evt.insert("order", order);
+        evt
+    }
+
+    #[test]
+    fn by_id_within_a_count_window() {
+        for dedup in [
+            Dedup::exact(Identity::Id, Window::Count(2)),
+            Dedup::bloom_last(Identity::Id, 2, 0.001),
+        ] {
+            let (a, b, c) = (order("u", 1), order("u", 1), order("u", 1));
+            assert!(!dedup.is_duplicate(&a));
+            assert!(dedup.is_duplicate(&a.clone()));
+            assert!(!dedup.is_duplicate(&b));
+            assert!(!dedup.is_duplicate(&c));
+            assert!(dedup.is_duplicate(&c));
+        }
+        let exact = Dedup::exact(Identity::Id, Window::Count(2));
+        let events: Vec<_> = (0..3).map(|i| order("u", i)).collect();
+        events.iter().for_each(|e| assert!(!exact.is_duplicate(e)));
+        assert!(!exact.is_duplicate(&events[0]));
+    }
+
+    #[test]
+    fn by_fields_within_a_time_window() {
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH));
+        let fields = Identity::fields(["user", "order"]);
+        for dedup in [
+            Dedup::exact(fields.clone(), Window::Time(Duration::from_secs(10))),
+            Dedup::bloom_within(fields.clone(), Duration::from_secs(10), 100, 0.001),
+        ] {
+            let dedup = dedup.clock(clock.clone());
+            assert!(matches!(dedup.process(order("a", 1)).unwrap(), Output::One(_)));
+            assert!(matches!(dedup.process(order("a", 1)).unwrap(), Output::Drop));
+            assert!(!dedup.is_duplicate(&order("a", 2)));
+            assert!(!dedup.is_duplicate(&order("b", 1)));
+            let mut other_kind = order("a", 1);
+            other_kind.kind = "refund".into();
+            assert!(!dedup.is_duplicate(&other_kind));
+            let mut as_string = order("a", 1);
+            as_string.insert("order", "1");
+            assert!(!dedup.is_duplicate(&as_string));
+
+            clock.advance(Duration::from_secs(25));
+            assert!(!dedup.is_duplicate(&order("a", 1)));
+        }
+    }
+
+    #[test]
+    fn fields_hash_by_structure() {
+        let tagged = |tags: Value| {
+            let mut evt = TelemetryEvent::new("order");
+            evt.insert("tags", tags);
+            evt
+        };
+        let identity = Identity::fields(["tags"]);
+        let fp = |evt: &TelemetryEvent| identity.fingerprint(evt);
+        let joined = tagged(vec![Value::from("a, b")].into());
+        let split = tagged(vec![Value::from("a"), Value::from("b")].into());
+        assert_ne!(fp(&joined), fp(&split));
+        assert_ne!(fp(&tagged(Value::Bytes(vec![1]))), fp(&tagged(vec![Value::Int(1)].into())));
+        assert_ne!(fp(&tagged(0.0.into())), fp(&tagged((-0.0).into())));
+
+        let map = |pairs: &[(&str, i64)]| {
+            Value::Map(pairs.iter().map(|&(k, v)| (k.to_owned(), v.into())).collect())
+        };
+        assert_eq!(
+            fp(&tagged(map(&[("a", 1), ("b", 2)]))),
+            fp(&tagged(map(&[("b", 2), ("a", 1)])))
+        );
+        assert_ne!(fp(&tagged(map(&[("a", 1)]))), fp(&tagged(map(&[("a", 2)]))));
+    }
+
+    #[test]
+    fn exact_windows_are_capped() {
+        for window in [Window::Time(Duration::from_secs(60)), Window::Count(1000)] {
+            let dedup = Dedup::exact(Identity::Id, window).max_entries(3);
+            let events: Vec<_> = (0..4).map(|i| order("u", i)).collect();
+            events.iter().for_each(|e| assert!(!dedup.is_duplicate(e)));
+            assert!(dedup.is_duplicate(&events[3]));
+            assert!(!dedup.is_duplicate(&events[0]));
+        }
+    }
+
+    #[test]
+    fn bloom_false_positives_stay_near_the_target() {
+        let dedup = Dedup::bloom_last(Identity::fields(["order"]), 10_000, 0.01);
+        let false_positives = (0..10_000).filter(|&i| dedup.is_duplicate(&order("u", i))).count();
+        assert!(false_positives < 200, "{false_positives}");
+    }
+}
@@ -0,0 +1,559 @@
+//! Small processors that reshape [`TelemetryEvent::payload`]: rename, copy,
+//! move, drop and default keys, coerce value types, and rewrite `kind` from
+//! a template.
+//!
+//! They all work on top-level keys only; a key containing `.` is just a key.
+//! A key an event does not have is skipped, never an error, so one chain of
+//! these can serve events of several shapes. Where a destination key may
+//! already be set, [`OnConflict`] says who wins.
+
+use std::error::Error;
+use std::fmt;
+
+use super::{Output, Payload, ProcessError, Processor, TelemetryEvent, Value};
+
+/// What to do when a destination key is already set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnConflict {
+    /// Replace the existing value.
+    #[default]
+    Overwrite,
+    /// Keep the existing value and leave the source key where it was.
+    Keep,
+}
+
+fn pairs<K, V>(pairs: impl IntoIterator<Item = (K, V)>) -> Vec<(String, String)>
+where
+    K: Into<String>,
+    V: Into<String>,
+{
+    pairs.into_iter().map(|(from, to)| (from.into(), to.into())).collect()
+}
+
+/// Renames keys in place. All pairs are applied at once, so `a -> b` and
+/// `b -> a` swap the two values.
+pub struct RenameFields {
+    pairs: Vec<(String, String)>,
+    on_conflict: OnConflict,
+}
+
+impl RenameFields {
+    pub fn new<K, V>(renames: impl IntoIterator<Item = (K, V)>) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        Self { pairs: pairs(renames), on_conflict: OnConflict::default() }
+    }
+
+    pub fn on_conflict(mut self, on_conflict: OnConflict) -> Self {
+        self.on_conflict = on_conflict;
+        self
+    }
+}
+
+impl Processor for RenameFields {
+    fn name(&self) -> &str {
+        "rename_fields"
+    }
+
+    fn process(&self, mut input: TelemetryEvent) -> Result<Output, ProcessError> {
+        let payload = &mut input.payload;
+        // Under `Keep`, a rename onto a present key happens only if that
+        // key's own rename does, so settle which ones happen first. Start
+        // from every rename with a value and strike the blocked ones until
+        // none are; cycles survive, so swaps still swap.
+        let mut happens: Vec<bool> =
+            self.pairs.iter().map(|(from, _)| payload.contains_key(from)).collect();
+        if self.on_conflict == OnConflict::Keep {
+            let vacated = |to: &str, happens: &[bool]| {
+                self.pairs.iter().zip(happens).any(|((from, _), &h)| h && from == to)
+            };
+            while let Some(i) = (0..self.pairs.len()).find(|&i| {
+                let to = &self.pairs[i].1;
+                happens[i] && payload.contains_key(to) && !vacated(to, &happens)
+            }) {
+                happens[i] = false;
+            }
+        }
+        let mut taken = Vec::with_capacity(self.pairs.len());
+        for ((from, to), _) in self.pairs.iter().zip(happens).filter(|(_, h)| *h) {
+            if let Some(value) = payload.remove(from) {
+                taken.push((to, value));
+            }
+        }
+        for (to, value) in taken {
+            payload.insert(to.clone(), value);
+        }
+        Ok(input.into())
+    }
+}
+
+/// Copies values to further keys, leaving the originals in place.
+pub struct CopyFields {
+    pairs: Vec<(String, String)>,
+    on_conflict: OnConflict,
+}
+
+impl CopyFields {
+    pub fn new<K, V>(copies: impl IntoIterator<Item = (K, V)>) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        Self { pairs: pairs(copies), on_conflict: OnConflict::default() }
+    }
+
+    pub fn on_conflict(mut self, on_conflict: OnConflict) -> Self {
+        self.on_conflict = on_conflict;
+        self
+    }
+}
+
+impl Processor for CopyFields {
+    fn name(&self) -> &str {
+        "copy_fields"
+    }
+
+    fn process(&self, mut input: TelemetryEvent) -> Result<Output, ProcessError> {
+        let payload = &mut input.payload;
+        for (from, to) in &self.pairs {
+            if self.on_conflict == OnConflict::Keep && payload.contains_key(to) {
+                continue;
+            }
+            if let Some(value) = payload.get(from).cloned() {
+                payload.insert(to.clone(), value);
+            }
+        }
+        Ok(input.into())
+    }
+}
+
+/// Moves keys into the map at `into`, creating it when the event has none.
+/// For moving a value to another top-level key, see [`RenameFields`].
+pub struct MoveFields {
+    keys: Vec<String>,
+    into: String,
+    on_conflict: OnConflict,
+}
+
+impl MoveFields {
+    pub fn new(keys: impl IntoIterator<Item = impl Into<String>>, into: impl Into<String>) -> Self {
+        Self {
+            keys: keys.into_iter().map(Into::into).collect(),
+            into: into.into(),
+            on_conflict: OnConflict::default(),
+        }
+    }
+
+    /// Applies both to keys already in the target map and to a target that
+    /// is set but is not a map, which [`OnConflict::Overwrite`] replaces.
+    pub fn on_conflict(mut self, on_conflict: OnConflict) -> Self {
+        self.on_conflict = on_conflict;
+        self
+    }
+}
+
+impl Processor for MoveFields {
+    fn name(&self) -> &str {
+        "move_fields"
+    }
+
+    fn process(&self, mut input: TelemetryEvent) -> Result<Output, ProcessError> {
+        if !self.keys.iter().any(|k| k != &self.into && input.payload.contains_key(k)) {
+            return Ok(input.into());
+        }
+        let mut target = match input.payload.remove(&self.into) {
+            Some(Value::Map(map)) => map,
+            None => Payload::new(),
+            Some(other) => match self.on_conflict {
+                OnConflict::Overwrite => Payload::new(),
+                OnConflict::Keep => {
+                    input.payload.insert(self.into.clone(), other);
+                    return Ok(input.into());
+                }
+            },
+        };
+        for key in self.keys.iter().filter(|k| **k != self.into) {
+            if self.on_conflict == OnConflict::Keep && target.contains_key(key) {
+                continue;
+            }
+            if let Some(value) = input.payload.remove(key) {
+                target.insert(key.clone(), value);
+            }
+        }
+        input.payload.insert(self.into.clone(), Value::Map(target));
+        Ok(input.into())
+    }
+}
+
+pub struct DropFields {
+    keys: Vec<String>,
+}
+
+impl DropFields {
+    pub fn new(keys: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self { keys: keys.into_iter().map(Into::into).collect() }
+    }
+}
+
+impl Processor for DropFields {
+    fn name(&self) -> &str {
+        "drop_fields"
+    }
+
+    fn process(&self, mut input: TelemetryEvent) -> Result<Output, ProcessError> {
+        for key in &self.keys {
+            input.payload.remove(key);
+        }
+        Ok(input.into())
+    }
+}
+
+/// Sets keys the event does not have; values already present always win.
+pub struct SetDefaults {
+    defaults: Payload,
+}
+
+impl SetDefaults {
+    pub fn new<K, V>(defaults: impl IntoIterator<Item = (K, V)>) -> Self
+    where
+        K: Into<String>,
+        V: Into<Value>,
+    {
+        Self { defaults: defaults.into_iter().map(|(k, v)| (k.into(), v.into())).collect() }
+    }
+}
+
+impl Processor for SetDefaults {
+    fn name(&self) -> &str {
+        "set_defaults"
+    }
+
+    fn process(&self, mut input: TelemetryEvent) -> Result<Output, ProcessError> {
+        for (key, value) in &self.defaults {
+            input.payload.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+        Ok(input.into())
+    }
+}
+
+/// A scalar type [`Coerce`] converts to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldType {
+    String,
+    Int,
+    Float,
+    Bool,
+}
+
+impl FieldType {
+    /// The name used in config files, as in [`Value::type_name`].
+    pub fn from_name(name: &str) -> Option<FieldType> {
+        match name {
+            "string" => Some(FieldType::String),
+            "int" => Some(FieldType::Int),
+            "float" => Some(FieldType::Float),
+            "bool" => Some(FieldType::Bool),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FieldType::String => "string",
+            FieldType::Int => "int",
+            FieldType::Float => "float",
+            FieldType::Bool => "bool",
+        }
+    }
+
+    /// Strings are trimmed and parsed; `"true"`, `"yes"` and `"1"` (and
+    /// their opposites) are booleans, as are the integers 0 and 1. Floats
+    /// become ints only when they are whole. Lists, maps and bytes convert
+    /// to nothing but themselves.
+    fn convert(self, value: &Value) -> Option<Value> {
+        match (self, value) {
+            (FieldType::String, Value::String(_))
+            | (FieldType::Int, Value::Int(_))
+            | (FieldType::Float, Value::Float(_))
+            | (FieldType::Bool, Value::Bool(_)) => Some(value.clone()),
+            (FieldType::String, Value::Int(_) | Value::Float(_) | Value::Bool(_)) => {
+                Some(Value::String(value.to_string()))
+            }
+            (FieldType::Int, Value::Float(f)) => {
+                let whole = f.fract() == 0.0 && *f >= i64::MIN as f64 && *f < i64::MAX as f64;
+                whole.then_some(Value::Int(*f as i64))
+            }
+            (FieldType::Int, Value::Bool(b)) => Some(Value::Int(*b as i64)),
+            (FieldType::Int, Value::String(s)) => s.trim().parse().ok().map(Value::Int),
+            (FieldType::Float, Value::Int(n)) => Some(Value::Float(*n as f64)),
+            (FieldType::Float, Value::String(s)) => s.trim().parse().ok().map(Value::Float),
+            (FieldType::Bool, Value::Int(0)) => Some(Value::Bool(false)),
+            (FieldType::Bool, Value::Int(1)) => Some(Value::Bool(true)),
+            (FieldType::Bool, Value::String(s)) => match s.trim().to_ascii_lowercase().as_str() {
+                "true" | "yes" | "1" => Some(Value::Bool(true)),
+                "false" | "no" | "0" => Some(Value::Bool(false)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Converts values to the given types. A value that does not convert fails
+/// the event unless the processor is [`lenient`](Self::lenient).
+pub struct Coerce {
+    fields: Vec<(String, FieldType)>,
+    lenient: bool,
+}
+
+impl Coerce {
+    pub fn new(fields: impl IntoIterator<Item = (impl Into<String>, FieldType)>) -> Self {
+        Self { fields: fields.into_iter().map(|(k, t)| (k.into(), t)).collect(), lenient: false }
+    }
+
+    /// Leaves values that do not convert as they are.
+    pub fn lenient(mut self) -> Self {
+        self.lenient = true;
+        self
+    }
+}
+
+impl Processor for Coerce {
+    fn name(&self) -> &str {
+        "coerce"
+    }
+
+    fn process(&self, mut input: TelemetryEvent) -> Result<Output, ProcessError> {
+        for (key, ty) in &self.fields {
+            let Some(value) = input.payload.get_mut(key) else { continue };
+            match ty.convert(value) {
+                Some(converted) => *value = converted,
+                None if self.lenient => {}
+                None => {
+                    return Err(ProcessError::new(format!(
+                        "cannot coerce `{key}` from {} to {}",
+                        value.type_name(),
+                        ty.name()
+                    )))
+                }
+            }
+        }
+        Ok(input.into())
+    }
+}
+
+/// A template for [`KindTemplate`] that does not parse.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateError {
+    pub template: String,
+    pub message: &'static str,
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid template `{}`: {}", self.template, self.message)
+    }
+}
+
+impl Error for TemplateError {}
+
+enum Part {
+    Text(String),
+    Kind,
+    Field(String),
+}
+
+/// Rewrites `kind` from a template such as `"{kind}.{status}"`. `{kind}` is
+/// the current kind, any other `{name}` is that payload value as it
+/// [displays](Value#impl-Display-for-Value), and `{{` and `}}` are literal
+/// braces. An event missing any of the named keys keeps its kind.
+pub struct KindTemplate {
+    parts: Vec<Part>,
+}
+
+impl KindTemplate {
+    pub fn new(template: &str) -> Result<Self, TemplateError> {
+        let err = |message| TemplateError { template: template.to_owned(), message };
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = rest.find('}').ok_or_else(|| err("unclosed `{`"))?;
+                    let name = &rest[..end];
+                    if name.is_empty() || name.contains('{') {
+                        return Err(err("placeholders need a key name between `{` and `}`"));
+                    }
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(match name {
+                        "kind" => Part::Kind,
+                        _ => Part::Field(name.to_owned()),
+                    });
+                    chars = rest[end + 1..].chars();
+                }
+                '}' => return Err(err("unmatched `}`; write `}}` for a literal brace")),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(Self { parts })
+    }
+}
+
+impl Processor for KindTemplate {
+    fn name(&self) -> &str {
+        "kind_template"
+    }
+
+    fn process(&self, mut input: TelemetryEvent) -> Result<Output, ProcessError> {
+        let mut kind = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => kind.push_str(text),
+                Part::Kind => kind.push_str(&input.kind),
+                Part::Field(key) => match input.get(key) {
+                    Some(value) => kind.push_str(&value.to_string()),
+                    None => return Ok(input.into()),
+                },
+            }
+        }
+        input.kind = kind;
+        Ok(input.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event() -> TelemetryEvent {
+        let mut evt = TelemetryEvent::new("http");
+        evt.insert("a", 1);
+        evt.insert("b", "two");
+        evt
+    }
+
+    fn run(p: &dyn Processor, evt: TelemetryEvent) -> TelemetryEvent {
+        match p.process(evt).unwrap() {
+            Output::One(evt) => evt,
+            _ => panic!("expected one event"),
+        }
+    }
+
+    #[test]
+    fn rename_copy_and_drop() {
+        let out = run(&RenameFields::new([("a", "b"), ("b", "a"), ("missing", "c")]), event());
+        assert_eq!((out.get("a"), out.get("b")), (Some(&Value::from("two")), Some(&Value::Int(1))));
+        assert_eq!(out.get("c"), None);
+
+        let keep = RenameFields::new([("a", "b")]).on_conflict(OnConflict::Keep);
+        assert_eq!(run(&keep, event()).payload, event().payload);
+        let chain = RenameFields::new([("a", "b"), ("b", "c")]).on_conflict(OnConflict::Keep);
+        let mut evt = event();
+        evt.insert("c", 3);
+        assert_eq!(run(&chain, evt.clone()).payload, evt.payload);
+        evt.payload.remove("c");
+        let out = run(&chain, evt);
+        assert_eq!(
+            (out.get("a"), out.get("b"), out.get_str("c")),
+            (None, Some(&1.into()), Some("two"))
+        );
+        let swap = RenameFields::new([("a", "b"), ("b", "a")]).on_conflict(OnConflict::Keep);
+        assert_eq!(run(&swap, event()).get_str("a"), Some("two"));
+        let out = run(&RenameFields::new([("a", "b")]), event());
+        assert_eq!((out.get("a"), out.get("b")), (None, Some(&Value::Int(1))));
+
+        let out = run(&CopyFields::new([("a", "b"), ("a", "c"), ("missing", "d")]), event());
+        assert_eq!(
+            (out.get("a"), out.get("b"), out.get("c")),
+            (Some(&1.into()), Some(&1.into()), Some(&1.into()))
+        );
+        assert_eq!(out.get("d"), None);
+        let out = run(&CopyFields::new([("a", "b")]).on_conflict(OnConflict::Keep), event());
+        assert_eq!(out.get_str("b"), Some("two"));
+
+        let out = run(&DropFields::new(["a", "missing"]), event());
+        assert_eq!(out.payload.keys().collect::<Vec<_>>(), ["b"]);
+    }
+
+    #[test]
+    fn move_into_map() {
+        let out = run(&MoveFields::new(["a", "b", "missing"], "m"), event());
+        let moved: Payload = [("a".into(), 1.into()), ("b".into(), "two".into())].into();
+        assert_eq!(out.payload, Payload::from([("m".into(), Value::Map(moved))]));
+
+        let mut evt = event();
+        evt.insert("m", Value::Map(Payload::from([("a".into(), 9.into())])));
+        let out = run(&MoveFields::new(["a"], "m").on_conflict(OnConflict::Keep), evt.clone());
+        assert_eq!(out.payload, evt.payload);
+        let out = run(&MoveFields::new(["a"], "m"), evt);
+        assert_eq!(out.get("m"), Some(&Value::Map(Payload::from([("a".into(), 1.into())]))));
+
+        let mut evt = event();
+        evt.insert("m", "scalar");
+        let out = run(&MoveFields::new(["a"], "m").on_conflict(OnConflict::Keep), evt.clone());
+        assert_eq!(out.payload, evt.payload);
+        assert_eq!(run(&MoveFields::new(["missing"], "m"), event()).payload, event().payload);
+    }
+
+    #[test]
+    fn defaults_never_overwrite() {
+        let out = run(&SetDefaults::new([("a", Value::Int(7)), ("c", Value::from("x"))]), event());
+        assert_eq!((out.get("a"), out.get_str("c")), (Some(&Value::Int(1)), Some("x")));
+    }
+
+    #[test]
+    fn coerce() {
+        let mut evt = event();
+        evt.insert("n", " 42 ");
+        evt.insert("f", 3.0);
+        evt.insert("flag", "Yes");
+        let p = Coerce::new([
+            ("n", FieldType::Int),
+            ("f", FieldType::Int),
+            ("flag", FieldType::Bool),
+            ("a", FieldType::String),
+            ("missing", FieldType::Float),
+        ]);
+        let out = run(&p, evt);
+        assert_eq!(out.get("n"), Some(&Value::Int(42)));
+        assert_eq!(out.get("f"), Some(&Value::Int(3)));
+        assert_eq!(out.get("flag"), Some(&Value::Bool(true)));
+        assert_eq!(out.get_str("a"), Some("1"));
+        assert_eq!(out.get("missing"), None);
+
+        let strict = Coerce::new([("b", FieldType::Float)]);
+        let err = strict.process(event()).err().unwrap();
+        assert_eq!(err.message(), "cannot coerce `b` from string to float");
+        let out = run(&strict.lenient(), event());
+        assert_eq!(out.get_str("b"), Some("two"));
+    }
+
+    #[test]
+    fn kind_template() {
+        let t = KindTemplate::new("{kind}.{b}-{a}{{x}}").unwrap();
+        assert_eq!(run(&t, event()).kind, "http.two-1{x}");
+        let missing = KindTemplate::new("{kind}.{status}").unwrap();
+        assert_eq!(run(&missing, event()).kind, "http");
+
+        for bad in ["{kind", "{}", "a}b"] {
+            assert!(KindTemplate::new(bad).is_err(), "{bad}");
+        }
+    }
+}
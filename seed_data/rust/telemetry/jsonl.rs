@@ -0,0 +1,236 @@
+//! JSON Lines framing for [`TelemetryEvent`]: one JSON object per line.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+
+use super::codec::DEFAULT_MAX_FRAME_LEN;
+use super::{Pipeline, PipelineError, TelemetryEvent};
+
+#[derive(Debug)]
+pub enum JsonLinesError {
+    Io(io::Error),
+    /// A line that is not a valid event. Readers skip these and carry on.
+    Malformed {
+        line: usize,
+        source: serde_json::Error,
+    },
+    Pipeline {
+        line: usize,
+        source: Box<PipelineError>,
+    },
+}
+
+impl fmt::Display for JsonLinesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonLinesError::Io(e) => write!(f, "i/o error: {e}"),
+            JsonLinesError::Malformed { line, source } => {
+                write!(f, "line {line}: malformed event: {source}")
+            }
+            JsonLinesError::Pipeline { line, source } => write!(f, "line {line}: {source}"),
+        }
+    }
+}
+
+impl Error for JsonLinesError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            JsonLinesError::Io(e) => Some(e),
+            JsonLinesError::Malformed { source, .. } => Some(source),
+            JsonLinesError::Pipeline { source, .. } => Some(&**source),
+        }
+    }
+}
+
+impl From<io::Error> for JsonLinesError {
+    fn from(e: io::Error) -> Self {
+        JsonLinesError::Io(e)
+    }
+}
+
+/// Iterates the events of a JSON Lines stream. Blank lines are ignored and
+/// a malformed line, or one longer than [`DEFAULT_MAX_FRAME_LEN`], yields
+/// [`JsonLinesError::Malformed`] without ending the iteration; an I/O error
+/// is yielded once and ends it.
+pub struct JsonLinesReader<R> {
+    input: R,
+    buf: Vec<u8>,
+    line: usize,
+    done: bool,
+}
+
+impl<R: BufRead> JsonLinesReader<R> {
+    pub fn new(input: R) -> Self {
+        Self { input, buf: Vec::new(), line: 0, done: false }
+    }
+
+    /// 1-based number of the line last read.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+}
+
+impl<R: BufRead> Iterator for JsonLinesReader<R> {
+    type Item = Result<TelemetryEvent, JsonLinesError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            self.buf.clear();
+            let limit = DEFAULT_MAX_FRAME_LEN as u64 + 1;
+            match self.input.by_ref().take(limit).read_until(b'\n', &mut self.buf) {
+                Ok(0) => self.done = true,
+                Ok(_) => {
+                    self.line += 1;
+                    let line = self.line;
+                    if self.buf.len() > DEFAULT_MAX_FRAME_LEN && self.buf.last() != Some(&b'\n') {
+                        if let Err(e) = skip_line(&mut self.input) {
+                            self.done = true;
+                            return Some(Err(e.into()));
+                        }
+                        let message = format!("line is longer than {DEFAULT_MAX_FRAME_LEN} bytes");
+                        let source = serde::de::Error::custom(message);
+                        return Some(Err(JsonLinesError::Malformed { line, source }));
+                    }
+                    if self.buf.trim_ascii().is_empty() {
+                        continue;
+                    }
+                    return Some(
+                        serde_json::from_slice(&self.buf)
+                            .map_err(|source| JsonLinesError::Malformed { line, source }),
+                    );
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e.into()));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Consumes input up to and including the next newline.
+fn skip_line(input: &mut impl BufRead) -> io::Result<()> {
+    loop {
+        let buf = match input.fill_buf() {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        let (used, found) = match buf.iter().position(|&b| b == b'\n') {
+            Some(i) => (i + 1, true),
+            None => (buf.len(), buf.is_empty()),
+        };
+        input.consume(used);
+        if found {
+            return Ok(());
+        }
+    }
+}
+
+pub struct JsonLinesWriter<W> {
+    out: W,
+}
+
+impl<W: Write> JsonLinesWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+
+    pub fn write(&mut self, evt: &TelemetryEvent) -> io::Result<()> {
+        serde_json::to_writer(&mut self.out, evt)?;
+        self.out.write_all(b"\n")
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+/// Streams `input` through `pipeline` into `output`, one event per line.
+/// Malformed lines go to `on_malformed` and are skipped; I/O and pipeline
+/// failures stop the stream. Returns the number of events written.
+pub fn run_json_lines<R: BufRead, W: Write>(
+    pipeline: &Pipeline,
+    input: R,
+    output: W,
+    mut on_malformed: impl FnMut(JsonLinesError),
+) -> Result<usize, JsonLinesError> {
+    let mut reader = JsonLinesReader::new(input);
+    let mut writer = JsonLinesWriter::new(output);
+    let mut written = 0;
+    while let Some(next) = reader.next() {
+        let evt = match next {
+            Ok(evt) => evt,
+            Err(e @ JsonLinesError::Malformed { .. }) => {
+                on_malformed(e);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let line = reader.line();
+        for out in pipeline
+            .run(evt)
+            .map_err(|source| JsonLinesError::Pipeline { line, source: Box::new(source) })?
+        {
+            writer.write(&out)?;
+            written += 1;
+        }
+    }
+    writer.flush()?;
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_lines_are_skipped_with_their_line_number() {
+        let input = "{\"kind\":\"a\"}\n\nnot json\n{\"kind\":\"b\",\"payload\":{\"n\":1}}\n{\"payload\":{}}\n";
+        let results: Vec<_> = JsonLinesReader::new(input.as_bytes()).collect();
+        let lines: Vec<_> = results
+            .iter()
+            .map(|r| match r {
+                Ok(evt) => Ok(evt.kind.as_str()),
+                Err(JsonLinesError::Malformed { line, .. }) => Err(*line),
+                Err(e) => panic!("unexpected {e}"),
+            })
+            .collect();
+        assert_eq!(lines, [Ok("a"), Err(3), Ok("b"), Err(5)]);
+    }
+
+    #[test]
+    fn overlong_lines_are_malformed_and_skipped() {
+        let mut input = vec![b'x'; DEFAULT_MAX_FRAME_LEN + 1];
+        input.extend_from_slice(b"\n{\"kind\":\"a\"}\n");
+        let mut reader = JsonLinesReader::new(&input[..]);
+        let e = reader.next().unwrap().unwrap_err();
+        assert!(matches!(e, JsonLinesError::Malformed { line: 1, .. }), "{e}");
+        assert_eq!(reader.next().unwrap().unwrap().kind, "a");
+        assert_eq!(reader.line(), 2);
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn run_json_lines_reports_and_continues() {
+        let input = "{\"kind\":\"a\"}\n{oops\n{\"kind\":\"b\"}\n";
+        let mut out = Vec::new();
+        let mut malformed = Vec::new();
+        let written = run_json_lines(&Pipeline::new(), input.as_bytes(), &mut out, |e| {
+            malformed.push(e.to_string())
+        })
+        .unwrap();
+        assert_eq!(written, 2);
+        assert_eq!(malformed.len(), 1);
+        assert!(malformed[0].starts_with("line 2: malformed event"), "{}", malformed[0]);
+        let kinds: Vec<_> = JsonLinesReader::new(&out[..]).map(|r| r.unwrap().kind).collect();
+        assert_eq!(kinds, ["a", "b"]);
+    }
+}
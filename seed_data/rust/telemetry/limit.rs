@@ -0,0 +1,441 @@
+//! Token-bucket rate limiting, so one noisy `kind` (or user, or host)
+//! cannot crowd out everything else in a [`Pipeline`](super::Pipeline).
+//!
+//! Events are grouped into buckets by their kind or by one payload value.
+//! Each bucket refills at its [`Limit`]'s rate up to its burst size, and an
+//! event that finds its bucket empty is handled by the [`Overflow`] action.
+//! Every so often the limiter adds a [`SUMMARY_KIND`] event to its output
+//! counting what it limited since the last one.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime};
+
+use super::{
+    Clock, Output, Payload, ProcessError, Processor, Sink, SystemClock, TelemetryEvent, Value,
+};
+
+pub const SUMMARY_KIND: &str = "rate_limit.summary";
+pub const DEFAULT_SUMMARY_INTERVAL: Duration = Duration::from_secs(60);
+pub const DEFAULT_MAX_BUCKETS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    per_second: f64,
+    burst: f64,
+}
+
+impl Limit {
+    /// Allows bursts of one second's worth of events, and at least one.
+    pub fn per_second(rate: f64) -> Self {
+        let rate = rate.max(0.0);
+        Self { per_second: rate, burst: rate.ceil().max(1.0) }
+    }
+
+    pub fn burst(mut self, events: u32) -> Self {
+        self.burst = f64::from(events);
+        self
+    }
+}
+
+/// What happens to an event over its limit.
+pub enum Overflow {
+    Drop,
+    /// Pass the event on with this payload key set to `true`.
+    Tag(String),
+    /// Write the event to this sink instead of passing it on. The sink is
+    /// flushed after every event.
+    Divert(Box<dyn Sink>),
+}
+
+enum Action {
+    Drop,
+    Tag(String),
+    Divert(Mutex<Box<dyn Sink>>),
+}
+
+impl Action {
+    fn name(&self) -> &'static str {
+        match self {
+            Action::Drop => "drop",
+            Action::Tag(_) => "tag",
+            Action::Divert(_) => "divert",
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: SystemTime,
+    /// Creation order, to tell new buckets from established ones.
+    serial: u64,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: Limit, now: SystemTime) {
+        if let Ok(elapsed) = now.duration_since(self.updated) {
+            self.tokens = (self.tokens + elapsed.as_secs_f64() * limit.per_second).min(limit.burst);
+            self.updated = now;
+        }
+    }
+}
+
+struct State {
+    buckets: HashMap<String, Bucket>,
+    /// Only counted while summaries are on.
+    limited: HashMap<String, u64>,
+    limited_total: u64,
+    next_serial: u64,
+    last_summary: Option<SystemTime>,
+}
+
+pub struct RateLimiter {
+    by: Option<String>,
+    limit: Limit,
+    quotas: HashMap<String, Limit>,
+    action: Action,
+    max_buckets: usize,
+    summary_every: Option<Duration>,
+    clock: Arc<dyn Clock>,
+    state: Mutex<State>,
+}
+
+impl RateLimiter {
+    /// One bucket per event kind, each with `limit`.
+    pub fn per_kind(limit: Limit) -> Self {
+        Self {
+            by: None,
+            limit,
+            quotas: HashMap::new(),
+            action: Action::Drop,
+            max_buckets: DEFAULT_MAX_BUCKETS,
+            summary_every: Some(DEFAULT_SUMMARY_INTERVAL),
+            clock: Arc::new(SystemClock),
+            state: Mutex::new(State {
+                buckets: HashMap::new(),
+                limited: HashMap::new(),
+                limited_total: 0,
+                next_serial: 0,
+                last_summary: None,
+            }),
+        }
+    }
+
+    /// One bucket per value of the payload key `key`, as it displays.
+    /// Events without the key share a bucket.
+    pub fn per_key(key: impl Into<String>, limit: Limit) -> Self {
+        Self { by: Some(key.into()), ..Self::per_kind(limit) }
+    }
+
+    /// Gives the bucket for one kind (or key value) a limit of its own.
+    pub fn quota(mut self, bucket: impl Into<String>, limit: Limit) -> Self {
+        self.quotas.insert(bucket.into(), limit);
+        self
+    }
+
+    pub fn on_overflow(mut self, overflow: Overflow) -> Self {
+        self.action = match overflow {
+            Overflow::Drop => Action::Drop,
+            Overflow::Tag(key) => Action::Tag(key),
+            Overflow::Divert(sink) => Action::Divert(Mutex::new(sink)),
+        };
+        self
+    }
+
+    /// Caps how many buckets are tracked. Past the cap, the fullest are
+    /// forgotten to make room; a full bucket starts over the same, so this
+    /// only matters when more buckets are draining at once than the cap.
+    pub fn max_buckets(mut self, max: usize) -> Self {
+        self.max_buckets = max;
+        self
+    }
+
+    /// How often to emit a summary; `None` turns summaries off. A summary
+    /// goes out with the first event processed once the interval is up,
+    /// and only if something was limited.
+    pub fn summary_every(mut self, every: Option<Duration>) -> Self {
+        self.summary_every = every;
+        self
+    }
+
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    fn bucket_of(&self, evt: &TelemetryEvent) -> String {
+        match &self.by {
+            None => evt.kind.clone(),
+            Some(key) => evt.get(key).map(Value::to_string).unwrap_or_default(),
+        }
+    }
+
+    fn limit_of(&self, bucket: &str) -> Limit {
+        self.quotas.get(bucket).copied().unwrap_or(self.limit)
+    }
+
+    /// Takes a token for `bucket`, counting the event as limited if there
+    /// is none, and returns whether there was one along with any summary
+    /// that has come due.
+    fn admit(&self, bucket: String, now: SystemTime) -> (bool, Option<TelemetryEvent>) {
+        let limit = self.limit_of(&bucket);
+        let mut st = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if !st.buckets.contains_key(&bucket) {
+            if st.buckets.len() >= self.max_buckets {
+                self.evict(&mut st.buckets, now);
+            }
+            st.next_serial += 1;
+        }
+        let serial = st.next_serial;
+        let b = st.buckets.entry(bucket.clone()).or_insert(Bucket {
+            tokens: limit.burst,
+            updated: now,
+            serial,
+        });
+        b.refill(limit, now);
+        let allowed = b.tokens >= 1.0;
+        if allowed {
+            b.tokens -= 1.0;
+        } else if self.summary_every.is_some() {
+            st.limited_total += 1;
+            // Past the bucket cap, a limited bucket is only in the total.
+            if st.limited.len() < self.max_buckets || st.limited.contains_key(&bucket) {
+                *st.limited.entry(bucket).or_default() += 1;
+            }
+        }
+        (allowed, self.summary(&mut st, now))
+    }
+
+    /// Forgets a tenth of the buckets, at least one: the fullest, which lose
+    /// least by starting over full, and of those equally full the newest.
+    /// A stream of new keys thus churns through itself while established
+    /// buckets that are being limited stay put. Freeing a tenth at a time
+    /// keeps the scan to once per that many new buckets.
+    fn evict(&self, buckets: &mut HashMap<String, Bucket>, now: SystemTime) {
+        let mut missing: Vec<(f64, u64, String)> = buckets
+            .iter_mut()
+            .map(|(name, b)| {
+                let limit = self.limit_of(name);
+                b.refill(limit, now);
+                (limit.burst - b.tokens, b.serial, name.clone())
+            })
+            .collect();
+        let evict = (self.max_buckets / 10).max(1).min(missing.len());
+        if evict == 0 {
+            return;
+        }
+        missing.select_nth_unstable_by(evict - 1, |a, b| a.0.total_cmp(&b.0).then(b.1.cmp(&a.1)));
+        for (_, _, name) in &missing[..evict] {
+            buckets.remove(name);
+        }
+    }
+
+    fn summary(&self, st: &mut State, now: SystemTime) -> Option<TelemetryEvent> {
+        let every = self.summary_every?;
+        let since = *st.last_summary.get_or_insert(now);
+        match now.duration_since(since) {
+            Ok(elapsed) if elapsed >= every => st.last_summary = Some(now),
+            Ok(_) => return None,
+            Err(_) => {
+                st.last_summary = Some(now);
+                return None;
+            }
+        }
+        if st.limited_total == 0 {
+            return None;
+        }
+        let limited = std::mem::take(&mut st.limited);
+        let total = std::mem::take(&mut st.limited_total);
+        let buckets: Payload =
+            limited.into_iter().map(|(b, n)| (b, Value::Int(n as i64))).collect();
+        let mut evt = TelemetryEvent::new_with_clock(SUMMARY_KIND, &*self.clock);
+        evt.insert("limited", total as i64);
+        evt.insert("buckets", buckets);
+        evt.insert("by", self.by.as_deref().unwrap_or("kind"));
+        evt.insert("action", self.action.name());
+        Some(evt)
+    }
+}
+
+impl Processor for RateLimiter {
+    fn name(&self) -> &str {
+        "rate_limit"
+    }
+
+    fn process(&self, mut input: TelemetryEvent) -> Result<Output, ProcessError> {
+        let (allowed, summary) = self.admit(self.bucket_of(&input), self.clock.now());
+        let mut out = Vec::with_capacity(2);
+        if allowed {
+            out.push(input);
+        } else {
+            match &self.action {
+                Action::Drop => {}
+                Action::Tag(key) => {
+                    input.insert(key.clone(), true);
+                    out.push(input);
+                }
+                Action::Divert(sink) => {
+                    let mut sink = sink.lock().unwrap_or_else(PoisonError::into_inner);
+                    sink.write(std::slice::from_ref(&input))
+                        .and_then(|()| sink.flush())
+                        .map_err(|e| ProcessError::with_source("cannot divert limited event", e))?;
+                }
+            }
+        }
+        out.extend(summary);
+        Ok(match out.len() {
+            0 => Output::Drop,
+            1 => Output::One(out.remove(0)),
+            _ => Output::Many(out),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+    use crate::telemetry::ManualClock;
+
+    fn run(p: &RateLimiter, evt: TelemetryEvent) -> Vec<TelemetryEvent> {
+        let mut out = Vec::new();
+        p.process(evt).unwrap().append_to(&mut out);
+        out
+    }
+
+    fn limiter(clock: &Arc<ManualClock>, limit: Limit) -> RateLimiter {
+        RateLimiter::per_kind(limit).summary_every(None).clock(clock.clone())
+    }
+
+    #[test]
+    fn buckets_refill_per_kind_with_quotas() {
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH));
+        let p =
+            limiter(&clock, Limit::per_second(2.0)).quota("vip", Limit::per_second(1.0).burst(5));
+        let passed = |kind: &str, n: usize| {
+            (0..n).filter(|_| !run(&p, TelemetryEvent::new(kind)).is_empty()).count()
+        };
+        assert_eq!(passed("noisy", 10), 2);
+        assert_eq!(passed("quiet", 1), 1);
+        assert_eq!(passed("vip", 10), 5);
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(passed("noisy", 10), 1);
+        assert_eq!(passed("vip", 10), 0);
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(passed("noisy", 10), 2);
+    }
+
+    #[test]
+    fn tag_divert_and_per_key_buckets() {
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH));
+        let tagged = RateLimiter::per_key("user", Limit::per_second(1.0))
+            .on_overflow(Overflow::Tag("limited".into()))
+            .summary_every(None)
+            .clock(clock.clone());
+        let from = |user: &str| {
+            let mut evt = TelemetryEvent::new("click");
+            evt.insert("user", user);
+            evt
+        };
+        assert_eq!(run(&tagged, from("a"))[0].get("limited"), None);
+        assert_eq!(run(&tagged, from("b"))[0].get("limited"), None);
+        assert_eq!(run(&tagged, from("a"))[0].get("limited"), Some(&Value::Bool(true)));
+
+        struct Collect(Arc<Mutex<Vec<String>>>);
+        impl Sink for Collect {
+            fn name(&self) -> &str {
+                "collect"
+            }
+            fn write(&mut self, events: &[TelemetryEvent]) -> io::Result<()> {
+                self.0.lock().unwrap().extend(events.iter().map(|e| e.kind.clone()));
+                Ok(())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+            fn close(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let diverted = Arc::new(Mutex::new(Vec::new()));
+        let p = limiter(&clock, Limit::per_second(1.0))
+            .on_overflow(Overflow::Divert(Box::new(Collect(diverted.clone()))));
+        assert_eq!(run(&p, TelemetryEvent::new("x")).len(), 1);
+        assert!(run(&p, TelemetryEvent::new("x")).is_empty());
+        assert_eq!(*diverted.lock().unwrap(), ["x"]);
+    }
+
+    #[test]
+    fn summaries_count_what_was_limited() {
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH));
+        let p =
+            limiter(&clock, Limit::per_second(1.0)).summary_every(Some(Duration::from_secs(10)));
+        for _ in 0..4 {
+            run(&p, TelemetryEvent::new("a"));
+        }
+        run(&p, TelemetryEvent::new("b"));
+        run(&p, TelemetryEvent::new("b"));
+        clock.advance(Duration::from_secs(10));
+        let out = run(&p, TelemetryEvent::new("c"));
+        assert_eq!(out.len(), 2);
+        let summary = &out[1];
+        assert_eq!(summary.kind, SUMMARY_KIND);
+        assert_eq!(summary.get("limited"), Some(&Value::Int(4)));
+        let buckets = summary.get("buckets").and_then(Value::as_map).unwrap();
+        assert_eq!((buckets["a"].as_i64(), buckets["b"].as_i64()), (Some(3), Some(1)));
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(run(&p, TelemetryEvent::new("d")).len(), 1);
+    }
+
+    #[test]
+    fn bucket_count_is_capped() {
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH));
+        let p = limiter(&clock, Limit::per_second(1.0)).max_buckets(2);
+        run(&p, TelemetryEvent::new("a"));
+        run(&p, TelemetryEvent::new("b"));
+        run(&p, TelemetryEvent::new("c"));
+        assert!(p.state.lock().unwrap().buckets.len() <= 2);
+        clock.advance(Duration::from_secs(1));
+        run(&p, TelemetryEvent::new("d"));
+        run(&p, TelemetryEvent::new("e"));
+        let st = p.state.lock().unwrap();
+        let mut kept: Vec<_> = st.buckets.keys().collect();
+        kept.sort();
+        assert_eq!(kept, ["d", "e"]);
+    }
+
+    #[test]
+    fn new_keys_cannot_refill_a_limited_bucket() {
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH));
+        let p = RateLimiter::per_key("user", Limit::per_second(1.0).burst(2))
+            .max_buckets(3)
+            .summary_every(None)
+            .clock(clock.clone());
+        let from = |user: String| {
+            let mut evt = TelemetryEvent::new("click");
+            evt.insert("user", user);
+            evt
+        };
+        let passed = |p: &RateLimiter| {
+            let mut passed = 0;
+            for i in 0..100 {
+                passed += run(p, from("noisy".into())).len();
+                for j in 0..3 {
+                    run(p, from(format!("fresh-{i}-{j}")));
+                }
+            }
+            passed
+        };
+        assert_eq!(passed(&p), 2);
+        assert!(p.state.lock().unwrap().limited.is_empty());
+
+        let p = RateLimiter::per_key("user", Limit::per_second(1.0))
+            .max_buckets(3)
+            .summary_every(None)
+            .clock(clock.clone());
+        assert_eq!(passed(&p), 1);
+    }
+}
@@ -0,0 +1,242 @@
+//! Built-in per-stage instrumentation. Every processor and sink in a
+//! [`Pipeline`](super::Pipeline) gets counters and a latency histogram, read
+//! back as a [`PipelineStats`] snapshot.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bounds of the latency buckets. Anything slower lands in a final,
+/// unbounded bucket.
+pub const LATENCY_BUCKETS: [Duration; 14] = [
+    Duration::from_micros(1),
+    Duration::from_micros(5),
+    Duration::from_micros(10),
+    Duration::from_micros(50),
+    Duration::from_micros(100),
+    Duration::from_micros(500),
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(5),
+];
+
+/// A fixed-bucket histogram that can be updated from many threads.
+#[derive(Debug, Default)]
+struct Histogram {
+    counts: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, d: Duration) {
+        let bucket = LATENCY_BUCKETS.partition_point(|b| *b < d);
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos.fetch_add(d.as_nanos().try_into().unwrap_or(u64::MAX), Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> HistogramSnapshot {
+        let counts: Vec<u64> = self.counts.iter().map(|c| c.load(Ordering::Relaxed)).collect();
+        HistogramSnapshot {
+            count: counts.iter().sum(),
+            counts,
+            sum: Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    /// Per-bucket (not cumulative) counts, one for each of
+    /// [`LATENCY_BUCKETS`] plus the overflow bucket.
+    pub counts: Vec<u64>,
+    pub count: u64,
+    pub sum: Duration,
+}
+
+impl HistogramSnapshot {
+    pub fn mean(&self) -> Option<Duration> {
+        let count = u32::try_from(self.count).ok().filter(|c| *c > 0)?;
+        Some(self.sum / count)
+    }
+
+    /// The upper bound of the bucket holding the `q`th quantile, so an
+    /// overestimate by at most one bucket. `None` when nothing was recorded
+    /// or the quantile falls in the overflow bucket.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, n) in self.counts.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return LATENCY_BUCKETS.get(i).copied();
+            }
+        }
+        None
+    }
+}
+
+/// Live counters for one stage, shared with whichever thread runs it.
+#[derive(Debug)]
+pub(crate) struct StageMetrics {
+    name: String,
+    calls: AtomicU64,
+    events_in: AtomicU64,
+    events_out: AtomicU64,
+    errors: AtomicU64,
+    latency: Histogram,
+}
+
+impl StageMetrics {
+    pub(crate) fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            calls: AtomicU64::new(0),
+            events_in: AtomicU64::new(0),
+            events_out: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            latency: Histogram::default(),
+        }
+    }
+
+    /// Records one call that took `events_in` events. `events_out` is `None`
+    /// when the call failed.
+    pub(crate) fn record(&self, events_in: usize, events_out: Option<usize>, elapsed: Duration) {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        self.events_in.fetch_add(events_in as u64, Ordering::Relaxed);
+        match events_out {
+            Some(n) => self.events_out.fetch_add(n as u64, Ordering::Relaxed),
+            None => self.errors.fetch_add(1, Ordering::Relaxed),
+        };
+        self.latency.observe(elapsed);
+    }
+
+    pub(crate) fn snapshot(&self, index: usize) -> StageStats {
+        StageStats {
+            name: self.name.clone(),
+            index,
+            calls: self.calls.load(Ordering::Relaxed),
+            events_in: self.events_in.load(Ordering::Relaxed),
+            events_out: self.events_out.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            latency: self.latency.snapshot(),
+        }
+    }
+}
+
+/// Counters for one processor or sink. A call is one `process_batch` (or
+/// `process` for async stages and [`Pipeline::run_async`](super::Pipeline::run_async))
+/// or one sink write, and `latency` holds one sample per call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StageStats {
+    pub name: String,
+    /// Position in the pipeline, processors first and then sinks, matching
+    /// [`PipelineError::index`](super::PipelineError::index).
+    pub index: usize,
+    pub calls: u64,
+    pub events_in: u64,
+    /// Events the stage emitted. For sinks, events written.
+    pub events_out: u64,
+    /// Failed calls. Events in a failed call count as in but not out.
+    pub errors: u64,
+    pub latency: HistogramSnapshot,
+}
+
+impl StageStats {
+    /// Events that went in and did not come out, counting those lost to
+    /// errors.
+    pub fn dropped(&self) -> u64 {
+        self.events_in.saturating_sub(self.events_out)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PipelineStats {
+    pub stages: Vec<StageStats>,
+}
+
+impl PipelineStats {
+    pub(crate) fn collect<'a>(metrics: impl IntoIterator<Item = &'a StageMetrics>) -> Self {
+        Self { stages: metrics.into_iter().enumerate().map(|(i, m)| m.snapshot(i)).collect() }
+    }
+
+    /// The first stage with this name.
+    pub fn get(&self, name: &str) -> Option<&StageStats> {
+        self.stages.iter().find(|s| s.name == name)
+    }
+
+    /// The stage with the highest mean latency.
+    pub fn slowest(&self) -> Option<&StageStats> {
+        self.stages.iter().filter(|s| s.latency.count > 0).max_by_key(|s| s.latency.mean())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::{Output, Pipeline, ProcessError, Processor, TelemetryEvent};
+
+    struct KeepEven;
+
+    impl Processor for KeepEven {
+        fn name(&self) -> &str {
+            "keep_even"
+        }
+
+        fn process(&self, input: TelemetryEvent) -> Result<Output, ProcessError> {
+            match input.get("n").and_then(|n| n.as_i64()) {
+                Some(n) if n % 2 == 0 => Ok(input.into()),
+                Some(_) => Ok(Output::Drop),
+                None => Err(ProcessError::new("missing n")),
+            }
+        }
+    }
+
+    #[test]
+    fn pipeline_counts_events_per_stage() {
+        let mut pipeline = Pipeline::new();
+        pipeline.add(Box::new(KeepEven));
+        let mut evt = |n: i64| {
+            let mut evt = TelemetryEvent::new("n");
+            evt.insert("n", n);
+            evt
+        };
+        pipeline.run_batch((0..5).map(&mut evt)).unwrap();
+        pipeline.run(evt(6)).unwrap();
+        assert!(pipeline.run(TelemetryEvent::new("n")).is_err());
+
+        let stats = pipeline.stats();
+        let keep = stats.get("keep_even").unwrap();
+        assert_eq!((keep.calls, keep.events_in, keep.events_out, keep.errors), (3, 7, 4, 1));
+        assert_eq!(keep.dropped(), 3);
+        assert_eq!(keep.latency.count, 3);
+        assert_eq!(stats.slowest().map(|s| s.index), Some(0));
+    }
+
+    #[test]
+    fn histogram_buckets_and_quantiles() {
+        let h = Histogram::default();
+        for us in [0, 3, 3, 40, 2_000] {
+            h.observe(Duration::from_micros(us));
+        }
+        h.observe(Duration::from_secs(60));
+        let snap = h.snapshot();
+        assert_eq!(snap.count, 6);
+        assert_eq!(snap.counts[0], 1);
+        assert_eq!(snap.counts[1], 2);
+        assert_eq!(snap.counts[LATENCY_BUCKETS.len()], 1);
+        assert_eq!(snap.quantile(0.5), Some(Duration::from_micros(5)));
+        assert_eq!(snap.quantile(0.8), Some(Duration::from_millis(5)));
+        assert_eq!(snap.quantile(1.0), None);
+        assert_eq!(
+            HistogramSnapshot { counts: vec![], count: 0, sum: Duration::ZERO }.mean(),
+            None
+        );
+    }
+}
@@ -0,0 +1,456 @@
+//! Mapping between [`TelemetryEvent`] and OpenTelemetry log records, plus an
+//! OTLP/HTTP exporter.
+//!
+//! An event becomes one `LogRecord`: `kind` is the record's `event_name`,
+//! `timestamp` its `time_unix_nano`, `ingested_at` its
+//! `observed_time_unix_nano`, and every payload entry an attribute. The
+//! event id travels as the [`EVENT_ID_ATTR`] attribute; a payload entry of
+//! that name is not exported, so it cannot pass for the id.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use opentelemetry_proto::tonic::collector::logs::v1::ExportLogsServiceRequest;
+use opentelemetry_proto::tonic::common::v1::any_value::Value as Any;
+use opentelemetry_proto::tonic::common::v1::{AnyValue, ArrayValue, KeyValue, KeyValueList};
+use opentelemetry_proto::tonic::logs::v1::{LogRecord, ResourceLogs, ScopeLogs};
+use opentelemetry_proto::tonic::resource::v1::Resource;
+use prost::Message;
+
+use super::{Clock, EventId, Output, ProcessError, Processor, SystemClock, TelemetryEvent, Value};
+
+pub const EVENT_ID_ATTR: &str = "event.id";
+pub const DEFAULT_PATH: &str = "/v1/logs";
+
+/// How much of a collector's response is read; enough for the status line
+/// and an error message.
+const MAX_RESPONSE_LEN: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OtlpEncoding {
+    Protobuf,
+    Json,
+}
+
+impl OtlpEncoding {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            OtlpEncoding::Protobuf => "application/x-protobuf",
+            OtlpEncoding::Json => "application/json",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum OtlpError {
+    Io(io::Error),
+    Decode(String),
+    InvalidEndpoint(String),
+    Http { status: u16, body: String },
+}
+
+impl fmt::Display for OtlpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OtlpError::Io(e) => write!(f, "i/o error: {e}"),
+            OtlpError::Decode(msg) => write!(f, "invalid OTLP payload: {msg}"),
+            OtlpError::InvalidEndpoint(url) => write!(f, "invalid OTLP endpoint `{url}`"),
+            OtlpError::Http { status, body } => write!(f, "collector answered {status}: {body}"),
+        }
+    }
+}
+
+impl Error for OtlpError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            OtlpError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for OtlpError {
+    fn from(e: io::Error) -> Self {
+        OtlpError::Io(e)
+    }
+}
+
+pub fn to_log_record(evt: &TelemetryEvent) -> LogRecord {
+    let mut attributes = Vec::with_capacity(evt.payload.len() + 1);
+    attributes.push(key_value(EVENT_ID_ATTR, Any::StringValue(evt.id.to_string())));
+    attributes.extend(
+        evt.payload
+            .iter()
+            .filter(|(k, _)| *k != EVENT_ID_ATTR)
+            .map(|(k, v)| key_value(k, to_any(v))),
+    );
+    LogRecord {
+        time_unix_nano: unix_nanos(evt.timestamp),
+        observed_time_unix_nano: evt.ingested_at.map_or(0, unix_nanos),
+        event_name: evt.kind.clone(),
+        attributes,
+        ..Default::default()
+    }
+}
+
+/// The inverse of [`to_log_record`]. Records from other producers may lack
+/// an id or a timestamp; those get a fresh id and the observed time (or
+/// now) respectively. Attributes without a value are skipped.
+pub fn from_log_record(rec: &LogRecord) -> TelemetryEvent {
+    let mut evt = TelemetryEvent::new(rec.event_name.clone());
+    let mut id = None;
+    for kv in &rec.attributes {
+        let Some(value) = kv.value.as_ref().and_then(from_any) else { continue };
+        if kv.key == EVENT_ID_ATTR {
+            if let Some(raw) = value.as_str().and_then(|s| u128::from_str_radix(s, 16).ok()) {
+                id = Some(EventId::from_u128(raw));
+                continue;
+            }
+        }
+        evt.payload.insert(kv.key.clone(), value);
+    }
+    evt.id = id.unwrap_or_else(|| SystemClock.next_id());
+    let observed =
+        (rec.observed_time_unix_nano != 0).then(|| from_nanos(rec.observed_time_unix_nano));
+    evt.ingested_at = observed;
+    if rec.time_unix_nano != 0 {
+        evt.timestamp = from_nanos(rec.time_unix_nano);
+    } else if let Some(observed) = observed {
+        evt.timestamp = observed;
+    }
+    evt
+}
+
+/// Wraps the events in a single resource and scope, tagging the resource
+/// with `service.name`.
+pub fn to_request(events: &[TelemetryEvent], service_name: &str) -> ExportLogsServiceRequest {
+    ExportLogsServiceRequest {
+        resource_logs: vec![ResourceLogs {
+            resource: Some(Resource {
+                attributes: vec![key_value("service.name", Any::StringValue(service_name.into()))],
+                ..Default::default()
+            }),
+            scope_logs: vec![ScopeLogs {
+                log_records: events.iter().map(to_log_record).collect(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }],
+    }
+}
+
+pub fn from_request(req: &ExportLogsServiceRequest) -> Vec<TelemetryEvent> {
+    req.resource_logs
+        .iter()
+        .flat_map(|r| &r.scope_logs)
+        .flat_map(|s| &s.log_records)
+        .map(from_log_record)
+        .collect()
+}
+
+pub fn encode(req: &ExportLogsServiceRequest, encoding: OtlpEncoding) -> Vec<u8> {
+    match encoding {
+        OtlpEncoding::Protobuf => req.encode_to_vec(),
+        OtlpEncoding::Json => serde_json::to_vec(req).expect("OTLP messages always serialize"),
+    }
+}
+
+pub fn decode(bytes: &[u8], encoding: OtlpEncoding) -> Result<ExportLogsServiceRequest, OtlpError> {
+    match encoding {
+        OtlpEncoding::Protobuf => {
+            ExportLogsServiceRequest::decode(bytes).map_err(|e| OtlpError::Decode(e.to_string()))
+        }
+        OtlpEncoding::Json => {
+            serde_json::from_slice(bytes).map_err(|e| OtlpError::Decode(e.to_string()))
+        }
+    }
+}
+
+fn key_value(key: &str, value: Any) -> KeyValue {
+    KeyValue { key: key.to_owned(), value: Some(AnyValue { value: Some(value) }) }
+}
+
+fn to_any(v: &Value) -> Any {
+    match v {
+        Value::String(s) => Any::StringValue(s.clone()),
+        Value::Int(i) => Any::IntValue(*i),
+        Value::Float(f) => Any::DoubleValue(*f),
+        Value::Bool(b) => Any::BoolValue(*b),
+        Value::Bytes(b) => Any::BytesValue(b.clone()),
+        Value::List(l) => Any::ArrayValue(ArrayValue {
+            values: l.iter().map(|v| AnyValue { value: Some(to_any(v)) }).collect(),
+        }),
+        Value::Map(m) => Any::KvlistValue(KeyValueList {
+            values: m.iter().map(|(k, v)| key_value(k, to_any(v))).collect(),
+        }),
+    }
+}
+
+fn from_any(v: &AnyValue) -> Option<Value> {
+    Some(match v.value.as_ref()? {
+        Any::StringValue(s) => Value::String(s.clone()),
+        Any::IntValue(i) => Value::Int(*i),
+        Any::DoubleValue(f) => Value::Float(*f),
+        Any::BoolValue(b) => Value::Bool(*b),
+        Any::BytesValue(b) => Value::Bytes(b.clone()),
+        Any::ArrayValue(a) => Value::List(a.values.iter().filter_map(from_any).collect()),
+        Any::KvlistValue(kv) => Value::Map(
+            kv.values
+                .iter()
+                .filter_map(|kv| Some((kv.key.clone(), from_any(kv.value.as_ref()?)?)))
+                .collect(),
+        ),
+    })
+}
+
+fn unix_nanos(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos().try_into().unwrap_or(u64::MAX))
+}
+
+fn from_nanos(nanos: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_nanos(nanos)
+}
+
+/// Posts every event it sees to an OTLP/HTTP logs endpoint and passes it on
+/// unchanged. Batches go out as one request. Only plain `http://` is
+/// supported; put a local collector in front for TLS.
+pub struct OtlpHttpExporter {
+    authority: String,
+    path: String,
+    encoding: OtlpEncoding,
+    service_name: String,
+    timeout: Duration,
+}
+
+impl OtlpHttpExporter {
+    /// `endpoint` is `http://host:port[/path]`; the path defaults to
+    /// [`DEFAULT_PATH`].
+    pub fn new(endpoint: &str) -> Result<Self, OtlpError> {
+        let invalid = || OtlpError::InvalidEndpoint(endpoint.to_owned());
+        let rest = endpoint.strip_prefix("http://").ok_or_else(invalid)?;
+        let (authority, path) = rest.find('/').map_or((rest, ""), |i| rest.split_at(i));
+        if authority.is_empty() {
+            return Err(invalid());
+        }
+        let authority =
+            if authority.contains(':') { authority.to_owned() } else { format!("{authority}:80") };
+        Ok(Self {
+            authority,
+            path: if path.is_empty() { DEFAULT_PATH.to_owned() } else { path.to_owned() },
+            encoding: OtlpEncoding::Protobuf,
+            service_name: "telemetry-pipeline".to_owned(),
+            timeout: Duration::from_secs(10),
+        })
+    }
+
+    pub fn encoding(mut self, encoding: OtlpEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    pub fn service_name(mut self, name: impl Into<String>) -> Self {
+        self.service_name = name.into();
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn export(&self, events: &[TelemetryEvent]) -> Result<(), OtlpError> {
+        let body = encode(&to_request(events, &self.service_name), self.encoding);
+        let addr = self
+            .authority
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| OtlpError::InvalidEndpoint(self.authority.clone()))?;
+        let mut stream = TcpStream::connect_timeout(&addr, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.path,
+            self.authority,
+            self.encoding.content_type(),
+            body.len()
+        )?;
+        stream.write_all(&body)?;
+
+        let mut response = Vec::new();
+        stream.take(MAX_RESPONSE_LEN).read_to_end(&mut response)?;
+        let text = String::from_utf8_lossy(&response);
+        let status = text
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse::<u16>().ok())
+            .ok_or_else(|| OtlpError::Decode("malformed HTTP response".into()))?;
+        if !(200..300).contains(&status) {
+            let body = text.split_once("\r\n\r\n").map_or("", |(_, b)| b).trim().to_owned();
+            return Err(OtlpError::Http { status, body });
+        }
+        Ok(())
+    }
+}
+
+impl Processor for OtlpHttpExporter {
+    fn name(&self) -> &str {
+        "otlp_http"
+    }
+
+    fn process(&self, input: TelemetryEvent) -> Result<Output, ProcessError> {
+        self.export(std::slice::from_ref(&input))
+            .map_err(|e| ProcessError::with_source("OTLP export failed", e))?;
+        Ok(input.into())
+    }
+
+    fn process_batch(
+        &self,
+        input: Vec<TelemetryEvent>,
+    ) -> Result<Vec<TelemetryEvent>, ProcessError> {
+        self.export(&input).map_err(|e| ProcessError::with_source("OTLP export failed", e))?;
+        Ok(input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::*;
+    use crate::telemetry::{ManualClock, Pipeline};
+
+    fn sample() -> TelemetryEvent {
+        let clock = ManualClock::new(from_nanos(1_700_000_000_000_000_001));
+        let mut evt = TelemetryEvent::new_with_clock("login", &clock);
+        evt.ingested_at = Some(from_nanos(1_700_000_000_500_000_000));
+        evt.insert("user", "ada");
+        evt.insert("attempts", 3);
+        evt.insert("latency", 0.5);
+        evt.insert("ok", true);
+        evt.insert("raw", vec![1u8, 2, 3]);
+        evt.insert("tags", vec![Value::from("a"), Value::Int(1)]);
+        evt.insert("geo", Value::Map([("city".to_owned(), Value::from("Oslo"))].into()));
+        evt
+    }
+
+    /// Accepts `n` requests, answering each with `status` and forwarding the
+    /// decoded body.
+    fn mock_collector(
+        n: usize,
+        status: u16,
+    ) -> (String, mpsc::Receiver<(String, Vec<TelemetryEvent>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming().take(n) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let (mut len, mut ctype, mut path) = (0, String::new(), String::new());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                path.push_str(line.split_whitespace().nth(1).unwrap());
+                loop {
+                    line.clear();
+                    reader.read_line(&mut line).unwrap();
+                    let Some((name, value)) = line.trim_end().split_once(": ") else { break };
+                    match name.to_ascii_lowercase().as_str() {
+                        "content-length" => len = value.parse().unwrap(),
+                        "content-type" => ctype = value.to_owned(),
+                        _ => {}
+                    }
+                }
+                let mut body = vec![0; len];
+                reader.read_exact(&mut body).unwrap();
+                let encoding = if ctype == "application/json" {
+                    OtlpEncoding::Json
+                } else {
+                    OtlpEncoding::Protobuf
+                };
+                let req = decode(&body, encoding).unwrap();
+                write!(stream, "HTTP/1.1 {status} X\r\nContent-Length: 4\r\n\r\nnope").unwrap();
+                tx.send((path, from_request(&req))).unwrap();
+            }
+        });
+        (format!("http://{addr}"), rx)
+    }
+
+    #[test]
+    fn log_record_round_trips() {
+        let evt = sample();
+        assert_eq!(from_log_record(&to_log_record(&evt)), evt);
+    }
+
+    #[test]
+    fn payload_cannot_shadow_the_event_id() {
+        let mut evt = sample();
+        evt.insert(EVENT_ID_ATTR, "0");
+        let rec = to_log_record(&evt);
+        assert_eq!(rec.attributes.iter().filter(|kv| kv.key == EVENT_ID_ATTR).count(), 1);
+        assert_eq!(from_log_record(&rec).id, evt.id);
+    }
+
+    #[test]
+    fn request_round_trips_in_both_encodings() {
+        let events = vec![sample(), sample()];
+        for encoding in [OtlpEncoding::Protobuf, OtlpEncoding::Json] {
+            let bytes = encode(&to_request(&events, "svc"), encoding);
+            assert_eq!(from_request(&decode(&bytes, encoding).unwrap()), events);
+        }
+    }
+
+    #[test]
+    fn foreign_record_gets_id_and_timestamp() {
+        let rec =
+            LogRecord { observed_time_unix_nano: 42, event_name: "x".into(), ..Default::default() };
+        let evt = from_log_record(&rec);
+        assert_eq!(evt.timestamp, from_nanos(42));
+        assert_eq!(evt.ingested_at, Some(from_nanos(42)));
+    }
+
+    #[test]
+    fn exporter_posts_to_collector() {
+        for encoding in [OtlpEncoding::Protobuf, OtlpEncoding::Json] {
+            let (url, rx) = mock_collector(1, 200);
+            let mut pipeline = Pipeline::new();
+            pipeline.add(Box::new(OtlpHttpExporter::new(&url).unwrap().encoding(encoding)));
+            let evt = sample();
+            let out = pipeline.run_batch([evt.clone(), evt.clone()]).unwrap();
+            let (path, received) = rx.recv().unwrap();
+            assert_eq!(path, DEFAULT_PATH);
+            assert_eq!(received, out);
+            assert_eq!(received.len(), 2);
+        }
+    }
+
+    #[test]
+    fn collector_rejection_is_a_pipeline_error() {
+        let (url, _rx) = mock_collector(1, 503);
+        let mut pipeline = Pipeline::new();
+        pipeline.add(Box::new(OtlpHttpExporter::new(&format!("{url}/custom")).unwrap()));
+        let err = pipeline.run(sample()).unwrap_err();
+        assert_eq!(err.processor, "otlp_http");
+        assert_eq!(err.kind, "login");
+        let cause = err.source.source().unwrap().downcast_ref::<OtlpError>().unwrap();
+        assert!(matches!(cause, OtlpError::Http { status: 503, body } if body == "nope"));
+    }
+
+    #[test]
+    fn endpoint_must_be_http() {
+        assert!(OtlpHttpExporter::new("https://collector:4318").is_err());
+        assert!(OtlpHttpExporter::new("http:///v1/logs").is_err());
+        let exporter = OtlpHttpExporter::new("http://collector").unwrap();
+        assert_eq!(
+            (exporter.authority.as_str(), exporter.path.as_str()),
+            ("collector:80", DEFAULT_PATH)
+        );
+    }
+}
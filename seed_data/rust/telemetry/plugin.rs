@@ -0,0 +1,534 @@
+//! Processors loaded at runtime from `cdylib` plugins.
+//!
+//! A plugin crate implements [`Processor`] and [`ExportProcessor`] as usual
+//! and exports it with [`export_processor!`](crate::export_processor). Only
+//! `#[repr(C)]` types cross the boundary: the host first calls
+//! `telemetry_plugin_abi_version` and refuses the library unless it returns
+//! [`ABI_VERSION`], then fetches a [`PluginVTable`] from
+//! `telemetry_plugin_vtable`. Batches travel as MessagePack arrays of
+//! events, config as JSON, so host and plugin need not share a compiler.
+//! Buffers are always freed by the side that allocated them.
+
+use std::error::Error;
+use std::ffi::c_void;
+use std::fmt;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::Arc;
+
+use libloading::Library;
+
+use super::{Output, Pipeline, ProcessError, Processor, TelemetryEvent};
+
+/// Bumped on any change to the symbols, [`PluginVTable`] or wire format.
+pub const ABI_VERSION: u32 = 1;
+
+const VERSION_SYMBOL: &[u8] = b"telemetry_plugin_abi_version\0";
+const VTABLE_SYMBOL: &[u8] = b"telemetry_plugin_vtable\0";
+
+pub const STATUS_OK: i32 = 0;
+pub const STATUS_ERROR: i32 = 1;
+
+/// Borrowed bytes.
+#[repr(C)]
+pub struct RawSlice {
+    pub ptr: *const u8,
+    pub len: usize,
+}
+
+impl RawSlice {
+    fn new(bytes: &[u8]) -> Self {
+        Self { ptr: bytes.as_ptr(), len: bytes.len() }
+    }
+
+    /// # Safety
+    /// `ptr` must point at `len` readable bytes that outlive `'a`.
+    unsafe fn as_bytes<'a>(&self) -> &'a [u8] {
+        match self.len {
+            0 => &[],
+            len => std::slice::from_raw_parts(self.ptr, len),
+        }
+    }
+}
+
+/// Bytes owned by the plugin, handed back through
+/// [`PluginVTable::free_buf`].
+#[repr(C)]
+pub struct RawBuf {
+    pub ptr: *mut u8,
+    pub len: usize,
+}
+
+impl RawBuf {
+    const EMPTY: RawBuf = RawBuf { ptr: ptr::null_mut(), len: 0 };
+
+    fn new(bytes: Vec<u8>) -> Self {
+        let len = bytes.len();
+        Self { ptr: Box::into_raw(bytes.into_boxed_slice()).cast(), len }
+    }
+}
+
+#[repr(C)]
+pub struct PluginVTable {
+    /// Builds an instance from JSON config. On failure returns null and
+    /// stores a UTF-8 message in `*error`.
+    pub create: unsafe extern "C" fn(config: RawSlice, error: *mut RawBuf) -> *mut c_void,
+    /// The instance's [`Processor::name`], valid until `destroy`. Empty if
+    /// the plugin could not produce one.
+    pub name: unsafe extern "C" fn(instance: *const c_void) -> RawSlice,
+    /// Runs a batch. Returns [`STATUS_OK`] with the output batch in
+    /// `*output`, or [`STATUS_ERROR`] with a UTF-8 message. May be called
+    /// from several threads at once.
+    pub process:
+        unsafe extern "C" fn(instance: *const c_void, input: RawSlice, output: *mut RawBuf) -> i32,
+    pub free_buf: unsafe extern "C" fn(buf: RawBuf),
+    pub destroy: unsafe extern "C" fn(instance: *mut c_void),
+}
+
+/// Implemented by processors a plugin exports.
+pub trait ExportProcessor: Processor + Sized + 'static {
+    fn from_config(config: &serde_json::Value) -> Result<Self, String>;
+}
+
+impl PluginVTable {
+    pub const fn of<P: ExportProcessor>() -> Self {
+        Self {
+            create: plugin_create::<P>,
+            name: plugin_name::<P>,
+            process: plugin_process::<P>,
+            free_buf: plugin_free_buf,
+            destroy: plugin_destroy::<P>,
+        }
+    }
+}
+
+fn panic_message(panic: Box<dyn std::any::Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(msg) => format!("plugin panicked: {msg}"),
+        Err(panic) => match panic.downcast::<&str>() {
+            Ok(msg) => format!("plugin panicked: {msg}"),
+            Err(_) => "plugin panicked".to_owned(),
+        },
+    }
+}
+
+unsafe extern "C" fn plugin_create<P: ExportProcessor>(
+    config: RawSlice,
+    error: *mut RawBuf,
+) -> *mut c_void {
+    let built = catch_unwind(|| {
+        let config = serde_json::from_slice(config.as_bytes()).map_err(|e| e.to_string())?;
+        P::from_config(&config)
+    });
+    match built.unwrap_or_else(|panic| Err(panic_message(panic))) {
+        Ok(p) => Box::into_raw(Box::new(p)).cast(),
+        Err(msg) => {
+            *error = RawBuf::new(msg.into_bytes());
+            ptr::null_mut()
+        }
+    }
+}
+
+unsafe extern "C" fn plugin_name<P: ExportProcessor>(instance: *const c_void) -> RawSlice {
+    let p = &*instance.cast::<P>();
+    catch_unwind(AssertUnwindSafe(|| RawSlice::new(p.name().as_bytes())))
+        .unwrap_or_else(|_| RawSlice::new(&[]))
+}
+
+unsafe extern "C" fn plugin_process<P: ExportProcessor>(
+    instance: *const c_void,
+    input: RawSlice,
+    output: *mut RawBuf,
+) -> i32 {
+    let p = &*instance.cast::<P>();
+    let res = catch_unwind(AssertUnwindSafe(|| {
+        let events = decode_batch(input.as_bytes())?;
+        let out = p.process_batch(events).map_err(|e| e.to_string())?;
+        encode_batch(&out)
+    }));
+    let (status, bytes) = match res.unwrap_or_else(|panic| Err(panic_message(panic))) {
+        Ok(bytes) => (STATUS_OK, bytes),
+        Err(msg) => (STATUS_ERROR, msg.into_bytes()),
+    };
+    *output = RawBuf::new(bytes);
+    status
+}
+
+unsafe extern "C" fn plugin_free_buf(buf: RawBuf) {
+    if !buf.ptr.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(buf.ptr, buf.len)));
+    }
+}
+
+unsafe extern "C" fn plugin_destroy<P: ExportProcessor>(instance: *mut c_void) {
+    let p = Box::from_raw(instance.cast::<P>());
+    // Nobody is left to report a panic to; it must only not cross the ABI.
+    let _ = catch_unwind(AssertUnwindSafe(|| drop(p)));
+}
+
+fn encode_batch(events: &[TelemetryEvent]) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    rmp_serde::encode::write_named(&mut out, events).map_err(|e| e.to_string())?;
+    Ok(out)
+}
+
+fn decode_batch(bytes: &[u8]) -> Result<Vec<TelemetryEvent>, String> {
+    rmp_serde::from_slice(bytes).map_err(|e| e.to_string())
+}
+
+/// Exports `$ty`, which must implement [`ExportProcessor`], as this
+/// `cdylib`'s plugin. Use it once per library.
+///
+/// [`ExportProcessor`]: crate::telemetry::plugin::ExportProcessor
+#[macro_export]
+macro_rules! export_processor {
+    ($ty:ty) => {
+        #[no_mangle]
+        pub extern "C" fn telemetry_plugin_abi_version() -> u32 {
+            $crate::telemetry::plugin::ABI_VERSION
+        }
+
+        #[no_mangle]
+        pub extern "C" fn telemetry_plugin_vtable() -> *const $crate::telemetry::plugin::PluginVTable
+        {
+            static VTABLE: $crate::telemetry::plugin::PluginVTable =
+                $crate::telemetry::plugin::PluginVTable::of::<$ty>();
+            &VTABLE
+        }
+    };
+}
+
+#[derive(Debug)]
+pub enum PluginError {
+    Load {
+        path: PathBuf,
+        source: libloading::Error,
+    },
+    MissingSymbol {
+        path: PathBuf,
+        symbol: &'static str,
+    },
+    AbiMismatch {
+        path: PathBuf,
+        found: u32,
+    },
+    /// The plugin's `create` rejected its config.
+    Init {
+        path: PathBuf,
+        message: String,
+    },
+}
+
+impl fmt::Display for PluginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PluginError::Load { path, source } => {
+                write!(f, "cannot load plugin {}: {source}", path.display())
+            }
+            PluginError::MissingSymbol { path, symbol } => {
+                write!(f, "{} is not a telemetry plugin: no `{symbol}` symbol", path.display())
+            }
+            PluginError::AbiMismatch { path, found } => write!(
+                f,
+                "plugin {} targets ABI version {found}, this host speaks version {ABI_VERSION}",
+                path.display()
+            ),
+            PluginError::Init { path, message } => {
+                write!(f, "plugin {} rejected its config: {message}", path.display())
+            }
+        }
+    }
+}
+
+impl Error for PluginError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PluginError::Load { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// A processor living in a plugin library. The library stays loaded until
+/// the last processor created from it is dropped.
+pub struct PluginProcessor {
+    name: String,
+    instance: *mut c_void,
+    /// Points at a static inside `_lib`, so it is only ever borrowed through
+    /// [`PluginProcessor::vtable`], never for longer than `self`. `Drop`
+    /// destroys the instance before the fields go, and `_lib` is declared
+    /// last so the library is the last thing released.
+    vtable: *const PluginVTable,
+    _lib: Option<Arc<Library>>,
+}
+
+// SAFETY: `ExportProcessor` requires `Processor`, which is `Send + Sync`,
+// and the ABI requires `process` to be callable concurrently.
+unsafe impl Send for PluginProcessor {}
+unsafe impl Sync for PluginProcessor {}
+
+impl PluginProcessor {
+    pub fn load(path: impl AsRef<Path>, config: &serde_json::Value) -> Result<Self, PluginError> {
+        let path = path.as_ref();
+        // SAFETY: loading runs the library's initialisers; plugins are
+        // trusted code by the time they are named in a config.
+        let lib = unsafe { Library::new(path) }
+            .map_err(|source| PluginError::Load { path: path.into(), source })?;
+        let missing = |symbol: &'static [u8]| PluginError::MissingSymbol {
+            path: path.into(),
+            symbol: std::str::from_utf8(&symbol[..symbol.len() - 1]).unwrap_or_default(),
+        };
+        // SAFETY: the signatures are fixed by the ABI. The version is checked
+        // before anything else in the library is trusted.
+        let vtable = unsafe {
+            let version = lib
+                .get::<extern "C" fn() -> u32>(VERSION_SYMBOL)
+                .map_err(|_| missing(VERSION_SYMBOL))?;
+            let found = version();
+            if found != ABI_VERSION {
+                return Err(PluginError::AbiMismatch { path: path.into(), found });
+            }
+            let vtable = lib
+                .get::<extern "C" fn() -> *const PluginVTable>(VTABLE_SYMBOL)
+                .map_err(|_| missing(VTABLE_SYMBOL))?;
+            vtable()
+        };
+        if vtable.is_null() {
+            return Err(missing(VTABLE_SYMBOL));
+        }
+        Self::from_vtable(path, vtable, config, Some(Arc::new(lib)))
+    }
+
+    /// `vtable` must stay valid for as long as `lib` is loaded, or for good
+    /// when there is no `lib`.
+    fn from_vtable(
+        path: &Path,
+        vtable: *const PluginVTable,
+        config: &serde_json::Value,
+        lib: Option<Arc<Library>>,
+    ) -> Result<Self, PluginError> {
+        // SAFETY: `lib` is still held here, and moves into the processor.
+        let table = unsafe { &*vtable };
+        let config = config.to_string();
+        let mut error = RawBuf::EMPTY;
+        // SAFETY: per the ABI, `create` reads `config` only during the call.
+        let instance = unsafe { (table.create)(RawSlice::new(config.as_bytes()), &mut error) };
+        if instance.is_null() {
+            let message = unsafe { take(table, error) };
+            let message = String::from_utf8_lossy(&message).into_owned();
+            return Err(PluginError::Init { path: path.into(), message });
+        }
+        // SAFETY: the name lives as long as the instance.
+        let name = unsafe { String::from_utf8_lossy((table.name)(instance).as_bytes()) };
+        let name = match &*name {
+            "" => path.display().to_string(),
+            name => name.to_owned(),
+        };
+        Ok(Self { name, instance, vtable, _lib: lib })
+    }
+
+    fn vtable(&self) -> &PluginVTable {
+        // SAFETY: `_lib` keeps the vtable's library loaded while `self` lives.
+        unsafe { &*self.vtable }
+    }
+
+    fn call(&self, events: &[TelemetryEvent]) -> Result<Vec<TelemetryEvent>, ProcessError> {
+        let input = encode_batch(events).map_err(ProcessError::new)?;
+        let mut output = RawBuf::EMPTY;
+        // SAFETY: `instance` is live until drop and the ABI allows
+        // concurrent calls.
+        let (status, bytes) = unsafe {
+            let status = (self.vtable().process)(self.instance, RawSlice::new(&input), &mut output);
+            (status, take(self.vtable(), output))
+        };
+        match status {
+            STATUS_OK => decode_batch(&bytes).map_err(|e| {
+                ProcessError::new(format!("plugin `{}` returned a malformed batch: {e}", self.name))
+            }),
+            _ => Err(ProcessError::new(String::from_utf8_lossy(&bytes).into_owned())),
+        }
+    }
+}
+
+/// Copies a plugin-owned buffer and hands it back for freeing.
+///
+/// # Safety
+/// `buf` must have come from this vtable's plugin and not been freed.
+unsafe fn take(vtable: &PluginVTable, buf: RawBuf) -> Vec<u8> {
+    let bytes = RawSlice { ptr: buf.ptr, len: buf.len }.as_bytes().to_vec();
+    (vtable.free_buf)(buf);
+    bytes
+}
+
+impl Drop for PluginProcessor {
+    fn drop(&mut self) {
+        // SAFETY: created by this vtable's `create`, destroyed once, and
+        // before `_lib` unloads the code.
+        unsafe { (self.vtable().destroy)(self.instance) }
+    }
+}
+
+impl Processor for PluginProcessor {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn process(&self, input: TelemetryEvent) -> Result<Output, ProcessError> {
+        self.call(std::slice::from_ref(&input)).map(Output::Many)
+    }
+
+    fn process_batch(
+        &self,
+        input: Vec<TelemetryEvent>,
+    ) -> Result<Vec<TelemetryEvent>, ProcessError> {
+        self.call(&input)
+    }
+}
+
+impl Pipeline {
+    /// Loads a plugin library and appends its processor.
+    pub fn add_plugin(
+        &mut self,
+        path: impl AsRef<Path>,
+        config: &serde_json::Value,
+    ) -> Result<(), PluginError> {
+        self.add(Box::new(PluginProcessor::load(path, config)?));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stands in for a plugin crate: the macro exports it from the test
+    /// binary, and the tests pick up its vtable directly.
+    struct Suffix {
+        suffix: String,
+    }
+
+    impl Processor for Suffix {
+        fn name(&self) -> &str {
+            "suffix"
+        }
+
+        fn process(&self, mut input: TelemetryEvent) -> Result<Output, ProcessError> {
+            match input.kind.as_str() {
+                "fail" => Err(ProcessError::new("refused")),
+                "panic" => panic!("boom"),
+                _ => {
+                    input.kind.push_str(&self.suffix);
+                    Ok(input.into())
+                }
+            }
+        }
+    }
+
+    impl ExportProcessor for Suffix {
+        fn from_config(config: &serde_json::Value) -> Result<Self, String> {
+            let suffix = config["suffix"].as_str().ok_or("`suffix` must be a string")?;
+            Ok(Self { suffix: suffix.to_owned() })
+        }
+    }
+
+    crate::export_processor!(Suffix);
+
+    fn plugin(config: serde_json::Value) -> Result<PluginProcessor, PluginError> {
+        assert_eq!(telemetry_plugin_abi_version(), ABI_VERSION);
+        PluginProcessor::from_vtable(
+            Path::new("in-process"),
+            telemetry_plugin_vtable(),
+            &config,
+            None,
+        )
+    }
+
+    /// Panics wherever it can.
+    struct Grumpy;
+
+    impl Processor for Grumpy {
+        fn name(&self) -> &str {
+            panic!("no name")
+        }
+
+        fn process(&self, _: TelemetryEvent) -> Result<Output, ProcessError> {
+            panic!("no processing")
+        }
+    }
+
+    impl Drop for Grumpy {
+        fn drop(&mut self) {
+            panic!("no dropping")
+        }
+    }
+
+    impl ExportProcessor for Grumpy {
+        fn from_config(_: &serde_json::Value) -> Result<Self, String> {
+            Ok(Grumpy)
+        }
+    }
+
+    #[test]
+    fn batches_round_trip_through_the_abi() {
+        let mut pipeline = Pipeline::new();
+        pipeline.add(Box::new(plugin(serde_json::json!({ "suffix": ".x" })).unwrap()));
+        let mut evt = TelemetryEvent::new("a");
+        evt.insert("n", 7);
+        let out = pipeline.run_batch([evt.clone(), TelemetryEvent::new("b")]).unwrap();
+        assert_eq!(out.len(), 2);
+        assert_eq!((out[0].kind.as_str(), out[1].kind.as_str()), ("a.x", "b.x"));
+        assert_eq!((out[0].id, out[0].get("n")), (evt.id, evt.get("n")));
+        assert_eq!(pipeline.stats().stages[0].name, "suffix");
+    }
+
+    #[test]
+    fn plugin_errors_and_panics_become_process_errors() {
+        let p = plugin(serde_json::json!({ "suffix": "" })).unwrap();
+        assert_eq!(p.process(TelemetryEvent::new("fail")).err().unwrap().message(), "refused");
+        let err = p.process(TelemetryEvent::new("panic")).err().unwrap();
+        assert_eq!(err.message(), "plugin panicked: boom");
+
+        let err = plugin(serde_json::json!({})).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "plugin in-process rejected its config: `suffix` must be a string"
+        );
+    }
+
+    #[test]
+    fn panics_in_name_and_destroy_stay_in_the_plugin() {
+        static VTABLE: PluginVTable = PluginVTable::of::<Grumpy>();
+        let p = PluginProcessor::from_vtable(
+            Path::new("grumpy.so"),
+            &VTABLE,
+            &serde_json::Value::Null,
+            None,
+        )
+        .unwrap();
+        assert_eq!(p.name(), "grumpy.so");
+        let err = p.process(TelemetryEvent::new("a")).err().unwrap();
+        assert_eq!(err.message(), "plugin panicked: no processing");
+        drop(p);
+    }
+
+    #[test]
+    fn loading_reports_clean_errors() {
+        let err = PluginProcessor::load("/nonexistent/libplugin.so", &serde_json::Value::Null);
+        assert!(matches!(err, Err(PluginError::Load { .. })));
+
+        #[cfg(target_os = "linux")]
+        {
+            let err = PluginProcessor::load("libc.so.6", &serde_json::Value::Null).err().unwrap();
+            assert!(matches!(
+                err,
+                PluginError::MissingSymbol { symbol: "telemetry_plugin_abi_version", .. }
+            ));
+        }
+        let err = PluginError::AbiMismatch { path: "p.so".into(), found: 9 };
+        assert_eq!(
+            err.to_string(),
+            format!("plugin p.so targets ABI version 9, this host speaks version {ABI_VERSION}")
+        );
+    }
+}
@@ -0,0 +1,315 @@
+//! Prometheus text exposition for [`Pipeline`] and [`StagedRuntime`]
+//! internals, with a small embedded HTTP endpoint to scrape it from.
+
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use super::metrics::LATENCY_BUCKETS;
+use super::runtime::{QueueStats, StagedRuntime};
+use super::{Pipeline, PipelineStats, StageStats};
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+pub const DEFAULT_PATH: &str = "/metrics";
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Scrapes are answered one at a time, so each request gets a bounded
+/// share of the server: this long to arrive, in at most this many header
+/// lines and bytes.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_HEADERS: usize = 100;
+const MAX_HEAD_LEN: u64 = 16 * 1024;
+
+/// Anything that can describe itself in the text format.
+pub trait MetricsSource: Send + Sync {
+    fn encode(&self, out: &mut String);
+}
+
+impl MetricsSource for Pipeline {
+    fn encode(&self, out: &mut String) {
+        encode(&self.stats(), &[], out);
+    }
+}
+
+impl MetricsSource for StagedRuntime {
+    fn encode(&self, out: &mut String) {
+        encode(&self.stats(), &self.queue_stats(), out);
+    }
+}
+
+impl<T: MetricsSource + ?Sized> MetricsSource for Arc<T> {
+    fn encode(&self, out: &mut String) {
+        (**self).encode(out)
+    }
+}
+
+/// Name suffix, metric type, help text, and how to read the sample.
+type Family<T> = (&'static str, &'static str, &'static str, fn(&T) -> u64);
+
+/// Appends one family per stage counter, the latency histogram, and, when
+/// `queues` is non-empty, the queue gauges.
+pub fn encode(stats: &PipelineStats, queues: &[QueueStats], out: &mut String) {
+    let counters: [Family<StageStats>; 4] = [
+        ("calls", "counter", "Processor or sink invocations.", |s| s.calls),
+        ("events_in", "counter", "Events handed to the stage.", |s| s.events_in),
+        ("events_out", "counter", "Events the stage emitted or wrote.", |s| s.events_out),
+        ("errors", "counter", "Failed invocations.", |s| s.errors),
+    ];
+    for (name, kind, help, get) in counters {
+        family(out, &format!("telemetry_stage_{name}_total"), kind, help);
+        for s in &stats.stages {
+            let _ = writeln!(
+                out,
+                "telemetry_stage_{name}_total{{{}}} {}",
+                stage_labels(&s.name, s.index),
+                get(s)
+            );
+        }
+    }
+
+    let name = "telemetry_stage_latency_seconds";
+    family(out, name, "histogram", "Time spent per invocation.");
+    for s in &stats.stages {
+        let labels = stage_labels(&s.name, s.index);
+        let mut cumulative = 0;
+        for (bound, n) in LATENCY_BUCKETS.iter().zip(&s.latency.counts) {
+            cumulative += n;
+            let le = bound.as_secs_f64();
+            let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{le}\"}} {cumulative}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {}", s.latency.count);
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {}", s.latency.sum.as_secs_f64());
+        let _ = writeln!(out, "{name}_count{{{labels}}} {}", s.latency.count);
+    }
+
+    if queues.is_empty() {
+        return;
+    }
+    let gauges: [Family<QueueStats>; 4] = [
+        ("depth", "gauge", "Events waiting in memory.", |q| q.depth as u64),
+        ("capacity", "gauge", "Events the queue holds before overflowing.", |q| q.capacity as u64),
+        ("spilled", "gauge", "Events waiting on disk.", |q| q.spilled as u64),
+        ("dropped_total", "counter", "Events discarded on overflow.", |q| q.dropped),
+    ];
+    for (name, kind, help, get) in gauges {
+        family(out, &format!("telemetry_queue_{name}"), kind, help);
+        for (i, q) in queues.iter().enumerate() {
+            let _ =
+                writeln!(out, "telemetry_queue_{name}{{{}}} {}", stage_labels(&q.stage, i), get(q));
+        }
+    }
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+}
+
+fn stage_labels(stage: &str, index: usize) -> String {
+    let mut escaped = String::with_capacity(stage.len());
+    for c in stage.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    format!("stage=\"{escaped}\",index=\"{index}\"")
+}
+
+/// Serves `GET /metrics` on a background thread until dropped.
+pub struct MetricsServer {
+    addr: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    pub fn bind(addr: impl ToSocketAddrs, source: Arc<dyn MetricsSource>) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let stop = shutdown.clone();
+        let thread = thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    // A scrape is cheap enough to answer inline; a failed
+                    // one only concerns that client.
+                    Ok((stream, _)) => {
+                        let _ = respond(stream, &*source);
+                    }
+                    // Nothing pending, or a client gave up before we got to it.
+                    Err(_) => thread::sleep(POLL_INTERVAL),
+                }
+            }
+        });
+        Ok(Self { addr, shutdown, thread: Some(thread) })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn respond(stream: TcpStream, source: &dyn MetricsSource) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    let deadline = Instant::now() + REQUEST_TIMEOUT;
+    let mut reader = BufReader::new(Deadline { stream, deadline }.take(MAX_HEAD_LEN));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Drain the headers; nothing in them changes the answer.
+    let mut header = String::new();
+    let mut complete = false;
+    for _ in 0..=MAX_HEADERS {
+        header.clear();
+        reader.read_line(&mut header)?;
+        if !header.ends_with('\n') {
+            break;
+        }
+        if header.trim_end().is_empty() {
+            complete = true;
+            break;
+        }
+    }
+    let mut stream = reader.into_inner().into_inner().stream;
+    if !complete {
+        return stream.write_all(
+            b"HTTP/1.1 431 Request Header Fields Too Large\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        );
+    }
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let path = target.split('?').next().unwrap_or("");
+    let (status, content_type, body) = match (method, path) {
+        ("GET", DEFAULT_PATH) => {
+            let mut body = String::new();
+            source.encode(&mut body);
+            ("200 OK", CONTENT_TYPE, body)
+        }
+        ("GET", _) => ("404 Not Found", "text/plain", "not found\n".to_owned()),
+        _ => ("405 Method Not Allowed", "text/plain", "method not allowed\n".to_owned()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+/// A stream whose reads all share one deadline, so a client trickling in
+/// bytes cannot hold the scrape thread past it.
+struct Deadline {
+    stream: TcpStream,
+    deadline: Instant,
+}
+
+impl Read for Deadline {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        self.stream.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::{Output, ProcessError, Processor, TelemetryEvent};
+
+    struct Pass;
+
+    impl Processor for Pass {
+        fn name(&self) -> &str {
+            "pass \"quoted\""
+        }
+
+        fn process(&self, input: TelemetryEvent) -> Result<Output, ProcessError> {
+            Ok(input.into())
+        }
+    }
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: test\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn scrape_reports_stage_counters_and_histogram() {
+        let mut pipeline = Pipeline::new();
+        pipeline.add(Box::new(Pass));
+        pipeline.run_batch([TelemetryEvent::new("a"), TelemetryEvent::new("b")]).unwrap();
+        let pipeline = Arc::new(pipeline);
+        let server = MetricsServer::bind("127.0.0.1:0", pipeline).unwrap();
+
+        let response = get(server.local_addr(), "/metrics?x=1");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains(CONTENT_TYPE));
+        let labels = r#"stage="pass \"quoted\"",index="0""#;
+        assert!(response.contains("# TYPE telemetry_stage_events_in_total counter\n"));
+        assert!(response.contains(&format!("telemetry_stage_events_in_total{{{labels}}} 2\n")));
+        assert!(response.contains(&format!("telemetry_stage_calls_total{{{labels}}} 1\n")));
+        assert!(response.contains(&format!(
+            "telemetry_stage_latency_seconds_bucket{{{labels},le=\"+Inf\"}} 1\n"
+        )));
+        assert!(
+            response.contains(&format!("telemetry_stage_latency_seconds_count{{{labels}}} 1\n"))
+        );
+        assert!(!response.contains("telemetry_queue_"));
+
+        assert!(get(server.local_addr(), "/other").starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn oversized_requests_are_refused() {
+        let server = MetricsServer::bind("127.0.0.1:0", Arc::new(Pipeline::new())).unwrap();
+        let send = |request: String| {
+            let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+            // The server may hang up before reading everything.
+            let _ = stream.write_all(request.as_bytes());
+            let mut response = String::new();
+            let _ = stream.read_to_string(&mut response);
+            response
+        };
+        let many = "X-A: b\r\n".repeat(MAX_HEADERS + 1);
+        assert!(send(format!("GET / HTTP/1.1\r\n{many}\r\n")).starts_with("HTTP/1.1 431"));
+        let long = "x".repeat(MAX_HEAD_LEN as usize);
+        assert!(send(format!("GET / HTTP/1.1\r\nX-A: {long}\r\n\r\n")).starts_with("HTTP/1.1 431"));
+        assert!(get(server.local_addr(), "/metrics").starts_with("HTTP/1.1 200"));
+    }
+
+    #[test]
+    fn staged_runtime_exposes_queue_gauges() {
+        let mut out = String::new();
+        let mut pipeline = Pipeline::new();
+        pipeline.add(Box::new(Pass));
+        let rt = pipeline.into_staged(Default::default(), |_| {}).unwrap();
+        rt.encode(&mut out);
+        assert!(out.contains("# TYPE telemetry_queue_depth gauge\n"));
+        assert!(out.contains(
+            "telemetry_queue_capacity{stage=\"pass \\\"quoted\\\"\",index=\"0\"} 1024\n"
+        ));
+        assert!(out.contains("# TYPE telemetry_queue_dropped_total counter\n"));
+    }
+}
@@ -0,0 +1,484 @@
+//! Scrubs personal data from payloads before it leaves the process.
+//!
+//! A [`Redactor`] holds an ordered list of rules, each a [`Detector`] and the
+//! [`Policy`] applied to what it finds. Detectors either match text inside
+//! string values (emails, IP addresses, tokens, card numbers, known names,
+//! any regex) or match keys, in which case the whole value goes. Nested maps
+//! and lists are scanned too; [`Value::Bytes`] and map keys are not, so
+//! whatever is in them passes through unredacted.
+//!
+//! In a config file:
+//!
+//! ```toml
+//! [[processors]]
+//! type = "redact"
+//! report_to = "redacted"
+//! rules = [
+//!     { detect = "keys", keys = ["password", "authorization"], policy = "drop" },
+//!     { detect = "email", policy = "hash", salt = "${REDACT_SALT}" },
+//!     { detect = "card_number" },
+//! ]
+//! ```
+
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::ops::Range;
+
+use regex::{Captures, Regex};
+use sha2::{Digest, Sha256};
+
+use super::{Output, ProcessError, Processor, TelemetryEvent, Value};
+use crate::domain::User;
+
+/// What [`Policy::Mask`] puts in place of a match.
+pub const MASK: &str = "[REDACTED]";
+
+/// What to do with a match.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Policy {
+    /// Replace it with [`MASK`].
+    Mask,
+    /// Replace it with `sha256:` and the first 16 hex digits of the SHA-256
+    /// of the salt followed by the match, so equal values stay correlatable
+    /// without being recoverable by a dictionary built without the salt.
+    Hash { salt: Vec<u8> },
+    /// Remove the whole field, or the whole list item, the match is in.
+    Drop,
+}
+
+impl Policy {
+    pub fn hash(salt: impl Into<Vec<u8>>) -> Self {
+        Policy::Hash { salt: salt.into() }
+    }
+
+    fn action(&self) -> Action {
+        match self {
+            Policy::Mask => Action::Masked,
+            Policy::Hash { .. } => Action::Hashed,
+            Policy::Drop => Action::Dropped,
+        }
+    }
+
+    /// `None` for [`Policy::Drop`].
+    fn replace(&self, text: &str) -> Option<String> {
+        match self {
+            Policy::Mask => Some(MASK.to_owned()),
+            Policy::Hash { salt } => {
+                let digest = Sha256::new().chain_update(salt).chain_update(text).finalize();
+                let mut out = String::from("sha256:");
+                for byte in &digest[..8] {
+                    let _ = write!(out, "{byte:02x}");
+                }
+                Some(out)
+            }
+            Policy::Drop => None,
+        }
+    }
+}
+
+enum Kind {
+    Text { regex: Regex, check: Option<Check> },
+    Keys(HashSet<String>),
+}
+
+/// Validates what a detector's regex finds. The regex is loose and greedy,
+/// so a candidate that fails may still hold a real match, as `fe80::1:`
+/// holds an address; such a candidate is searched for stretches that pass,
+/// starting and ending at `split` characters or the candidate's ends.
+#[derive(Clone, Copy)]
+struct Check {
+    valid: fn(&str) -> bool,
+    split: fn(char) -> bool,
+}
+
+impl Check {
+    /// The non-overlapping ranges of `candidate` that pass, longest first
+    /// and then leftmost, each side of a pass searched again.
+    fn spans(self, candidate: &str, offset: usize, out: &mut Vec<Range<usize>>) {
+        if candidate.is_empty() {
+            return;
+        }
+        if (self.valid)(candidate) {
+            out.push(offset..offset + candidate.len());
+            return;
+        }
+        let mut bounds = vec![0, candidate.len()];
+        for (i, c) in candidate.char_indices().filter(|&(_, c)| (self.split)(c)) {
+            bounds.extend([i, i + c.len_utf8()]);
+        }
+        bounds.sort_unstable();
+        bounds.dedup();
+        let mut windows: Vec<_> = bounds
+            .iter()
+            .flat_map(|&a| bounds.iter().filter(move |&&b| b > a).map(move |&b| a..b))
+            .filter(|w| w.len() < candidate.len())
+            .collect();
+        windows.sort_by_key(|w| (std::cmp::Reverse(w.len()), w.start));
+        if let Some(w) = windows.into_iter().find(|w| (self.valid)(&candidate[w.clone()])) {
+            self.spans(&candidate[..w.start], offset, out);
+            out.push(offset + w.start..offset + w.end);
+            self.spans(&candidate[w.end..], offset + w.end, out);
+        }
+    }
+}
+
+/// Finds what a rule redacts.
+pub struct Detector(Kind);
+
+impl Detector {
+    /// Matches of `pattern` inside string values.
+    pub fn pattern(pattern: &str) -> Result<Self, regex::Error> {
+        Ok(Self::text(Regex::new(pattern)?, None))
+    }
+
+    /// Any of `values` appearing as whole words inside string values.
+    pub fn values(values: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
+        let alternatives: Vec<_> = values.into_iter().map(|v| regex::escape(v.as_ref())).collect();
+        let regex = match alternatives.is_empty() {
+            true => r"[^\s\S]",
+            false => &format!(r"\b(?:{})\b", alternatives.join("|")),
+        };
+        Self::text(Regex::new(regex).expect("escaped values always form a valid regex"), None)
+    }
+
+    /// The names of `users`, wherever they are mentioned.
+    pub fn user_names(users: &[User]) -> Self {
+        Self::values(users.iter().map(|u| &u.name))
+    }
+
+    /// Fields whose key is one of `keys`, compared case-insensitively.
+    pub fn keys(keys: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
+        Detector(Kind::Keys(keys.into_iter().map(|k| k.as_ref().to_lowercase()).collect()))
+    }
+
+    pub fn email() -> Self {
+        Self::builtin(r"(?i)\b[a-z0-9._%+-]+@[a-z0-9-]+(?:\.[a-z0-9-]+)*\.[a-z]{2,}\b", None)
+    }
+
+    /// IPv4 and IPv6 addresses, including IPv6 with an embedded IPv4 tail
+    /// such as `::ffff:10.0.0.1`; candidates that do not parse as an
+    /// address, such as `12:30:05`, are left alone.
+    pub fn ip_address() -> Self {
+        let check = Check { valid: |s| s.parse::<IpAddr>().is_ok(), split: |c| c == ':' };
+        let v4 = r"(?:\d{1,3}\.){3}\d{1,3}\b";
+        let v6 = format!(r"(?i)(?:[0-9a-f]{{0,4}}:){{2,7}}(?:{v4}|[0-9a-f]{{0,4}})");
+        Self::builtin(&format!(r"{v6}|\b{v4}"), Some(check))
+    }
+
+    /// `Bearer` credentials and JSON Web Tokens.
+    pub fn token() -> Self {
+        Self::builtin(
+            r"(?i)\bbearer\s+[a-z0-9._~+/-]+=*|\beyJ[a-z0-9_-]+\.[a-z0-9_-]+\.[a-z0-9_-]*",
+            None,
+        )
+    }
+
+    /// 13 to 19 digits, optionally grouped by spaces or dashes, that pass
+    /// the Luhn check. Within a longer run of groups, whole groups are.
+    pub fn card_number() -> Self {
+        let check = Check { valid: luhn, split: |c| c == ' ' || c == '-' };
+        Self::builtin(r"\b(?:\d[ -]?){12,18}\d\b", Some(check))
+    }
+
+    fn builtin(pattern: &str, check: Option<Check>) -> Self {
+        Self::text(Regex::new(pattern).expect("built-in patterns are valid"), check)
+    }
+
+    fn text(regex: Regex, check: Option<Check>) -> Self {
+        Detector(Kind::Text { regex, check })
+    }
+}
+
+fn luhn(candidate: &str) -> bool {
+    let is_digit = |c: Option<char>| c.is_some_and(|c| c.is_ascii_digit());
+    if !is_digit(candidate.chars().next()) || !is_digit(candidate.chars().last()) {
+        return false;
+    }
+    let digits: Vec<u32> = candidate.chars().filter_map(|c| c.to_digit(10)).collect();
+    if !(13..=19).contains(&digits.len()) {
+        return false;
+    }
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &d)| match (i % 2, d * 2) {
+            (0, _) => d,
+            (_, doubled) if doubled > 9 => doubled - 9,
+            (_, doubled) => doubled,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Masked,
+    Hashed,
+    Dropped,
+}
+
+/// One field a [`Redactor`] changed. `path` is the field's place in the
+/// payload before redaction, e.g. `user.emails[1]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redaction {
+    pub path: String,
+    pub rule: String,
+    pub action: Action,
+}
+
+struct Rule {
+    name: String,
+    detector: Detector,
+    policy: Policy,
+}
+
+/// Applies its rules in the order they were added. A key rule replaces or
+/// drops the field outright; text rules then run one after another over
+/// what is left, so a later rule never sees an earlier rule's matches.
+#[derive(Default)]
+pub struct Redactor {
+    rules: Vec<Rule>,
+    report_to: Option<String>,
+}
+
+impl Redactor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a rule; `name` identifies it in the [`Redaction`] report.
+    pub fn rule(mut self, name: impl Into<String>, detector: Detector, policy: Policy) -> Self {
+        self.rules.push(Rule { name: name.into(), detector, policy });
+        self
+    }
+
+    /// When run as a processor, lists the paths of changed fields under
+    /// `key` in events that had any.
+    pub fn report_to(mut self, key: impl Into<String>) -> Self {
+        self.report_to = Some(key.into());
+        self
+    }
+
+    /// Redacts `evt` in place and reports what changed, ordered by path.
+    pub fn redact(&self, evt: &mut TelemetryEvent) -> Vec<Redaction> {
+        let mut report = Vec::new();
+        evt.payload.retain(|key, value| !self.scan(key.clone(), key, value, &mut report));
+        report.sort_by(|a, b| a.path.cmp(&b.path));
+        report
+    }
+
+    /// Returns whether the value should be dropped.
+    fn scan(
+        &self,
+        path: String,
+        key: &str,
+        value: &mut Value,
+        report: &mut Vec<Redaction>,
+    ) -> bool {
+        let key = key.to_lowercase();
+        for rule in &self.rules {
+            let Kind::Keys(keys) = &rule.detector.0 else { continue };
+            if !keys.contains(&key) {
+                continue;
+            }
+            report.push(Redaction { path, rule: rule.name.clone(), action: rule.policy.action() });
+            return match rule.policy.replace(&value.to_string()) {
+                Some(replaced) => {
+                    *value = Value::String(replaced);
+                    false
+                }
+                None => true,
+            };
+        }
+
+        match value {
+            Value::String(s) => self.scan_text(path, s, report),
+            Value::List(items) => {
+                let mut i = 0;
+                items.retain_mut(|item| {
+                    let path = format!("{path}[{i}]");
+                    i += 1;
+                    !self.scan(path, "", item, report)
+                });
+                false
+            }
+            Value::Map(map) => {
+                map.retain(|k, v| !self.scan(format!("{path}.{k}"), k, v, report));
+                false
+            }
+            _ => false,
+        }
+    }
+
+    fn scan_text(&self, path: String, s: &mut String, report: &mut Vec<Redaction>) -> bool {
+        for rule in &self.rules {
+            let Kind::Text { regex, check } = &rule.detector.0 else { continue };
+            let spans = |m: &str| {
+                let mut spans = Vec::new();
+                match check {
+                    Some(check) => check.spans(m, 0, &mut spans),
+                    None => spans.push(0..m.len()),
+                }
+                spans
+            };
+            let mut hit = false;
+            if rule.policy == Policy::Drop {
+                hit = regex.find_iter(s).any(|m| !spans(m.as_str()).is_empty());
+            } else {
+                let replaced = regex.replace_all(s, |c: &Captures| {
+                    let m = &c[0];
+                    let mut out = String::with_capacity(m.len());
+                    let mut last = 0;
+                    for span in spans(m) {
+                        hit = true;
+                        out.push_str(&m[last..span.start]);
+                        out.push_str(&rule.policy.replace(&m[span.clone()]).unwrap_or_default());
+                        last = span.end;
+                    }
+                    out.push_str(&m[last..]);
+                    out
+                });
+                if hit {
+                    *s = replaced.into_owned();
+                }
+            }
+            if hit {
+                let action = rule.policy.action();
+                report.push(Redaction { path: path.clone(), rule: rule.name.clone(), action });
+                if action == Action::Dropped {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+impl Processor for Redactor {
+    fn name(&self) -> &str {
+        "redact"
+    }
+
+    fn process(&self, mut input: TelemetryEvent) -> Result<Output, ProcessError> {
+        let report = self.redact(&mut input);
+        if let Some(key) = &self.report_to {
+            if !report.is_empty() {
+                let paths = report.into_iter().map(|r| Value::String(r.path)).collect();
+                input.payload.insert(key.clone(), Value::List(paths));
+            }
+        }
+        Ok(input.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::Payload;
+
+    fn changes(report: &[Redaction]) -> Vec<(&str, &str, Action)> {
+        report.iter().map(|r| (r.path.as_str(), r.rule.as_str(), r.action)).collect()
+    }
+
+    #[test]
+    fn detectors_find_what_they_should() {
+        let redactor = Redactor::new()
+            .rule("email", Detector::email(), Policy::Mask)
+            .rule("ip", Detector::ip_address(), Policy::Mask)
+            .rule("token", Detector::token(), Policy::Mask)
+            .rule("card", Detector::card_number(), Policy::Mask)
+            .rule("user", Detector::user_names(&[User::new("u1", "Ada Lovelace")]), Policy::Mask);
+        let cases = [
+            ("mail ada@example.co.uk now", "mail [REDACTED] now"),
+            (
+                "from 10.0.0.12 and fe80::1 at 12:30:05",
+                "from [REDACTED] and [REDACTED] at 12:30:05",
+            ),
+            ("version 1.2.3.400", "version 1.2.3.400"),
+            ("Authorization: Bearer abc.def-123", "Authorization: [REDACTED]"),
+            ("paid with 4111 1111 1111 1111", "paid with [REDACTED]"),
+            ("order 4111 1111 1111 1112", "order 4111 1111 1111 1112"),
+            ("error from fe80::1: timeout", "error from [REDACTED]: timeout"),
+            ("peer ::ffff:10.0.0.1", "peer [REDACTED]"),
+            ("card 4111 1111 1111 1111 2 items", "card [REDACTED] 2 items"),
+            ("ids 12 4111-1111-1111-1111", "ids 12 [REDACTED]"),
+            ("mac 00:1a:2b:3c:4d:5e", "mac 00:1a:2b:3c:4d:5e"),
+            ("Ada Lovelace logged in", "[REDACTED] logged in"),
+            ("Adam Lovelaceless", "Adam Lovelaceless"),
+        ];
+        for (input, expected) in cases {
+            let mut evt = TelemetryEvent::new("log");
+            evt.insert("msg", input);
+            redactor.redact(&mut evt);
+            assert_eq!(evt.get_str("msg"), Some(expected), "{input}");
+        }
+    }
+
+    #[test]
+    fn policies_and_report() {
+        let redactor = Redactor::new()
+            .rule("secrets", Detector::keys(["Password"]), Policy::Drop)
+            .rule("email", Detector::email(), Policy::hash("salt"))
+            .rule("ip", Detector::ip_address(), Policy::Drop)
+            .report_to("redacted");
+        let mut evt = TelemetryEvent::new("login");
+        evt.insert("password", "hunter2");
+        evt.insert("email", "ada@example.com");
+        evt.insert("hosts", vec![Value::from("a.local"), Value::from("10.1.1.1")]);
+        let user: Payload =
+            [("PASSWORD".into(), Value::Int(1234)), ("id".into(), "u1".into())].into();
+        evt.insert("user", user);
+        evt.insert("count", 3);
+
+        let mut copy = evt.clone();
+        let report = redactor.redact(&mut copy);
+        assert_eq!(
+            changes(&report),
+            [
+                ("email", "email", Action::Hashed),
+                ("hosts[1]", "ip", Action::Dropped),
+                ("password", "secrets", Action::Dropped),
+                ("user.PASSWORD", "secrets", Action::Dropped),
+            ]
+        );
+        let hashed = copy.get_str("email").unwrap();
+        assert!(hashed.starts_with("sha256:") && hashed.len() == 23, "{hashed}");
+        let unsalted = Redactor::new().rule("email", Detector::email(), Policy::hash(""));
+        let mut other = evt.clone();
+        unsalted.redact(&mut other);
+        assert_ne!(other.get_str("email"), Some(hashed));
+
+        let Output::One(out) = redactor.process(evt).unwrap() else { panic!("expected one event") };
+        assert_eq!(out.get("hosts"), Some(&Value::List(vec!["a.local".into()])));
+        assert_eq!(out.get("password"), None);
+        assert_eq!(out.get("count"), Some(&Value::Int(3)));
+        let listed = out.get("redacted").and_then(Value::as_list).unwrap();
+        assert_eq!(listed.len(), 4);
+
+        let mut clean = TelemetryEvent::new("x");
+        clean.insert("count", 1);
+        let Output::One(out) = redactor.process(clean).unwrap() else {
+            panic!("expected one event")
+        };
+        assert_eq!(out.get("redacted"), None);
+    }
+
+    #[test]
+    fn key_rules_replace_whole_values() {
+        let redactor = Redactor::new().rule("auth", Detector::keys(["auth"]), Policy::Mask).rule(
+            "none",
+            Detector::values(Vec::<&str>::new()),
+            Policy::Drop,
+        );
+        let mut evt = TelemetryEvent::new("x");
+        let auth: Payload = [("user".into(), "ada".into())].into();
+        evt.insert("auth", auth);
+        evt.insert("note", "");
+        let report = redactor.redact(&mut evt);
+        assert_eq!(changes(&report), [("auth", "auth", Action::Masked)]);
+        assert_eq!(evt.get_str("auth"), Some(MASK));
+        assert_eq!(evt.get_str("note"), Some(""));
+    }
+}
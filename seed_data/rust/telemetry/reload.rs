@@ -0,0 +1,341 @@
+//! Live replacement of a running [`Pipeline`]. A [`SwappablePipeline`] hands
+//! each run the pipeline that is current when it starts, so a swap never
+//! cuts a run short: in-flight events finish on the old chain while new ones
+//! take the new chain. A [`ConfigWatcher`] drives swaps from a config file.
+//!
+//! A swap only holds the lock long enough to exchange the two pointers; the
+//! new pipeline is built beforehand and the old one drained and closed
+//! afterwards, so neither blocks new runs. Both pipelines' sinks are open
+//! in between.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+use super::config::{ConfigError, Registry};
+use super::{Pipeline, PipelineError, TelemetryEvent};
+
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+pub const DEFAULT_RETIRE_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct SwappablePipeline {
+    current: RwLock<Arc<Pipeline>>,
+    generation: AtomicU64,
+}
+
+impl SwappablePipeline {
+    pub fn new(pipeline: Pipeline) -> Self {
+        Self { current: RwLock::new(Arc::new(pipeline)), generation: AtomicU64::new(0) }
+    }
+
+    /// The pipeline new runs go to. Holding on to it keeps it alive (and
+    /// keeps [`retire`](Self::retire) waiting) after a swap.
+    pub fn current(&self) -> Arc<Pipeline> {
+        self.current.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// How many swaps have happened.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    pub fn run(&self, evt: TelemetryEvent) -> Result<Vec<TelemetryEvent>, PipelineError> {
+        self.current().run(evt)
+    }
+
+    pub fn run_batch<I>(&self, events: I) -> Result<Vec<TelemetryEvent>, PipelineError>
+    where
+        I: IntoIterator<Item = TelemetryEvent>,
+    {
+        self.current().run_batch(events)
+    }
+
+    /// Installs `next` and returns the pipeline it replaced, which may still
+    /// be finishing runs that started before the swap.
+    pub fn swap(&self, next: Pipeline) -> Arc<Pipeline> {
+        let mut current = self.current.write().unwrap_or_else(PoisonError::into_inner);
+        let old = std::mem::replace(&mut *current, Arc::new(next));
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        old
+    }
+
+    /// Waits for every run still holding `old` to finish, then closes its
+    /// sinks.
+    pub fn retire(old: Arc<Pipeline>) -> Result<(), PipelineError> {
+        released(&old, None);
+        old.close()
+    }
+}
+
+/// Waits until `p` has no other holders, or until `deadline`.
+fn released(p: &Arc<Pipeline>, deadline: Option<Instant>) -> bool {
+    while Arc::strong_count(p) > 1 {
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    true
+}
+
+/// What a [`ConfigWatcher::check`] did.
+#[derive(Debug)]
+pub enum Reload {
+    Unchanged,
+    /// The new pipeline is live. `retired` is how closing the old one's
+    /// sinks went, or `None` if runs on it outlasted the retire timeout and
+    /// it is left to close on a background thread once they finish.
+    Swapped {
+        generation: u64,
+        retired: Option<Result<(), PipelineError>>,
+    },
+}
+
+/// Rebuilds a [`SwappablePipeline`] whenever its config file changes. A
+/// config that fails to load leaves the running pipeline in place, and is
+/// not retried until the file changes again. Only processors and sinks are
+/// rebuilt; sources stay as they were started.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    registry: Arc<Registry>,
+    target: Arc<SwappablePipeline>,
+    poll_interval: Duration,
+    retire_timeout: Duration,
+    seen: Option<(SystemTime, u64)>,
+}
+
+impl ConfigWatcher {
+    /// Treats the file as it is now as already loaded, so the first change
+    /// after this triggers a reload.
+    pub fn new(
+        path: impl Into<PathBuf>,
+        registry: Arc<Registry>,
+        target: Arc<SwappablePipeline>,
+    ) -> Self {
+        let path = path.into();
+        let seen = stamp(&path).ok();
+        Self {
+            path,
+            registry,
+            target,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            retire_timeout: DEFAULT_RETIRE_TIMEOUT,
+            seen,
+        }
+    }
+
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// How long a reload waits for runs on the old pipeline to finish
+    /// before leaving it to a background thread to close.
+    pub fn retire_timeout(mut self, timeout: Duration) -> Self {
+        self.retire_timeout = timeout;
+        self
+    }
+
+    /// Reloads if the file's modification time or size changed since the
+    /// last check.
+    pub fn check(&mut self) -> Result<Reload, ConfigError> {
+        let now = stamp(&self.path)
+            .map_err(|source| ConfigError::Io { path: self.path.clone(), source })?;
+        if self.seen == Some(now) {
+            return Ok(Reload::Unchanged);
+        }
+        self.seen = Some(now);
+        self.reload()
+    }
+
+    /// Rebuilds and swaps unconditionally. Nothing changes if any processor
+    /// or sink fails to build.
+    pub fn reload(&mut self) -> Result<Reload, ConfigError> {
+        let next = self.registry.load_pipeline(&self.path)?;
+        let old = self.target.swap(next);
+        let generation = self.target.generation();
+        let retired = if released(&old, Some(Instant::now() + self.retire_timeout)) {
+            Some(old.close())
+        } else {
+            thread::spawn(move || SwappablePipeline::retire(old));
+            None
+        };
+        Ok(Reload::Swapped { generation, retired })
+    }
+
+    /// Polls on a background thread until the handle is dropped, passing
+    /// every reload and every failed one to `report`.
+    pub fn spawn(
+        mut self,
+        mut report: impl FnMut(Result<Reload, ConfigError>) + Send + 'static,
+    ) -> WatchHandle {
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let stop = shutdown.clone();
+        let thread = thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                match self.check() {
+                    Ok(Reload::Unchanged) => {}
+                    res => report(res),
+                }
+                thread::sleep(self.poll_interval);
+            }
+        });
+        WatchHandle { shutdown, thread: Some(thread) }
+    }
+}
+
+fn stamp(path: &Path) -> io::Result<(SystemTime, u64)> {
+    let meta = fs::metadata(path)?;
+    Ok((meta.modified()?, meta.len()))
+}
+
+/// Stops the watcher thread when dropped.
+pub struct WatchHandle {
+    shutdown: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for WatchHandle {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::telemetry::{Output, ProcessError, Processor};
+
+    struct SetKind(String);
+
+    impl Processor for SetKind {
+        fn name(&self) -> &str {
+            "set_kind"
+        }
+
+        fn process(&self, mut input: TelemetryEvent) -> Result<Output, ProcessError> {
+            input.kind = self.0.clone();
+            Ok(input.into())
+        }
+    }
+
+    fn registry() -> Arc<Registry> {
+        let mut r = Registry::new();
+        r.register_processor("set_kind", |s| Ok(Box::new(SetKind(s.str("kind")?.to_owned()))));
+        Arc::new(r)
+    }
+
+    fn config(kind: &str) -> String {
+        format!("processors:\n  - type: set_kind\n    kind: {kind}\n")
+    }
+
+    fn kind_after(p: &SwappablePipeline) -> String {
+        p.run(TelemetryEvent::new("in")).unwrap().remove(0).kind
+    }
+
+    #[test]
+    fn swaps_on_change_and_keeps_running_pipeline_on_error() {
+        let path = std::env::temp_dir().join(format!("reload-{}.yaml", std::process::id()));
+        fs::write(&path, config("one")).unwrap();
+        let registry = registry();
+        let target = Arc::new(SwappablePipeline::new(registry.load_pipeline(&path).unwrap()));
+        let mut watcher = ConfigWatcher::new(&path, registry, target.clone());
+        assert!(matches!(watcher.check(), Ok(Reload::Unchanged)));
+        assert_eq!(kind_after(&target), "one");
+
+        fs::write(&path, config("second")).unwrap();
+        let reload = watcher.check().unwrap();
+        assert!(matches!(reload, Reload::Swapped { generation: 1, retired: Some(Ok(())) }));
+        assert_eq!(kind_after(&target), "second");
+
+        fs::write(&path, "processors:\n  - type: set_kind\n").unwrap();
+        let err = watcher.check().unwrap_err();
+        assert_eq!(err.key(), Some("processors[0].kind"));
+        assert_eq!((target.generation(), kind_after(&target)), (1, "second".to_owned()));
+        assert!(matches!(watcher.check(), Ok(Reload::Unchanged)));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn in_flight_runs_finish_on_the_old_pipeline() {
+        let target = SwappablePipeline::new(Pipeline::new());
+        let held = target.current();
+        let old = target.swap(Pipeline::new());
+        assert!(Arc::ptr_eq(&held, &old));
+        let (tx, rx) = mpsc::channel();
+        let retiring = thread::spawn(move || {
+            let res = SwappablePipeline::retire(old);
+            tx.send(()).unwrap();
+            res
+        });
+        thread::sleep(Duration::from_millis(20));
+        assert!(rx.try_recv().is_err());
+        drop(held);
+        retiring.join().unwrap().unwrap();
+        assert_eq!(target.generation(), 1);
+    }
+
+    #[test]
+    fn reloads_never_block_runs_and_keep_old_sinks_on_error() {
+        let dir = std::env::temp_dir().join(format!("reload-sinks-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (path, out) = (dir.join("config.yaml"), dir.join("out.jsonl"));
+        let with_sink = |kind: &str, ty: &str| {
+            format!("{}sinks:\n  - type: {ty}\n    path: {}\n", config(kind), out.display())
+        };
+        fs::write(&path, with_sink("a", "file")).unwrap();
+        let registry = registry();
+        let target = Arc::new(SwappablePipeline::new(registry.load_pipeline(&path).unwrap()));
+        let mut watcher = ConfigWatcher::new(&path, registry, target.clone())
+            .retire_timeout(Duration::from_millis(20));
+
+        fs::write(&path, with_sink("bb", "nope")).unwrap();
+        assert!(watcher.check().is_err());
+        assert_eq!((target.generation(), kind_after(&target)), (0, "a".to_owned()));
+
+        let held = target.current();
+        fs::write(&path, with_sink("ccc", "file")).unwrap();
+        let reload = watcher.check().unwrap();
+        assert!(matches!(reload, Reload::Swapped { generation: 1, retired: None }));
+        assert_eq!(kind_after(&target), "ccc");
+        held.run(TelemetryEvent::new("late")).unwrap();
+        drop(held);
+        target.current().close().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let written = loop {
+            let written = fs::read_to_string(&out).unwrap();
+            if written.lines().count() == 3 || Instant::now() >= deadline {
+                break written;
+            }
+            thread::sleep(Duration::from_millis(5));
+        };
+        assert_eq!(written.lines().count(), 3, "{written}");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn spawned_watcher_reports_reloads() {
+        let path = std::env::temp_dir().join(format!("reload-spawn-{}.yaml", std::process::id()));
+        fs::write(&path, config("a")).unwrap();
+        let registry = registry();
+        let target = Arc::new(SwappablePipeline::new(registry.load_pipeline(&path).unwrap()));
+        let (tx, rx) = mpsc::channel();
+        let handle = ConfigWatcher::new(&path, registry, target.clone())
+            .poll_interval(Duration::from_millis(5))
+            .spawn(move |res| tx.send(res.map(|_| ())).unwrap());
+        fs::write(&path, config("bb")).unwrap();
+        rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+        drop(handle);
+        assert_eq!(kind_after(&target), "bb");
+        fs::remove_file(&path).unwrap();
+    }
+}
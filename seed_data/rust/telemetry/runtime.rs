@@ -0,0 +1,690 @@
+//! A staged runtime: every processor and sink of a [`Pipeline`] runs on its
+//! own thread, and stages hand events to each other through bounded queues.
+//! A full queue applies its [`OverflowPolicy`], so a slow sink either holds
+//! back the stages in front of it or sheds load instead of buffering without
+//! limit.
+
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use super::codec::{CodecError, EventCodec, MessagePack};
+use super::metrics::StageMetrics;
+use super::{
+    Clock, Pipeline, PipelineError, PipelineStats, Sink, Stage, TelemetryEvent, BATCH_KIND,
+};
+
+/// What a queue does with an event that arrives while it is full.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait for room, pushing back on whoever is sending.
+    Block,
+    /// Discard the arriving event.
+    DropNewest,
+    /// Discard the event that has waited longest to make room.
+    DropOldest,
+    /// Append overflow to a file in this directory and read it back, in
+    /// order, as the queue drains. If the disk write fails the event is
+    /// dropped and counted.
+    SpillToDisk(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+pub struct QueueOptions {
+    pub capacity: NonZeroUsize,
+    pub overflow: OverflowPolicy,
+    /// Most events a stage takes off its queue for one
+    /// [`Processor::process_batch`](super::Processor::process_batch) call.
+    pub max_batch: NonZeroUsize,
+}
+
+impl Default for QueueOptions {
+    fn default() -> Self {
+        Self {
+            capacity: NonZeroUsize::new(1024).unwrap(),
+            overflow: OverflowPolicy::Block,
+            max_batch: NonZeroUsize::new(128).unwrap(),
+        }
+    }
+}
+
+/// A point-in-time view of the queue in front of one stage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueStats {
+    pub stage: String,
+    /// Events waiting in memory.
+    pub depth: usize,
+    pub capacity: usize,
+    /// Events waiting on disk under [`OverflowPolicy::SpillToDisk`].
+    pub spilled: usize,
+    pub dropped: u64,
+}
+
+impl QueueStats {
+    /// True when the queue is at capacity or has overflowed to disk.
+    pub fn saturated(&self) -> bool {
+        self.depth >= self.capacity || self.spilled > 0
+    }
+}
+
+struct Spill {
+    path: PathBuf,
+    writer: File,
+    reader: File,
+    pending: usize,
+    buf: Vec<u8>,
+}
+
+impl Spill {
+    /// Creates a file of its own in `dir`, named for the process, a
+    /// process-wide counter and the queue's stage `index`, so runtimes
+    /// sharing a directory never open each other's files.
+    fn create(dir: &Path, index: usize) -> io::Result<Self> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        loop {
+            let n = NEXT.fetch_add(1, Ordering::Relaxed);
+            let path = dir.join(format!("queue-{}-{n}-{index}.spill", std::process::id()));
+            let writer = match OpenOptions::new().create_new(true).write(true).open(&path) {
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                res => res?,
+            };
+            let reader = File::open(&path)?;
+            return Ok(Self { path, writer, reader, pending: 0, buf: Vec::new() });
+        }
+    }
+
+    fn push(&mut self, evt: &TelemetryEvent) -> io::Result<()> {
+        self.buf.clear();
+        MessagePack
+            .encode(evt, &mut self.buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let len = u32::try_from(self.buf.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "event too large to spill"))?;
+        let start = self.writer.stream_position()?;
+        let written = self
+            .writer
+            .write_all(&len.to_be_bytes())
+            .and_then(|()| self.writer.write_all(&self.buf));
+        if let Err(e) = written {
+            // Cut off the partial record so the reader never sees it.
+            self.writer.set_len(start)?;
+            self.writer.seek(SeekFrom::Start(start))?;
+            return Err(e);
+        }
+        self.pending += 1;
+        Ok(())
+    }
+
+    /// Reads the next record. The outer error means the file can no longer
+    /// be read in step; the inner one that this one record did not decode.
+    fn pop(&mut self) -> io::Result<Result<TelemetryEvent, CodecError>> {
+        let mut len = [0; 4];
+        self.reader.read_exact(&mut len)?;
+        self.buf.resize(u32::from_be_bytes(len) as usize, 0);
+        self.reader.read_exact(&mut self.buf)?;
+        self.pending -= 1;
+        if self.pending == 0 {
+            self.reset()?;
+        }
+        Ok(MessagePack.decode(&self.buf))
+    }
+
+    /// Forgets every record and starts the file over.
+    fn reset(&mut self) -> io::Result<()> {
+        self.pending = 0;
+        self.writer.set_len(0)?;
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.reader.seek(SeekFrom::Start(0))?;
+        Ok(())
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+struct State {
+    items: VecDeque<TelemetryEvent>,
+    spill: Option<Spill>,
+    dropped: u64,
+    closed: bool,
+}
+
+/// A bounded multi-producer queue feeding one stage.
+struct BoundedQueue {
+    stage: String,
+    capacity: usize,
+    overflow: OverflowPolicy,
+    state: Mutex<State>,
+    not_empty: Condvar,
+    not_full: Condvar,
+}
+
+impl BoundedQueue {
+    fn new(stage: String, index: usize, opts: &QueueOptions) -> io::Result<Self> {
+        let spill = match &opts.overflow {
+            OverflowPolicy::SpillToDisk(dir) => {
+                fs::create_dir_all(dir)?;
+                Some(Spill::create(dir, index)?)
+            }
+            _ => None,
+        };
+        Ok(Self {
+            stage,
+            capacity: opts.capacity.get(),
+            overflow: opts.overflow.clone(),
+            state: Mutex::new(State { items: VecDeque::new(), spill, dropped: 0, closed: false }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Hands the event back if the queue has been closed.
+    fn push(&self, evt: TelemetryEvent) -> Result<(), Box<TelemetryEvent>> {
+        let mut st = self.lock();
+        if st.closed {
+            return Err(Box::new(evt));
+        }
+        let spilling = st.spill.as_ref().is_some_and(|s| s.pending > 0);
+        if st.items.len() < self.capacity && !spilling {
+            st.items.push_back(evt);
+        } else {
+            match &self.overflow {
+                OverflowPolicy::Block => {
+                    st = self
+                        .not_full
+                        .wait_while(st, |st| st.items.len() >= self.capacity && !st.closed)
+                        .unwrap_or_else(PoisonError::into_inner);
+                    if st.closed {
+                        return Err(Box::new(evt));
+                    }
+                    st.items.push_back(evt);
+                }
+                OverflowPolicy::DropNewest => st.dropped += 1,
+                OverflowPolicy::DropOldest => {
+                    st.items.pop_front();
+                    st.items.push_back(evt);
+                    st.dropped += 1;
+                }
+                OverflowPolicy::SpillToDisk(_) => {
+                    let spill = st.spill.as_mut().expect("spill file is opened with the queue");
+                    if spill.push(&evt).is_err() {
+                        st.dropped += 1;
+                    }
+                }
+            }
+        }
+        drop(st);
+        self.not_empty.notify_one();
+        Ok(())
+    }
+
+    /// Waits for at least one event and takes up to `max`. Returns `None`
+    /// once the queue is closed and empty.
+    fn pop_batch(&self, max: usize) -> Option<Vec<TelemetryEvent>> {
+        let mut st = self
+            .not_empty
+            .wait_while(self.lock(), |st| st.items.is_empty() && !st.closed)
+            .unwrap_or_else(PoisonError::into_inner);
+        if st.items.is_empty() {
+            return None;
+        }
+        let take = max.min(st.items.len());
+        let batch: Vec<_> = st.items.drain(..take).collect();
+        let st = &mut *st;
+        if let Some(spill) = st.spill.as_mut() {
+            while spill.pending > 0 && st.items.len() < self.capacity {
+                match spill.pop() {
+                    Ok(Ok(evt)) => st.items.push_back(evt),
+                    Ok(Err(_)) => st.dropped += 1,
+                    Err(_) => {
+                        st.dropped += spill.pending as u64;
+                        let _ = spill.reset();
+                    }
+                }
+            }
+        }
+        self.not_full.notify_all();
+        Some(batch)
+    }
+
+    fn close(&self) {
+        self.lock().closed = true;
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+    }
+
+    fn stats(&self) -> QueueStats {
+        let st = self.lock();
+        QueueStats {
+            stage: self.stage.clone(),
+            depth: st.items.len(),
+            capacity: self.capacity,
+            spilled: st.spill.as_ref().map_or(0, |s| s.pending),
+            dropped: st.dropped,
+        }
+    }
+}
+
+type ErrorHandler = Arc<dyn Fn(PipelineError) + Send + Sync>;
+
+/// A running [`Pipeline`] split into threaded stages. Built with
+/// [`Pipeline::into_staged`].
+pub struct StagedRuntime {
+    queues: Vec<Arc<BoundedQueue>>,
+    first_sink: usize,
+    metrics: Vec<Arc<StageMetrics>>,
+    threads: Vec<JoinHandle<()>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Pipeline {
+    /// Starts one thread per processor and per sink, with a queue in front
+    /// of each. Since nobody is waiting on a result, a failed batch is handed
+    /// to `on_error` and dropped while the runtime keeps going.
+    pub fn into_staged(
+        self,
+        opts: QueueOptions,
+        on_error: impl Fn(PipelineError) + Send + Sync + 'static,
+    ) -> io::Result<StagedRuntime> {
+        let on_error: ErrorHandler = Arc::new(on_error);
+        let Pipeline { processors, sinks, metrics, clock } = self;
+        let first_sink = processors.len();
+        let mut names: Vec<String> = processors.iter().map(|p| p.name().to_owned()).collect();
+        let sinks: Vec<Box<dyn Sink>> = sinks
+            .into_iter()
+            .map(|s| s.into_inner().unwrap_or_else(PoisonError::into_inner))
+            .collect();
+        names.extend(sinks.iter().map(|s| s.name().to_owned()));
+        let queues = names
+            .into_iter()
+            .enumerate()
+            .map(|(i, name)| BoundedQueue::new(name, i, &opts).map(Arc::new))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let mut threads = Vec::new();
+        let max_batch = opts.max_batch.get();
+        for (i, stage) in processors.into_iter().enumerate() {
+            let input = queues[i].clone();
+            let next: Vec<_> = if i + 1 < first_sink {
+                vec![queues[i + 1].clone()]
+            } else {
+                queues[first_sink..].to_vec()
+            };
+            let (stats, on_error) = (metrics[i].clone(), on_error.clone());
+            let close = CloseOnExit([vec![input.clone()], next.clone()].concat());
+            threads.push(thread::spawn(move || {
+                let _close = close;
+                run_stage(i, stage, &stats, &input, &next, max_batch, &*on_error);
+            }));
+        }
+        for (j, sink) in sinks.into_iter().enumerate() {
+            let input = queues[first_sink + j].clone();
+            let (stats, on_error) = (metrics[first_sink + j].clone(), on_error.clone());
+            let close = CloseOnExit(vec![input.clone()]);
+            threads.push(thread::spawn(move || {
+                let _close = close;
+                run_sink(first_sink + j, sink, &stats, &input, max_batch, &*on_error);
+            }));
+        }
+        Ok(StagedRuntime { queues, first_sink, metrics, threads, clock })
+    }
+}
+
+/// Closes its queues when a stage thread ends, even by panicking, so the
+/// stages after it still see the end of their input and nothing upstream
+/// waits on a queue nobody drains.
+struct CloseOnExit(Vec<Arc<BoundedQueue>>);
+
+impl Drop for CloseOnExit {
+    fn drop(&mut self) {
+        self.0.iter().for_each(|q| q.close());
+    }
+}
+
+fn run_stage(
+    index: usize,
+    stage: Stage,
+    metrics: &StageMetrics,
+    input: &BoundedQueue,
+    next: &[Arc<BoundedQueue>],
+    max_batch: usize,
+    on_error: &dyn Fn(PipelineError),
+) {
+    while let Some(batch) = input.pop_batch(max_batch) {
+        let kind = match &batch[..] {
+            [only] => only.kind.clone(),
+            _ => BATCH_KIND.to_owned(),
+        };
+        match stage.process_observed(metrics, batch) {
+            Ok(out) => forward(out, next),
+            Err(e) => on_error(stage.fail(index, kind, e)),
+        }
+    }
+}
+
+fn forward(events: Vec<TelemetryEvent>, next: &[Arc<BoundedQueue>]) {
+    let Some((last, rest)) = next.split_last() else { return };
+    for evt in events {
+        for q in rest {
+            let _ = q.push(evt.clone());
+        }
+        let _ = last.push(evt);
+    }
+}
+
+fn run_sink(
+    index: usize,
+    mut sink: Box<dyn Sink>,
+    metrics: &StageMetrics,
+    input: &BoundedQueue,
+    max_batch: usize,
+    on_error: &dyn Fn(PipelineError),
+) {
+    let fail = |sink: &dyn Sink, kind: String, e: io::Error| PipelineError {
+        processor: sink.name().to_owned(),
+        index,
+        kind,
+        source: super::ProcessError::with_source("sink failed", e),
+    };
+    while let Some(batch) = input.pop_batch(max_batch) {
+        let start = Instant::now();
+        let res = sink.write(&batch);
+        metrics.record(batch.len(), res.as_ref().ok().map(|_| batch.len()), start.elapsed());
+        if let Err(e) = res {
+            let kind = match &batch[..] {
+                [only] => only.kind.clone(),
+                _ => BATCH_KIND.to_owned(),
+            };
+            on_error(fail(&*sink, kind, e));
+        }
+    }
+    if let Err(e) = sink.close() {
+        on_error(fail(&*sink, BATCH_KIND.to_owned(), e));
+    }
+}
+
+impl StagedRuntime {
+    /// Queues an event at the first stage, applying that queue's overflow
+    /// policy. Hands the event back if the runtime is shutting down or has
+    /// no stages.
+    pub fn send(&self, mut evt: TelemetryEvent) -> Result<(), Box<TelemetryEvent>> {
+        evt.ingested_at.get_or_insert_with(|| self.clock.now());
+        let Some((last, rest)) = self.entry().split_last() else {
+            return Err(Box::new(evt));
+        };
+        for q in rest {
+            let _ = q.push(evt.clone());
+        }
+        last.push(evt)
+    }
+
+    /// The queues `send` feeds: the first processor's, or every sink's when
+    /// there are no processors.
+    fn entry(&self) -> &[Arc<BoundedQueue>] {
+        match self.first_sink {
+            0 => &self.queues,
+            _ => &self.queues[..1],
+        }
+    }
+
+    /// One entry per stage, in pipeline order: processors, then sinks.
+    pub fn queue_stats(&self) -> Vec<QueueStats> {
+        self.queues.iter().map(|q| q.stats()).collect()
+    }
+
+    /// Per-stage counters, as [`Pipeline::stats`] would report them.
+    pub fn stats(&self) -> PipelineStats {
+        PipelineStats::collect(self.metrics.iter().map(|m| &**m))
+    }
+
+    /// Stops accepting events, lets everything queued drain through the
+    /// remaining stages, and closes the sinks.
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        self.entry().iter().for_each(|q| q.close());
+        for t in self.threads.drain(..) {
+            let _ = t.join();
+        }
+    }
+}
+
+impl Drop for StagedRuntime {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::telemetry::{Output, ProcessError, Processor};
+
+    struct Tag;
+
+    impl Processor for Tag {
+        fn name(&self) -> &str {
+            "tag"
+        }
+
+        fn process(&self, mut input: TelemetryEvent) -> Result<Output, ProcessError> {
+            if input.kind == "bad" {
+                return Err(ProcessError::new("bad event"));
+            }
+            input.insert("tagged", true);
+            Ok(input.into())
+        }
+    }
+
+    /// Records what it receives, optionally waiting for permission first.
+    struct Capture {
+        seen: mpsc::Sender<String>,
+        gate: Option<Arc<(Mutex<bool>, Condvar)>>,
+    }
+
+    impl Sink for Capture {
+        fn name(&self) -> &str {
+            "capture"
+        }
+
+        fn write(&mut self, events: &[TelemetryEvent]) -> io::Result<()> {
+            if let Some(gate) = &self.gate {
+                let _open = gate.1.wait_while(gate.0.lock().unwrap(), |open| !*open).unwrap();
+            }
+            events.iter().for_each(|e| self.seen.send(e.kind.clone()).unwrap());
+            Ok(())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn close(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn opts(capacity: usize, overflow: OverflowPolicy) -> QueueOptions {
+        QueueOptions {
+            capacity: NonZeroUsize::new(capacity).unwrap(),
+            overflow,
+            max_batch: NonZeroUsize::new(1).unwrap(),
+        }
+    }
+
+    #[test]
+    fn events_flow_through_and_errors_are_reported() {
+        let (tx, rx) = mpsc::channel();
+        let errors = Arc::new(AtomicUsize::new(0));
+        let mut pipeline = Pipeline::new();
+        pipeline.add(Box::new(Tag));
+        pipeline.add_sink(Box::new(Capture { seen: tx, gate: None }));
+        let counted = errors.clone();
+        let rt = pipeline
+            .into_staged(opts(16, OverflowPolicy::Block), move |e| {
+                assert_eq!((e.processor.as_str(), e.kind.as_str()), ("tag", "bad"));
+                counted.fetch_add(1, Ordering::Relaxed);
+            })
+            .unwrap();
+        for kind in ["a", "bad", "b"] {
+            rt.send(TelemetryEvent::new(kind)).unwrap();
+        }
+        let stats = loop {
+            let stats = rt.stats();
+            if stats.stages[1].events_in == 2 {
+                break stats;
+            }
+            thread::sleep(Duration::from_millis(1));
+        };
+        rt.shutdown();
+        assert_eq!(rx.iter().collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(errors.load(Ordering::Relaxed), 1);
+        assert_eq!((stats.stages[0].events_in, stats.stages[0].errors), (3, 1));
+    }
+
+    struct Boom;
+
+    impl Processor for Boom {
+        fn name(&self) -> &str {
+            "boom"
+        }
+
+        fn process(&self, _: TelemetryEvent) -> Result<Output, ProcessError> {
+            panic!("boom")
+        }
+    }
+
+    #[test]
+    fn a_panicking_stage_still_ends_the_stages_after_it() {
+        let (tx, rx) = mpsc::channel();
+        let mut pipeline = Pipeline::new();
+        pipeline.add(Box::new(Boom));
+        pipeline.add(Box::new(Tag));
+        pipeline.add_sink(Box::new(Capture { seen: tx, gate: None }));
+        let rt = pipeline.into_staged(opts(1, OverflowPolicy::Block), |e| panic!("{e}")).unwrap();
+        rt.send(TelemetryEvent::new("a")).unwrap();
+        let (done, finished) = mpsc::channel();
+        thread::spawn(move || {
+            while rt.send(TelemetryEvent::new("b")).is_ok() {}
+            rt.shutdown();
+            done.send(()).unwrap();
+        });
+        finished.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(rx.iter().count(), 0);
+    }
+
+    /// Blocks the sink, fills its queue past capacity, then releases it.
+    fn saturate(overflow: OverflowPolicy) -> (Vec<QueueStats>, Vec<String>) {
+        let (tx, rx) = mpsc::channel();
+        let gate = Arc::new((Mutex::new(false), Condvar::new()));
+        let mut pipeline = Pipeline::new();
+        pipeline.add_sink(Box::new(Capture { seen: tx, gate: Some(gate.clone()) }));
+        let rt = pipeline.into_staged(opts(2, overflow), |e| panic!("{e}")).unwrap();
+        rt.send(TelemetryEvent::new("0")).unwrap();
+        while rt.queue_stats()[0].depth > 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        for kind in ["1", "2", "3", "4"] {
+            rt.send(TelemetryEvent::new(kind)).unwrap();
+        }
+        let stats = rt.queue_stats();
+        *gate.0.lock().unwrap() = true;
+        gate.1.notify_all();
+        rt.shutdown();
+        (stats, rx.iter().collect())
+    }
+
+    #[test]
+    fn drop_newest_keeps_the_head() {
+        let (stats, seen) = saturate(OverflowPolicy::DropNewest);
+        assert_eq!((stats[0].depth, stats[0].dropped), (2, 2));
+        assert!(stats[0].saturated());
+        assert_eq!(seen, ["0", "1", "2"]);
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_tail() {
+        let (stats, seen) = saturate(OverflowPolicy::DropOldest);
+        assert_eq!(stats[0].dropped, 2);
+        assert_eq!(seen, ["0", "3", "4"]);
+    }
+
+    #[test]
+    fn spill_to_disk_keeps_everything_in_order() {
+        let dir = std::env::temp_dir().join(format!("spill-{}", std::process::id()));
+        let (stats, seen) = saturate(OverflowPolicy::SpillToDisk(dir.clone()));
+        assert_eq!((stats[0].depth, stats[0].spilled, stats[0].dropped), (2, 2, 0));
+        assert_eq!(seen, ["0", "1", "2", "3", "4"]);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn spill_files_are_private_and_skip_bad_records() {
+        let dir = std::env::temp_dir().join(format!("spill-bad-{}", std::process::id()));
+        let overflow = opts(1, OverflowPolicy::SpillToDisk(dir.clone()));
+        let queue = BoundedQueue::new("q".into(), 0, &overflow).unwrap();
+        let other = BoundedQueue::new("q".into(), 0, &overflow).unwrap();
+        for kind in ["a", "b", "c", "d"] {
+            queue.push(TelemetryEvent::new(kind)).unwrap();
+        }
+        other.push(TelemetryEvent::new("x")).unwrap();
+        other.push(TelemetryEvent::new("y")).unwrap();
+        {
+            let mut st = queue.lock();
+            let spill = st.spill.as_mut().unwrap();
+            // Corrupt the first spilled record ("b") in place.
+            let mut file = OpenOptions::new().write(true).open(&spill.path).unwrap();
+            file.seek(SeekFrom::Start(4)).unwrap();
+            file.write_all(&[0xc1]).unwrap();
+        }
+        let mut seen = Vec::new();
+        while let Some(batch) = queue.pop_batch(8) {
+            seen.extend(batch.into_iter().map(|e| e.kind));
+            if queue.stats().depth == 0 {
+                break;
+            }
+        }
+        assert_eq!(seen, ["a", "c", "d"]);
+        assert_eq!(queue.stats().dropped, 1);
+        assert_eq!(other.pop_batch(8).unwrap()[0].kind, "x");
+        assert_eq!(other.pop_batch(8).unwrap()[0].kind, "y");
+        drop((queue, other));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn block_pushes_back_on_the_sender() {
+        let queue =
+            Arc::new(BoundedQueue::new("q".into(), 0, &opts(1, OverflowPolicy::Block)).unwrap());
+        queue.push(TelemetryEvent::new("a")).unwrap();
+        let pusher = {
+            let queue = queue.clone();
+            thread::spawn(move || queue.push(TelemetryEvent::new("b")))
+        };
+        thread::sleep(Duration::from_millis(20));
+        assert!(!pusher.is_finished());
+        assert_eq!(queue.pop_batch(8).unwrap().len(), 1);
+        pusher.join().unwrap().unwrap();
+        assert_eq!(queue.stats().depth, 1);
+    }
+}
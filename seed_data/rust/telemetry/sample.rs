@@ -0,0 +1,348 @@
+//! Processors that keep only some events.
+//!
+//! Every kept event carries the probability it had of being kept under
+//! [`SAMPLE_RATE_KEY`], so each one stands for `1 / sample_rate` originals
+//! when counting downstream. Chained samplers multiply the recorded rate.
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime};
+
+use super::{Clock, Output, ProcessError, Processor, SystemClock, TelemetryEvent, Value};
+
+/// The payload key kept events record their sample rate under, as a float.
+pub const SAMPLE_RATE_KEY: &str = "sample_rate";
+
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(10);
+pub const DEFAULT_MAX_KINDS: usize = 10_000;
+
+/// A lock-free splitmix64 stream; good enough to pick events, not for keys.
+struct Rng(AtomicU64);
+
+impl Rng {
+    fn new() -> Self {
+        Rng(AtomicU64::new(RandomState::new().hash_one(std::process::id())))
+    }
+
+    /// Uniform in `[0, 1)`.
+    fn next_unit(&self) -> f64 {
+        to_unit(mix(self.0.fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed)))
+    }
+}
+
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+fn to_unit(bits: u64) -> f64 {
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Rates outside `[0, 1]` are clamped; NaN counts as 0.
+fn clamp(rate: f64) -> f64 {
+    if rate.is_nan() {
+        0.0
+    } else {
+        rate.clamp(0.0, 1.0)
+    }
+}
+
+fn keep(mut evt: TelemetryEvent, rate: f64) -> Output {
+    let prior = evt.get(SAMPLE_RATE_KEY).and_then(Value::as_f64).unwrap_or(1.0);
+    evt.insert(SAMPLE_RATE_KEY, prior * rate);
+    evt.into()
+}
+
+/// Keeps each event independently with probability `rate`.
+pub struct RandomSampler {
+    rate: f64,
+    rng: Rng,
+}
+
+impl RandomSampler {
+    pub fn new(rate: f64) -> Self {
+        Self { rate: clamp(rate), rng: Rng::new() }
+    }
+
+    /// Makes the choices repeatable, for tests.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.rng = Rng(AtomicU64::new(seed));
+        self
+    }
+}
+
+impl Processor for RandomSampler {
+    fn name(&self) -> &str {
+        "random_sampler"
+    }
+
+    fn process(&self, input: TelemetryEvent) -> Result<Output, ProcessError> {
+        Ok(match self.rng.next_unit() < self.rate {
+            true => keep(input, self.rate),
+            false => Output::Drop,
+        })
+    }
+}
+
+/// Keeps events by a hash of one payload value, so all events sharing the
+/// value are kept or dropped together, in every process and every run.
+/// Samplers at a lower rate keep a subset of what higher rates keep.
+/// Events without the key are sampled at random at the same rate.
+pub struct KeySampler {
+    key: String,
+    rate: f64,
+    rng: Rng,
+}
+
+impl KeySampler {
+    pub fn new(key: impl Into<String>, rate: f64) -> Self {
+        Self { key: key.into(), rate: clamp(rate), rng: Rng::new() }
+    }
+}
+
+/// FNV-1a, finished with the splitmix mixer: stable, unlike `std`'s hasher.
+fn stable_hash(bytes: &[u8]) -> u64 {
+    let mut h = 0xcbf2_9ce4_8422_2325u64;
+    for &b in bytes {
+        h = (h ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3);
+    }
+    mix(h)
+}
+
+impl Processor for KeySampler {
+    fn name(&self) -> &str {
+        "key_sampler"
+    }
+
+    fn process(&self, input: TelemetryEvent) -> Result<Output, ProcessError> {
+        let draw = match input.get(&self.key) {
+            Some(value) => to_unit(stable_hash(value.to_string().as_bytes())),
+            None => self.rng.next_unit(),
+        };
+        Ok(match draw < self.rate {
+            true => keep(input, self.rate),
+            false => Output::Drop,
+        })
+    }
+}
+
+struct KindWindow {
+    started: SystemTime,
+    seen: u64,
+    rate: f64,
+}
+
+/// Aims for a steady number of events per second for each `kind`. Every
+/// window, the rate for the next one is set from how many events of the
+/// kind arrived in the last: kinds under the target keep everything, and
+/// a burst is sampled down one window after it starts. State is kept per
+/// kind, up to [`max_kinds`](Self::max_kinds) of them.
+pub struct DynamicSampler {
+    per_second: f64,
+    window: Duration,
+    max_kinds: usize,
+    kinds: Mutex<HashMap<String, KindWindow>>,
+    clock: Arc<dyn Clock>,
+    rng: Rng,
+}
+
+impl DynamicSampler {
+    pub fn new(per_second: f64) -> Self {
+        Self {
+            per_second: per_second.max(0.0),
+            window: DEFAULT_WINDOW,
+            max_kinds: DEFAULT_MAX_KINDS,
+            kinds: Mutex::new(HashMap::new()),
+            clock: Arc::new(SystemClock),
+            rng: Rng::new(),
+        }
+    }
+
+    /// How long each rate holds before it is recomputed.
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Caps how many kinds are tracked. Past the cap, the windows that
+    /// started longest ago are forgotten to make room, idle kinds first; a
+    /// forgotten kind starts over keeping everything for a window.
+    pub fn max_kinds(mut self, max: usize) -> Self {
+        self.max_kinds = max;
+        self
+    }
+
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.rng = Rng(AtomicU64::new(seed));
+        self
+    }
+
+    /// Counts the event and returns the rate it is sampled at.
+    fn rate_for(&self, kind: &str) -> f64 {
+        let now = self.clock.now();
+        let mut kinds = self.kinds.lock().unwrap_or_else(PoisonError::into_inner);
+        if !kinds.contains_key(kind) {
+            if kinds.len() >= self.max_kinds {
+                self.evict(&mut kinds);
+            }
+            kinds.insert(kind.to_owned(), KindWindow { started: now, seen: 0, rate: 1.0 });
+        }
+        let w = kinds.get_mut(kind).expect("inserted above");
+        match now.duration_since(w.started) {
+            Ok(age) if age < self.window => {}
+            Ok(age) if age < self.window * 2 => {
+                let target = self.per_second * self.window.as_secs_f64();
+                w.rate = clamp(target / w.seen as f64);
+                (w.started, w.seen) = (now, 0);
+            }
+            // Idle for a whole window, or the clock went backwards.
+            _ => *w = KindWindow { started: now, seen: 0, rate: 1.0 },
+        }
+        w.seen += 1;
+        w.rate
+    }
+
+    /// Forgets a tenth of the kinds, at least one: those whose window
+    /// started longest ago, which takes kinds gone idle before busy ones.
+    /// Freeing a tenth at a time keeps the scan to once per that many new
+    /// kinds.
+    fn evict(&self, kinds: &mut HashMap<String, KindWindow>) {
+        let mut oldest: Vec<(SystemTime, String)> =
+            kinds.iter().map(|(kind, w)| (w.started, kind.clone())).collect();
+        let evict = (self.max_kinds / 10).max(1).min(oldest.len());
+        if evict == 0 {
+            return;
+        }
+        oldest.select_nth_unstable(evict - 1);
+        for (_, kind) in &oldest[..evict] {
+            kinds.remove(kind);
+        }
+    }
+}
+
+impl Processor for DynamicSampler {
+    fn name(&self) -> &str {
+        "dynamic_sampler"
+    }
+
+    fn process(&self, input: TelemetryEvent) -> Result<Output, ProcessError> {
+        let rate = self.rate_for(&input.kind);
+        Ok(match self.rng.next_unit() < rate {
+            true => keep(input, rate),
+            false => Output::Drop,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+    use crate::telemetry::ManualClock;
+
+    fn kept(
+        p: &dyn Processor,
+        events: impl IntoIterator<Item = TelemetryEvent>,
+    ) -> Vec<TelemetryEvent> {
+        let mut out = Vec::new();
+        for evt in events {
+            if let Output::One(evt) = p.process(evt).unwrap() {
+                out.push(evt);
+            }
+        }
+        out
+    }
+
+    fn events(kind: &str, n: usize) -> Vec<TelemetryEvent> {
+        (0..n).map(|_| TelemetryEvent::new(kind)).collect()
+    }
+
+    #[test]
+    fn random_sampler_keeps_about_its_rate_and_records_it() {
+        let out = kept(&RandomSampler::new(0.25).seed(7), events("x", 10_000));
+        assert!((2_200..2_800).contains(&out.len()), "{}", out.len());
+        assert!(out.iter().all(|e| e.get(SAMPLE_RATE_KEY) == Some(&Value::Float(0.25))));
+
+        assert_eq!(kept(&RandomSampler::new(1.0), events("x", 100)).len(), 100);
+        assert!(kept(&RandomSampler::new(-3.0), events("x", 100)).is_empty());
+
+        let mut pre = TelemetryEvent::new("x");
+        pre.insert(SAMPLE_RATE_KEY, 0.5);
+        let out = kept(&RandomSampler::new(1.0), [pre]);
+        assert_eq!(out[0].get(SAMPLE_RATE_KEY), Some(&Value::Float(0.5)));
+    }
+
+    #[test]
+    fn key_sampler_is_consistent_per_value() {
+        let sampler = KeySampler::new("user", 0.5);
+        let user_events = |user: i64| {
+            (0..20).map(move |_| {
+                let mut evt = TelemetryEvent::new("click");
+                evt.insert("user", user);
+                evt
+            })
+        };
+        let mut users_kept = 0;
+        for user in 0..200 {
+            match kept(&sampler, user_events(user)).len() {
+                0 => {}
+                20 => users_kept += 1,
+                n => panic!("user {user}: kept {n} of 20"),
+            }
+        }
+        assert!((70..130).contains(&users_kept), "{users_kept}");
+
+        let lower = KeySampler::new("user", 0.1);
+        for user in 0..200 {
+            if !kept(&lower, user_events(user).take(1)).is_empty() {
+                assert_eq!(kept(&sampler, user_events(user).take(1)).len(), 1);
+            }
+        }
+    }
+
+    #[test]
+    fn dynamic_sampler_tracks_each_kind() {
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH));
+        let sampler =
+            DynamicSampler::new(10.0).window(Duration::from_secs(1)).clock(clock.clone()).seed(3);
+        assert_eq!(kept(&sampler, events("noisy", 1_000)).len(), 1_000);
+        assert_eq!(kept(&sampler, events("quiet", 5)).len(), 5);
+
+        clock.advance(Duration::from_secs(1));
+        let noisy = kept(&sampler, events("noisy", 1_000));
+        assert!((3..30).contains(&noisy.len()), "{}", noisy.len());
+        assert!(noisy.iter().all(|e| e.get(SAMPLE_RATE_KEY) == Some(&Value::Float(0.01))));
+        assert_eq!(kept(&sampler, events("quiet", 5)).len(), 5);
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(kept(&sampler, events("noisy", 10)).len(), 10);
+    }
+
+    #[test]
+    fn dynamic_sampler_forgets_idle_kinds_past_the_cap() {
+        let clock = Arc::new(ManualClock::new(UNIX_EPOCH));
+        let sampler = DynamicSampler::new(10.0)
+            .window(Duration::from_secs(1))
+            .max_kinds(2)
+            .clock(clock.clone())
+            .seed(3);
+        kept(&sampler, events("idle", 1));
+        clock.advance(Duration::from_secs(5));
+        kept(&sampler, events("noisy", 1_000));
+        kept(&sampler, events("new", 1));
+        let kinds = sampler.kinds.lock().unwrap();
+        assert_eq!(kinds.len(), 2);
+        assert!(kinds.contains_key("noisy") && kinds.contains_key("new"));
+    }
+}
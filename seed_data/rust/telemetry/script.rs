@@ -0,0 +1,278 @@
+//! A processor that runs a [Rhai](https://rhai.rs) script against each
+//! event, for field edits too small to deserve a release.
+//!
+//! The script sees three variables and whatever it leaves in them is the
+//! result:
+//!
+//! - `kind`: the event kind, a string;
+//! - `payload`: the payload as an object map. Setting a field to `()`
+//!   removes it;
+//! - `drop`: `false`; set it to `true` to discard the event.
+//!
+//! ```rhai
+//! if payload.status >= 500 { kind = "http.error"; }
+//! payload.remove("cookie");
+//! if payload.path == "/healthz" { drop = true; }
+//! ```
+//!
+//! Scripts are compiled once, up front. A runaway script is cut off after
+//! [`DEFAULT_MAX_OPERATIONS`] steps, or once a string it builds passes
+//! [`MAX_STRING_SIZE`] bytes or an array or map passes
+//! [`MAX_COLLECTION_SIZE`] entries. `print` and `debug` are accepted and
+//! go nowhere.
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use rhai::{Dynamic, Engine, Scope, AST};
+
+use super::{Output, Payload, ProcessError, Processor, TelemetryEvent, Value};
+
+pub const DEFAULT_MAX_OPERATIONS: u64 = 100_000;
+pub const MAX_STRING_SIZE: usize = 1 << 20;
+pub const MAX_COLLECTION_SIZE: usize = 100_000;
+
+#[derive(Debug)]
+pub enum ScriptError {
+    Io(io::Error),
+    Compile(String),
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScriptError::Io(e) => write!(f, "i/o error: {e}"),
+            ScriptError::Compile(msg) => write!(f, "script does not compile: {msg}"),
+        }
+    }
+}
+
+impl Error for ScriptError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ScriptError::Io(e) => Some(e),
+            ScriptError::Compile(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for ScriptError {
+    fn from(e: io::Error) -> Self {
+        ScriptError::Io(e)
+    }
+}
+
+pub struct ScriptProcessor {
+    name: String,
+    engine: Engine,
+    ast: AST,
+}
+
+impl ScriptProcessor {
+    pub fn new(name: impl Into<String>, source: &str) -> Result<Self, ScriptError> {
+        let mut engine = Engine::new();
+        engine
+            .set_max_operations(DEFAULT_MAX_OPERATIONS)
+            .set_max_string_size(MAX_STRING_SIZE)
+            .set_max_array_size(MAX_COLLECTION_SIZE)
+            .set_max_map_size(MAX_COLLECTION_SIZE)
+            .on_print(|_| {})
+            .on_debug(|_, _, _| {});
+        let ast = engine.compile(source).map_err(|e| ScriptError::Compile(e.to_string()))?;
+        Ok(Self { name: name.into(), engine, ast })
+    }
+
+    /// Names the processor after the file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ScriptError> {
+        let path = path.as_ref();
+        Self::new(format!("script:{}", path.display()), &fs::read_to_string(path)?)
+    }
+
+    /// Steps a single run may take before it is aborted; 0 lifts the limit.
+    pub fn max_operations(mut self, ops: u64) -> Self {
+        self.engine.set_max_operations(ops);
+        self
+    }
+
+    fn fail(&self, message: String) -> ProcessError {
+        ProcessError::new(format!("script `{}`: {message}", self.name))
+    }
+}
+
+impl Processor for ScriptProcessor {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn process(&self, mut input: TelemetryEvent) -> Result<Output, ProcessError> {
+        let payload = std::mem::take(&mut input.payload);
+        let mut scope = Scope::new();
+        scope.push("kind", std::mem::take(&mut input.kind));
+        scope.push("payload", to_dynamic(Value::Map(payload)));
+        scope.push("drop", false);
+        self.engine
+            .run_ast_with_scope(&mut scope, &self.ast)
+            .map_err(|e| ProcessError::with_source(format!("script `{}` failed", self.name), e))?;
+
+        let get = |var: &str| scope.get_value::<Dynamic>(var).unwrap_or(Dynamic::UNIT);
+        if get("drop")
+            .as_bool()
+            .map_err(|t| self.fail(format!("`drop` must be a bool, not {t}")))?
+        {
+            return Ok(Output::Drop);
+        }
+        input.kind = get("kind")
+            .into_string()
+            .map_err(|t| self.fail(format!("`kind` must be a string, not {t}")))?;
+        input.payload = match from_dynamic(get("payload")) {
+            Ok(Some(Value::Map(map))) => map,
+            Ok(_) => return Err(self.fail("`payload` must be a map".into())),
+            Err(path) => return Err(self.fail(format!("`payload{path}` has an unsupported type"))),
+        };
+        Ok(input.into())
+    }
+}
+
+fn to_dynamic(value: Value) -> Dynamic {
+    match value {
+        Value::String(s) => s.into(),
+        Value::Int(n) => n.into(),
+        Value::Float(f) => f.into(),
+        Value::Bool(b) => b.into(),
+        Value::Bytes(b) => Dynamic::from_blob(b),
+        Value::List(items) => items.into_iter().map(to_dynamic).collect::<rhai::Array>().into(),
+        Value::Map(map) => {
+            map.into_iter().map(|(k, v)| (k.into(), to_dynamic(v))).collect::<rhai::Map>().into()
+        }
+    }
+}
+
+/// `Ok(None)` for `()`, which removes a map entry. On failure returns the
+/// path to the offending value, e.g. `.tags[2]`.
+fn from_dynamic(value: Dynamic) -> Result<Option<Value>, String> {
+    if value.is_unit() {
+        return Ok(None);
+    }
+    if value.is_string() || value.is_char() {
+        return Ok(Some(Value::String(value.to_string())));
+    }
+    if let Some(n) = value.clone().try_cast::<rhai::INT>() {
+        return Ok(Some(Value::Int(n)));
+    }
+    if let Some(f) = value.clone().try_cast::<rhai::FLOAT>() {
+        return Ok(Some(Value::Float(f)));
+    }
+    if let Some(b) = value.clone().try_cast::<bool>() {
+        return Ok(Some(Value::Bool(b)));
+    }
+    if value.is_blob() {
+        return Ok(value.try_cast::<rhai::Blob>().map(Value::Bytes));
+    }
+    if value.is_array() {
+        let items = value.cast::<rhai::Array>();
+        let mut out = Vec::with_capacity(items.len());
+        for (i, item) in items.into_iter().enumerate() {
+            match from_dynamic(item) {
+                Ok(Some(v)) => out.push(v),
+                Ok(None) => return Err(format!("[{i}]")),
+                Err(path) => return Err(format!("[{i}]{path}")),
+            }
+        }
+        return Ok(Some(Value::List(out)));
+    }
+    if value.is_map() {
+        let mut out = Payload::new();
+        for (k, v) in value.cast::<rhai::Map>() {
+            match from_dynamic(v) {
+                Ok(Some(v)) => {
+                    out.insert(k.to_string(), v);
+                }
+                Ok(None) => {}
+                Err(path) => return Err(format!(".{k}{path}")),
+            }
+        }
+        return Ok(Some(Value::Map(out)));
+    }
+    Err(String::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event() -> TelemetryEvent {
+        let mut evt = TelemetryEvent::new("http");
+        evt.insert("status", 503);
+        evt.insert("path", "/api");
+        evt.insert("cookie", "abc");
+        evt.insert("body", vec![1u8, 2]);
+        evt
+    }
+
+    fn run(source: &str, evt: TelemetryEvent) -> Result<Output, ProcessError> {
+        ScriptProcessor::new("t", source).unwrap().process(evt)
+    }
+
+    #[test]
+    fn edits_kind_and_payload() {
+        let source = r#"
+            if payload.status >= 500 { kind = "http.error"; }
+            payload.cookie = ();
+            payload.tags = ["a", 1, 2.5, true];
+            payload.path += "/v2";
+        "#;
+        let Output::One(out) = run(source, event()).unwrap() else { panic!("expected one event") };
+        assert_eq!(out.kind, "http.error");
+        assert_eq!(out.get("cookie"), None);
+        assert_eq!(out.get_str("path"), Some("/api/v2"));
+        assert_eq!(out.get("body"), Some(&Value::Bytes(vec![1, 2])));
+        let tags = vec![Value::from("a"), Value::Int(1), Value::Float(2.5), Value::Bool(true)];
+        assert_eq!(out.get("tags"), Some(&Value::List(tags)));
+    }
+
+    #[test]
+    fn drops_events() {
+        let source = r#"if payload.path == "/api" { drop = true; }"#;
+        assert!(matches!(run(source, event()), Ok(Output::Drop)));
+        let mut other = event();
+        other.insert("path", "/home");
+        assert!(matches!(run(source, other), Ok(Output::One(_))));
+    }
+
+    #[test]
+    fn bad_scripts_fail_cleanly() {
+        assert!(matches!(ScriptProcessor::new("t", "let = ;"), Err(ScriptError::Compile(_))));
+
+        let err = run("kind = 5;", event()).err().unwrap();
+        assert_eq!(err.message(), "script `t`: `kind` must be a string, not i64");
+        let err = run("payload.f = || 1;", event()).err().unwrap();
+        assert_eq!(err.message(), "script `t`: `payload.f` has an unsupported type");
+        let err = run("payload = 1;", event()).err().unwrap();
+        assert_eq!(err.message(), "script `t`: `payload` must be a map");
+        let err = run("throw \"nope\";", event()).err().unwrap();
+        assert_eq!(err.message(), "script `t` failed");
+
+        let spin = ScriptProcessor::new("t", "loop {}").unwrap().max_operations(1_000);
+        assert!(spin.process(event()).is_err());
+        // With no step limit, only the size limits stop these.
+        let grow = |source: &str| {
+            let script = ScriptProcessor::new("t", source).unwrap().max_operations(0);
+            script.process(event()).err().unwrap().source().unwrap().to_string()
+        };
+        assert!(grow(r#"let s = "x"; loop { s += s; }"#).contains("Length of string"));
+        assert!(grow("let a = [1]; loop { a += a; }").contains("Size of array"));
+        assert!(grow("let m = #{}; loop { m = #{a: m, b: m}; }").contains("Size of object map"));
+    }
+
+    #[test]
+    fn print_and_debug_are_silent() {
+        let Output::One(out) = run(r#"print("hi"); debug(kind); kind = "seen";"#, event()).unwrap()
+        else {
+            panic!("expected one event")
+        };
+        assert_eq!(out.kind, "seen");
+    }
+}
@@ -0,0 +1,564 @@
+//! Sinks: the terminal stage of a [`Pipeline`](super::Pipeline).
+//!
+//! Every sink encodes events with an [`EventCodec`] and separates them with
+//! a [`Framing`], so whatever a sink writes can be read back by the matching
+//! [`source`](super::source).
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use super::codec::{EventCodec, Framing};
+use super::{Clock, SystemClock, TelemetryEvent};
+
+/// Receives the events that made it through a pipeline. Writes may be
+/// buffered until [`Sink::flush`]; after [`Sink::close`] every call fails.
+pub trait Sink: Send {
+    fn name(&self) -> &str;
+    fn write(&mut self, events: &[TelemetryEvent]) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()>;
+    /// Flushes and releases the underlying file or connection.
+    fn close(&mut self) -> io::Result<()>;
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "sink is closed")
+}
+
+/// Encodes one event into a reusable buffer, framing included.
+struct Encoder {
+    codec: Arc<dyn EventCodec>,
+    framing: Framing,
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    fn new(codec: Arc<dyn EventCodec>, framing: Framing) -> Self {
+        Self { codec, framing, buf: Vec::new() }
+    }
+
+    fn encode(&mut self, evt: &TelemetryEvent) -> io::Result<&[u8]> {
+        self.buf.clear();
+        if self.framing == Framing::LengthPrefixed {
+            self.buf.extend_from_slice(&[0; 4]);
+        }
+        self.codec
+            .encode(evt, &mut self.buf)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        match self.framing {
+            Framing::Lines => self.buf.push(b'\n'),
+            Framing::LengthPrefixed => {
+                let len = u32::try_from(self.buf.len() - 4)
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "event too large"))?;
+                self.buf[..4].copy_from_slice(&len.to_be_bytes());
+            }
+        }
+        Ok(&self.buf)
+    }
+}
+
+pub struct StdoutSink {
+    encoder: Encoder,
+    closed: bool,
+}
+
+impl StdoutSink {
+    pub fn new(codec: Arc<dyn EventCodec>, framing: Framing) -> Self {
+        Self { encoder: Encoder::new(codec, framing), closed: false }
+    }
+}
+
+impl Sink for StdoutSink {
+    fn name(&self) -> &str {
+        "stdout"
+    }
+
+    fn write(&mut self, events: &[TelemetryEvent]) -> io::Result<()> {
+        if self.closed {
+            return Err(closed());
+        }
+        let mut out = io::stdout().lock();
+        for evt in events {
+            out.write_all(self.encoder.encode(evt)?)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.closed = true;
+        io::stdout().flush()
+    }
+}
+
+/// Appends to a single file, creating it if needed.
+pub struct FileSink {
+    name: String,
+    encoder: Encoder,
+    out: Option<BufWriter<File>>,
+}
+
+impl FileSink {
+    pub fn append(
+        path: impl AsRef<Path>,
+        codec: Arc<dyn EventCodec>,
+        framing: Framing,
+    ) -> io::Result<Self> {
+        let path = path.as_ref();
+        Ok(Self {
+            name: format!("file:{}", path.display()),
+            encoder: Encoder::new(codec, framing),
+            out: Some(BufWriter::new(open_append(path)?)),
+        })
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl Sink for FileSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn write(&mut self, events: &[TelemetryEvent]) -> io::Result<()> {
+        let out = self.out.as_mut().ok_or_else(closed)?;
+        for evt in events {
+            out.write_all(self.encoder.encode(evt)?)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.as_mut().ok_or_else(closed)?.flush()
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        match self.out.take() {
+            Some(out) => out.into_inner().map_err(|e| e.into_error())?.sync_all(),
+            None => Ok(()),
+        }
+    }
+}
+
+pub const ROTATE_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Writes to `path` and moves it aside once it grows past a size or age
+/// limit. Archives are named `path.1` (newest) to `path.N` (oldest); older
+/// ones are deleted. An event is never split across files, so a file can
+/// exceed `max_bytes` by at most one event when that event alone is larger.
+/// If moving the file aside fails, writing carries on in it and rotation is
+/// not tried again for [`ROTATE_RETRY_INTERVAL`].
+pub struct RollingFileSink {
+    name: String,
+    path: PathBuf,
+    encoder: Encoder,
+    out: Option<BufWriter<File>>,
+    size: u64,
+    opened_at: SystemTime,
+    max_bytes: Option<u64>,
+    max_age: Option<Duration>,
+    keep: usize,
+    clock: Arc<dyn Clock>,
+    retry_at: Option<SystemTime>,
+    closed: bool,
+}
+
+impl RollingFileSink {
+    /// Without limits this behaves like [`FileSink`]; set them with
+    /// [`max_bytes`](Self::max_bytes) and [`max_age`](Self::max_age).
+    pub fn new(
+        path: impl Into<PathBuf>,
+        codec: Arc<dyn EventCodec>,
+        framing: Framing,
+    ) -> io::Result<Self> {
+        let path = path.into();
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        let file = open_append(&path)?;
+        Ok(Self {
+            name: format!("rolling:{}", path.display()),
+            size: file.metadata()?.len(),
+            out: Some(BufWriter::new(file)),
+            opened_at: clock.now(),
+            path,
+            encoder: Encoder::new(codec, framing),
+            max_bytes: None,
+            max_age: None,
+            keep: 5,
+            clock,
+            retry_at: None,
+            closed: false,
+        })
+    }
+
+    pub fn max_bytes(mut self, max: u64) -> Self {
+        self.max_bytes = Some(max);
+        self
+    }
+
+    pub fn max_age(mut self, max: Duration) -> Self {
+        self.max_age = Some(max);
+        self
+    }
+
+    /// Number of archives to keep; 0 discards the old file on rotation.
+    pub fn keep(mut self, keep: usize) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.opened_at = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    fn archive(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{n}"));
+        name.into()
+    }
+
+    fn due(&self, next_len: usize) -> bool {
+        if self.retry_at.is_some_and(|at| self.clock.now() < at) {
+            return false;
+        }
+        let too_big =
+            self.max_bytes.is_some_and(|max| self.size > 0 && self.size + next_len as u64 > max);
+        let too_old = self.max_age.is_some_and(|max| {
+            self.clock.now().duration_since(self.opened_at).is_ok_and(|age| age >= max)
+        });
+        too_big || too_old
+    }
+
+    /// Moves the file aside and starts a new one. If moving it fails, the
+    /// file is reopened as it is and the next attempt put off; only failing
+    /// to reopen it is an error.
+    fn rotate(&mut self) -> io::Result<()> {
+        if let Some(out) = self.out.as_mut() {
+            out.flush()?;
+        }
+        let moved = self.shift();
+        self.out = None;
+        let file = open_append(&self.path)?;
+        self.size = file.metadata()?.len();
+        self.out = Some(BufWriter::new(file));
+        let now = self.clock.now();
+        match moved {
+            Ok(()) => (self.opened_at, self.retry_at) = (now, None),
+            Err(_) => self.retry_at = Some(now + ROTATE_RETRY_INTERVAL),
+        }
+        Ok(())
+    }
+
+    fn shift(&self) -> io::Result<()> {
+        if self.keep == 0 {
+            return fs::remove_file(&self.path);
+        }
+        match fs::remove_file(self.archive(self.keep)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        for n in (1..self.keep).rev() {
+            match fs::rename(self.archive(n), self.archive(n + 1)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        fs::rename(&self.path, self.archive(1))
+    }
+}
+
+impl Sink for RollingFileSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn write(&mut self, events: &[TelemetryEvent]) -> io::Result<()> {
+        if self.closed {
+            return Err(closed());
+        }
+        for evt in events {
+            let len = self.encoder.encode(evt)?.len();
+            if self.due(len) {
+                self.rotate()?;
+            }
+            let out = self.out.as_mut().ok_or_else(closed)?;
+            out.write_all(&self.encoder.buf)?;
+            self.size += len as u64;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.out.as_mut() {
+            Some(out) if !self.closed => out.flush(),
+            _ => Err(closed()),
+        }
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.closed = true;
+        match self.out.take() {
+            Some(out) => out.into_inner().map_err(|e| e.into_error())?.sync_all(),
+            None => Ok(()),
+        }
+    }
+}
+
+type Connect = Box<dyn FnMut() -> io::Result<Box<dyn Write + Send>> + Send>;
+
+pub const SOCKET_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+pub const SOCKET_WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Forwards events over a stream socket. Each batch is encoded whole and
+/// then written and flushed, so nothing sits in a buffer between batches;
+/// a failed write reconnects and retries the batch once, so a peer may see
+/// part of a batch twice. Connecting and each write give up after
+/// [`SOCKET_CONNECT_TIMEOUT`] and [`SOCKET_WRITE_TIMEOUT`], so a stalled
+/// peer fails the batch instead of hanging the sink.
+pub struct SocketSink {
+    name: String,
+    encoder: Encoder,
+    connect: Connect,
+    out: Option<Box<dyn Write + Send>>,
+    batch: Vec<u8>,
+    closed: bool,
+}
+
+impl SocketSink {
+    pub fn tcp(
+        addr: impl ToSocketAddrs,
+        codec: Arc<dyn EventCodec>,
+        framing: Framing,
+    ) -> io::Result<Self> {
+        let addrs: Vec<_> = addr.to_socket_addrs()?.collect();
+        let name = format!("tcp:{}", addrs.first().map_or(String::new(), |a| a.to_string()));
+        Self::new(name, codec, framing, Box::new(move || Ok(Box::new(connect_tcp(&addrs)?))))
+    }
+
+    #[cfg(unix)]
+    pub fn unix(
+        path: impl Into<PathBuf>,
+        codec: Arc<dyn EventCodec>,
+        framing: Framing,
+    ) -> io::Result<Self> {
+        use std::os::unix::net::UnixStream;
+
+        let path = path.into();
+        let name = format!("unix:{}", path.display());
+        let connect = move || {
+            let stream = UnixStream::connect(&path)?;
+            stream.set_write_timeout(Some(SOCKET_WRITE_TIMEOUT))?;
+            Ok(Box::new(stream) as Box<dyn Write + Send>)
+        };
+        Self::new(name, codec, framing, Box::new(connect))
+    }
+
+    fn new(
+        name: String,
+        codec: Arc<dyn EventCodec>,
+        framing: Framing,
+        mut connect: Connect,
+    ) -> io::Result<Self> {
+        let out = Some(connect()?);
+        let encoder = Encoder::new(codec, framing);
+        Ok(Self { name, encoder, connect, out, batch: Vec::new(), closed: false })
+    }
+
+    fn send(&mut self) -> io::Result<()> {
+        let out = match self.out.as_mut() {
+            Some(out) => out,
+            None => self.out.insert((self.connect)()?),
+        };
+        out.write_all(&self.batch)?;
+        out.flush()
+    }
+}
+
+/// Tries each address in turn, like [`TcpStream::connect`] but bounded.
+fn connect_tcp(addrs: &[SocketAddr]) -> io::Result<TcpStream> {
+    let mut last = io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to");
+    for addr in addrs {
+        match TcpStream::connect_timeout(addr, SOCKET_CONNECT_TIMEOUT) {
+            Ok(stream) => {
+                stream.set_write_timeout(Some(SOCKET_WRITE_TIMEOUT))?;
+                return Ok(stream);
+            }
+            Err(e) => last = e,
+        }
+    }
+    Err(last)
+}
+
+impl Sink for SocketSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn write(&mut self, events: &[TelemetryEvent]) -> io::Result<()> {
+        if self.closed {
+            return Err(closed());
+        }
+        self.batch.clear();
+        for evt in events {
+            self.batch.extend_from_slice(self.encoder.encode(evt)?);
+        }
+        self.send().or_else(|_| {
+            self.out = None;
+            self.send()
+        })
+    }
+
+    /// Batches are flushed as they are written, so this only checks the
+    /// sink is open.
+    fn flush(&mut self) -> io::Result<()> {
+        match self.closed {
+            false => Ok(()),
+            true => Err(closed()),
+        }
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.closed = true;
+        match self.out.take() {
+            Some(mut out) => out.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+    use crate::telemetry::codec::{FramedReader, Json, MessagePack};
+    use crate::telemetry::jsonl::JsonLinesReader;
+    use crate::telemetry::{ManualClock, Pipeline};
+
+    fn temp_dir(tag: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sink-{tag}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn kinds(path: &Path) -> Vec<String> {
+        let file = io::BufReader::new(File::open(path).unwrap());
+        JsonLinesReader::new(file).map(|e| e.unwrap().kind).collect()
+    }
+
+    #[test]
+    fn pipeline_delivers_to_sinks_and_reports_failures() {
+        let dir = temp_dir("pipeline");
+        let path = dir.join("out.jsonl");
+        let mut pipeline = Pipeline::new();
+        pipeline
+            .add_sink(Box::new(FileSink::append(&path, Arc::new(Json), Framing::Lines).unwrap()));
+        pipeline.run_batch([TelemetryEvent::new("a"), TelemetryEvent::new("b")]).unwrap();
+        pipeline.close().unwrap();
+        assert_eq!(kinds(&path), ["a", "b"]);
+
+        let err = pipeline.run(TelemetryEvent::new("late")).unwrap_err();
+        assert_eq!(
+            (err.processor.as_str(), err.index, err.kind.as_str()),
+            (&*format!("file:{}", path.display()), 0, "late")
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rolling_file_rotates_by_size_and_keeps_archives() {
+        let dir = temp_dir("size");
+        let path = dir.join("events.log");
+        let mut sink = RollingFileSink::new(&path, Arc::new(Json), Framing::Lines)
+            .unwrap()
+            .max_bytes(1)
+            .keep(2);
+        for kind in ["a", "b", "c", "d"] {
+            sink.write(&[TelemetryEvent::new(kind)]).unwrap();
+        }
+        sink.close().unwrap();
+        assert_eq!(kinds(&path), ["d"]);
+        assert_eq!(kinds(&dir.join("events.log.1")), ["c"]);
+        assert_eq!(kinds(&dir.join("events.log.2")), ["b"]);
+        assert!(!dir.join("events.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_rotation_keeps_writing_to_the_active_file() {
+        let dir = temp_dir("stuck");
+        let path = dir.join("events.log");
+        let clock = Arc::new(ManualClock::default());
+        let mut sink = RollingFileSink::new(&path, Arc::new(Json), Framing::Lines)
+            .unwrap()
+            .max_bytes(1)
+            .keep(1)
+            .clock(clock.clone());
+        sink.write(&[TelemetryEvent::new("a")]).unwrap();
+        let blocker = dir.join("events.log.1");
+        fs::create_dir_all(blocker.join("busy")).unwrap();
+        sink.write(&[TelemetryEvent::new("b")]).unwrap();
+        fs::remove_dir_all(&blocker).unwrap();
+        sink.write(&[TelemetryEvent::new("c")]).unwrap();
+        sink.flush().unwrap();
+        assert_eq!(kinds(&path), ["a", "b", "c"]);
+
+        clock.advance(ROTATE_RETRY_INTERVAL);
+        sink.write(&[TelemetryEvent::new("d")]).unwrap();
+        sink.close().unwrap();
+        assert_eq!(kinds(&blocker), ["a", "b", "c"]);
+        assert_eq!(kinds(&path), ["d"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rolling_file_rotates_by_age() {
+        let dir = temp_dir("age");
+        let path = dir.join("events.log");
+        let clock = Arc::new(ManualClock::default());
+        let mut sink = RollingFileSink::new(&path, Arc::new(Json), Framing::Lines)
+            .unwrap()
+            .max_age(Duration::from_secs(60))
+            .clock(clock.clone());
+        sink.write(&[TelemetryEvent::new("a"), TelemetryEvent::new("b")]).unwrap();
+        clock.advance(Duration::from_secs(60));
+        sink.write(&[TelemetryEvent::new("c")]).unwrap();
+        sink.close().unwrap();
+        assert_eq!(kinds(&dir.join("events.log.1")), ["a", "b"]);
+        assert_eq!(kinds(&path), ["c"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tcp_sink_forwards_frames() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut bytes = Vec::new();
+            listener.accept().unwrap().0.read_to_end(&mut bytes).unwrap();
+            bytes
+        });
+        let mut sink =
+            SocketSink::tcp(addr, Arc::new(MessagePack), Framing::LengthPrefixed).unwrap();
+        sink.write(&[TelemetryEvent::new("x"), TelemetryEvent::new("y")]).unwrap();
+        sink.close().unwrap();
+        drop(sink);
+        let bytes = server.join().unwrap();
+        let got: Vec<_> =
+            FramedReader::new(&bytes[..], MessagePack).map(|e| e.unwrap().kind).collect();
+        assert_eq!(got, ["x", "y"]);
+        assert!(SocketSink::tcp(addr, Arc::new(Json), Framing::Lines).is_err());
+    }
+}
@@ -0,0 +1,840 @@
+//! Event sources: the input side of a [`Pipeline`].
+//!
+//! Byte-stream sources cut their input into records with a [`Framing`] and
+//! turn each record into an event with an [`EventCodec`]. Datagram sources
+//! treat every datagram as one record.
+
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Stdin};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs, UdpSocket};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+pub use super::codec::Framing;
+use super::codec::{CodecError, EventCodec, DEFAULT_MAX_FRAME_LEN};
+use super::{Pipeline, PipelineError, TelemetryEvent};
+
+const READ_CHUNK: usize = 64 * 1024;
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Most connections a listening source serves at once; further clients
+/// wait in the listen backlog until one closes.
+pub const MAX_CONNECTIONS: usize = 256;
+
+/// Most decoded events a listening source holds for the pipeline;
+/// connection threads stop reading while it is full.
+pub const INBOX_CAPACITY: usize = 1024;
+
+/// Produces events for a [`Pipeline`].
+pub trait Source: Send {
+    fn name(&self) -> &str;
+
+    /// Blocks until input arrives or the source's poll interval passes, and
+    /// appends whatever it decoded to `out`. Records that fail to decode go
+    /// to `on_error` and are skipped. Returns `Ok(false)` once the source is
+    /// exhausted; events appended by that last call are still valid.
+    fn read(
+        &mut self,
+        out: &mut Vec<TelemetryEvent>,
+        on_error: &mut dyn FnMut(CodecError),
+    ) -> io::Result<bool>;
+}
+
+struct Framer {
+    framing: Framing,
+    buf: Vec<u8>,
+    /// How much of `buf` is known to hold no newline.
+    scanned: usize,
+}
+
+impl Framer {
+    fn new(framing: Framing) -> Self {
+        Self { framing, buf: Vec::new(), scanned: 0 }
+    }
+
+    /// Decodes every complete record buffered so far. An oversized frame or
+    /// line means the stream has lost sync, so it is returned rather than
+    /// skipped.
+    fn push(
+        &mut self,
+        data: &[u8],
+        codec: &dyn EventCodec,
+        out: &mut Vec<TelemetryEvent>,
+        on_error: &mut dyn FnMut(CodecError),
+    ) -> Result<(), CodecError> {
+        self.buf.extend_from_slice(data);
+        let (mut start, mut skip) = (0, std::mem::take(&mut self.scanned));
+        let res = loop {
+            let rest = &self.buf[start..];
+            let (record, used) = match self.framing {
+                Framing::Lines => match rest[skip..].iter().position(|&b| b == b'\n') {
+                    Some(end) if skip + end <= DEFAULT_MAX_FRAME_LEN => {
+                        (&rest[..skip + end], skip + end + 1)
+                    }
+                    Some(len) => break Err(too_large(skip + len)),
+                    None if rest.len() > DEFAULT_MAX_FRAME_LEN => break Err(too_large(rest.len())),
+                    None => {
+                        self.scanned = rest.len();
+                        break Ok(());
+                    }
+                },
+                Framing::LengthPrefixed => {
+                    let Some(len) = rest.first_chunk::<4>() else { break Ok(()) };
+                    let len = u32::from_be_bytes(*len) as usize;
+                    if len > DEFAULT_MAX_FRAME_LEN {
+                        break Err(too_large(len));
+                    }
+                    match rest.get(4..4 + len) {
+                        Some(record) => (record, 4 + len),
+                        None => break Ok(()),
+                    }
+                }
+            };
+            decode_record(record, self.framing, codec, out, on_error);
+            (start, skip) = (start + used, 0);
+        };
+        self.buf.drain(..start);
+        res
+    }
+
+    /// Handles whatever is left at end of stream: a final unterminated line
+    /// is decoded, a partial frame is reported.
+    fn finish(
+        &mut self,
+        codec: &dyn EventCodec,
+        out: &mut Vec<TelemetryEvent>,
+        on_error: &mut dyn FnMut(CodecError),
+    ) {
+        if self.buf.is_empty() {
+            return;
+        }
+        match self.framing {
+            Framing::Lines => decode_record(&self.buf, self.framing, codec, out, on_error),
+            Framing::LengthPrefixed => {
+                on_error(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+        }
+        self.buf.clear();
+        self.scanned = 0;
+    }
+}
+
+fn too_large(len: usize) -> CodecError {
+    CodecError::FrameTooLarge { len, max: DEFAULT_MAX_FRAME_LEN }
+}
+
+fn decode_record(
+    record: &[u8],
+    framing: Framing,
+    codec: &dyn EventCodec,
+    out: &mut Vec<TelemetryEvent>,
+    on_error: &mut dyn FnMut(CodecError),
+) {
+    if framing == Framing::Lines && record.trim_ascii().is_empty() {
+        return;
+    }
+    match codec.decode(record) {
+        Ok(evt) => out.push(evt),
+        Err(e) => on_error(e),
+    }
+}
+
+fn lost_sync(e: CodecError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Reads a finite byte stream to its end.
+pub struct ReaderSource<R> {
+    name: String,
+    input: R,
+    codec: Arc<dyn EventCodec>,
+    framer: Framer,
+    chunk: Box<[u8]>,
+    done: bool,
+}
+
+impl<R: Read + Send> ReaderSource<R> {
+    pub fn new(
+        name: impl Into<String>,
+        input: R,
+        codec: Arc<dyn EventCodec>,
+        framing: Framing,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            input,
+            codec,
+            framer: Framer::new(framing),
+            chunk: vec![0; READ_CHUNK].into_boxed_slice(),
+            done: false,
+        }
+    }
+}
+
+impl ReaderSource<Stdin> {
+    pub fn stdin(codec: Arc<dyn EventCodec>, framing: Framing) -> Self {
+        Self::new("stdin", io::stdin(), codec, framing)
+    }
+}
+
+impl<R: Read + Send> Source for ReaderSource<R> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn read(
+        &mut self,
+        out: &mut Vec<TelemetryEvent>,
+        on_error: &mut dyn FnMut(CodecError),
+    ) -> io::Result<bool> {
+        if self.done {
+            return Ok(false);
+        }
+        loop {
+            match self.input.read(&mut self.chunk) {
+                Ok(0) => {
+                    self.done = true;
+                    self.framer.finish(&*self.codec, out, on_error);
+                    return Ok(false);
+                }
+                Ok(n) => {
+                    self.framer
+                        .push(&self.chunk[..n], &*self.codec, out, on_error)
+                        .map_err(lost_sync)?;
+                    return Ok(true);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Follows a file like `tail -F`: new data is picked up as it is appended,
+/// and when the path is rotated (replaced by a new file) or truncated the
+/// old file is read to its end before reading the new one from the start.
+pub struct FileTailSource {
+    name: String,
+    path: PathBuf,
+    codec: Arc<dyn EventCodec>,
+    framer: Framer,
+    chunk: Box<[u8]>,
+    file: Option<File>,
+    identity: Option<(u64, u64)>,
+    pos: u64,
+    from_start: bool,
+    poll_interval: Duration,
+}
+
+impl FileTailSource {
+    /// Starts at the current end of the file, or at the start of a file
+    /// that does not exist yet.
+    pub fn new(path: impl Into<PathBuf>, codec: Arc<dyn EventCodec>, framing: Framing) -> Self {
+        let path = path.into();
+        Self {
+            name: format!("file:{}", path.display()),
+            path,
+            codec,
+            framer: Framer::new(framing),
+            chunk: vec![0; READ_CHUNK].into_boxed_slice(),
+            file: None,
+            identity: None,
+            pos: 0,
+            from_start: false,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+        }
+    }
+
+    /// Reads what is already in the file before following it.
+    pub fn from_start(mut self) -> Self {
+        self.from_start = true;
+        self
+    }
+
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    fn open(&mut self, at_end: bool) -> io::Result<()> {
+        let mut file = File::open(&self.path)?;
+        self.identity = identity(&file.metadata()?);
+        self.pos = if at_end { file.seek(SeekFrom::End(0))? } else { 0 };
+        self.file = Some(file);
+        Ok(())
+    }
+
+    /// True when the path now names a different or shorter file than the
+    /// one being read.
+    fn rotated(&self) -> io::Result<bool> {
+        match fs::metadata(&self.path) {
+            Ok(meta) => Ok(identity(&meta) != self.identity || meta.len() < self.pos),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn identity(meta: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn identity(_meta: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+impl Source for FileTailSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn read(
+        &mut self,
+        out: &mut Vec<TelemetryEvent>,
+        on_error: &mut dyn FnMut(CodecError),
+    ) -> io::Result<bool> {
+        let Some(file) = self.file.as_mut() else {
+            match self.open(!self.from_start) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    self.from_start = true;
+                    thread::sleep(self.poll_interval);
+                }
+                res => res?,
+            }
+            return Ok(true);
+        };
+        match file.read(&mut self.chunk) {
+            Ok(0) => {}
+            Ok(n) => {
+                self.pos += n as u64;
+                self.framer
+                    .push(&self.chunk[..n], &*self.codec, out, on_error)
+                    .map_err(lost_sync)?;
+                return Ok(true);
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return Ok(true),
+            Err(e) => return Err(e),
+        }
+        if self.rotated()? {
+            self.framer.finish(&*self.codec, out, on_error);
+            self.file = None;
+            self.from_start = true;
+        } else {
+            thread::sleep(self.poll_interval);
+        }
+        Ok(true)
+    }
+}
+
+/// One datagram, one event.
+pub struct UdpSource {
+    name: String,
+    socket: UdpSocket,
+    codec: Arc<dyn EventCodec>,
+    buf: Box<[u8]>,
+}
+
+impl UdpSource {
+    pub fn bind(addr: impl ToSocketAddrs, codec: Arc<dyn EventCodec>) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_read_timeout(Some(DEFAULT_POLL_INTERVAL))?;
+        Ok(Self {
+            name: format!("udp:{}", socket.local_addr()?),
+            socket,
+            codec,
+            buf: vec![0; 64 * 1024].into_boxed_slice(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+impl Source for UdpSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn read(
+        &mut self,
+        out: &mut Vec<TelemetryEvent>,
+        on_error: &mut dyn FnMut(CodecError),
+    ) -> io::Result<bool> {
+        match self.socket.recv(&mut self.buf) {
+            Ok(n) => match self.codec.decode(&self.buf[..n]) {
+                Ok(evt) => out.push(evt),
+                Err(e) => on_error(e),
+            },
+            Err(e) if is_timeout(&e) => {}
+            Err(e) => return Err(e),
+        }
+        Ok(true)
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted
+    )
+}
+
+/// Collects what connection threads decoded. Dropping it tells the accept
+/// loop and every connection thread to wind down.
+struct Inbox {
+    rx: Receiver<Result<TelemetryEvent, CodecError>>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl Inbox {
+    fn read(
+        &mut self,
+        out: &mut Vec<TelemetryEvent>,
+        on_error: &mut dyn FnMut(CodecError),
+    ) -> io::Result<bool> {
+        let mut deliver = |msg| match msg {
+            Ok(evt) => out.push(evt),
+            Err(e) => on_error(e),
+        };
+        match self.rx.recv_timeout(DEFAULT_POLL_INTERVAL) {
+            Ok(msg) => deliver(msg),
+            Err(RecvTimeoutError::Timeout) => return Ok(true),
+            Err(RecvTimeoutError::Disconnected) => return Ok(false),
+        }
+        self.rx.try_iter().for_each(deliver);
+        Ok(true)
+    }
+}
+
+impl Drop for Inbox {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+    }
+}
+
+type Accept = Box<dyn FnMut() -> io::Result<Option<Box<dyn Read + Send>>> + Send>;
+
+/// Runs `accept` until shutdown, giving every connection its own thread
+/// that frames and decodes the stream into the inbox, up to
+/// [`MAX_CONNECTIONS`] at a time. Accept errors go to the inbox too. The
+/// inbox holds at most [`INBOX_CAPACITY`] events, so a pipeline that falls
+/// behind stops the connections being read and pushes back on the senders.
+fn spawn_listener(mut accept: Accept, codec: Arc<dyn EventCodec>, framing: Framing) -> Inbox {
+    let (tx, rx) = mpsc::sync_channel(INBOX_CAPACITY);
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let stop = shutdown.clone();
+    let open = Arc::new(AtomicUsize::new(0));
+    thread::spawn(move || {
+        while !stop.load(Ordering::Relaxed) {
+            if open.load(Ordering::Relaxed) >= MAX_CONNECTIONS {
+                thread::sleep(DEFAULT_POLL_INTERVAL / 4);
+                continue;
+            }
+            match accept() {
+                Ok(Some(stream)) => {
+                    open.fetch_add(1, Ordering::Relaxed);
+                    let (codec, tx, stop, open) =
+                        (codec.clone(), tx.clone(), stop.clone(), open.clone());
+                    thread::spawn(move || {
+                        serve(stream, &*codec, framing, &tx, &stop);
+                        open.fetch_sub(1, Ordering::Relaxed);
+                    });
+                }
+                Ok(None) => thread::sleep(DEFAULT_POLL_INTERVAL / 4),
+                Err(e) => {
+                    // Usually out of file descriptors; back off so it can recover.
+                    drop(tx.send(Err(e.into())));
+                    thread::sleep(DEFAULT_POLL_INTERVAL);
+                }
+            }
+        }
+    });
+    Inbox { rx, shutdown }
+}
+
+fn serve(
+    mut stream: Box<dyn Read + Send>,
+    codec: &dyn EventCodec,
+    framing: Framing,
+    tx: &SyncSender<Result<TelemetryEvent, CodecError>>,
+    stop: &AtomicBool,
+) {
+    let mut framer = Framer::new(framing);
+    let mut chunk = vec![0; READ_CHUNK];
+    let mut events = Vec::new();
+    let mut on_error = |e| drop(tx.send(Err(e)));
+    while !stop.load(Ordering::Relaxed) {
+        let res = match stream.read(&mut chunk) {
+            Ok(0) => {
+                framer.finish(codec, &mut events, &mut on_error);
+                events.drain(..).for_each(|evt| drop(tx.send(Ok(evt))));
+                return;
+            }
+            Ok(n) => framer.push(&chunk[..n], codec, &mut events, &mut on_error),
+            Err(e) if is_timeout(&e) => continue,
+            Err(_) => return,
+        };
+        events.drain(..).for_each(|evt| drop(tx.send(Ok(evt))));
+        if let Err(e) = res {
+            on_error(e);
+            return;
+        }
+    }
+}
+
+/// Accepts any number of TCP connections, each a framed stream of events.
+pub struct TcpSource {
+    name: String,
+    addr: SocketAddr,
+    inbox: Inbox,
+}
+
+impl TcpSource {
+    pub fn bind(
+        addr: impl ToSocketAddrs,
+        codec: Arc<dyn EventCodec>,
+        framing: Framing,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let accept: Accept = Box::new(move || match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false)?;
+                stream.set_read_timeout(Some(DEFAULT_POLL_INTERVAL))?;
+                Ok(Some(Box::new(stream)))
+            }
+            Err(e) if is_timeout(&e) => Ok(None),
+            Err(e) => Err(e),
+        });
+        Ok(Self {
+            name: format!("tcp:{addr}"),
+            addr,
+            inbox: spawn_listener(accept, codec, framing),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Source for TcpSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn read(
+        &mut self,
+        out: &mut Vec<TelemetryEvent>,
+        on_error: &mut dyn FnMut(CodecError),
+    ) -> io::Result<bool> {
+        self.inbox.read(out, on_error)
+    }
+}
+
+/// Accepts connections on a Unix domain socket, each a framed stream of
+/// events. The socket file is removed when the source is dropped.
+#[cfg(unix)]
+pub struct UnixSource {
+    name: String,
+    path: PathBuf,
+    inbox: Inbox,
+}
+
+#[cfg(unix)]
+impl UnixSource {
+    pub fn bind(
+        path: impl Into<PathBuf>,
+        codec: Arc<dyn EventCodec>,
+        framing: Framing,
+    ) -> io::Result<Self> {
+        use std::os::unix::net::UnixListener;
+
+        let path = path.into();
+        let listener = UnixListener::bind(&path)?;
+        listener.set_nonblocking(true)?;
+        let accept: Accept = Box::new(move || match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false)?;
+                stream.set_read_timeout(Some(DEFAULT_POLL_INTERVAL))?;
+                Ok(Some(Box::new(stream)))
+            }
+            Err(e) if is_timeout(&e) => Ok(None),
+            Err(e) => Err(e),
+        });
+        Ok(Self {
+            name: format!("unix:{}", path.display()),
+            path,
+            inbox: spawn_listener(accept, codec, framing),
+        })
+    }
+}
+
+#[cfg(unix)]
+impl Source for UnixSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn read(
+        &mut self,
+        out: &mut Vec<TelemetryEvent>,
+        on_error: &mut dyn FnMut(CodecError),
+    ) -> io::Result<bool> {
+        self.inbox.read(out, on_error)
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixSource {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[derive(Debug)]
+pub enum SourceError {
+    Io { source: String, error: io::Error },
+    Pipeline(Box<PipelineError>),
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceError::Io { source, error } => write!(f, "source `{source}` failed: {error}"),
+            SourceError::Pipeline(e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for SourceError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SourceError::Io { error, .. } => Some(error),
+            SourceError::Pipeline(e) => Some(&**e),
+        }
+    }
+}
+
+impl Pipeline {
+    /// Feeds `source` through the pipeline until it is exhausted or `stop`
+    /// is set, handing each read's surviving events to `emit`. Records the
+    /// source could not decode go to `on_decode_error` and are skipped.
+    pub fn run_source(
+        &self,
+        source: &mut dyn Source,
+        stop: &AtomicBool,
+        mut emit: impl FnMut(Vec<TelemetryEvent>),
+        mut on_decode_error: impl FnMut(&str, CodecError),
+    ) -> Result<(), SourceError> {
+        let mut batch = Vec::new();
+        let mut live = true;
+        while live && !stop.load(Ordering::Relaxed) {
+            let name = source.name().to_owned();
+            live = source
+                .read(&mut batch, &mut |e| on_decode_error(&name, e))
+                .map_err(|error| SourceError::Io { source: name, error })?;
+            if !batch.is_empty() {
+                let out = self
+                    .run_batch(batch.drain(..))
+                    .map_err(|e| SourceError::Pipeline(Box::new(e)))?;
+                emit(out);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::TcpStream;
+
+    use super::*;
+    use crate::telemetry::codec::{FramedWriter, Json, MessagePack};
+
+    fn json() -> Arc<dyn EventCodec> {
+        Arc::new(Json)
+    }
+
+    fn drain(source: &mut dyn Source, want: usize) -> (Vec<TelemetryEvent>, usize) {
+        let (mut events, mut errors) = (Vec::new(), 0);
+        for _ in 0..100 {
+            if events.len() >= want || !source.read(&mut events, &mut |_| errors += 1).unwrap() {
+                break;
+            }
+        }
+        (events, errors)
+    }
+
+    #[test]
+    fn reader_source_skips_bad_lines_and_reads_unterminated_tail() {
+        let input = "{\"kind\":\"a\"}\nnope\n\n{\"kind\":\"b\"}";
+        let mut source = ReaderSource::new("mem", input.as_bytes(), json(), Framing::Lines);
+        let (events, errors) = drain(&mut source, usize::MAX);
+        assert_eq!(events.iter().map(|e| e.kind.as_str()).collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(errors, 1);
+    }
+
+    #[test]
+    fn length_prefixed_frames_split_across_reads() {
+        let mut writer = FramedWriter::new(Vec::new(), MessagePack);
+        writer.write(&TelemetryEvent::new("x")).unwrap();
+        writer.write(&TelemetryEvent::new("y")).unwrap();
+        let bytes = writer.into_inner();
+        let mut framer = Framer::new(Framing::LengthPrefixed);
+        let mut out = Vec::new();
+        for byte in &bytes {
+            framer
+                .push(std::slice::from_ref(byte), &MessagePack, &mut out, &mut |e| panic!("{e}"))
+                .unwrap();
+        }
+        assert_eq!(out.len(), 2);
+    }
+
+    #[test]
+    fn overlong_lines_lose_sync() {
+        let mut framer = Framer::new(Framing::Lines);
+        let long = vec![b'x'; DEFAULT_MAX_FRAME_LEN + 1];
+        let res = framer.push(&long, &Json, &mut Vec::new(), &mut |e| panic!("{e}"));
+        assert!(matches!(res, Err(CodecError::FrameTooLarge { .. })));
+
+        let mut framer = Framer::new(Framing::Lines);
+        let mut out = Vec::new();
+        framer.push(b"{\"kind\":\"a\"}\n{\"ki", &Json, &mut out, &mut |e| panic!("{e}")).unwrap();
+        assert_eq!(out.len(), 1);
+    }
+
+    #[test]
+    fn lines_split_across_reads() {
+        let mut framer = Framer::new(Framing::Lines);
+        let mut out = Vec::new();
+        for byte in b"{\"kind\":\"a\"}\n{\"kind\":\"b\"}\n" {
+            framer
+                .push(std::slice::from_ref(byte), &Json, &mut out, &mut |e| panic!("{e}"))
+                .unwrap();
+        }
+        assert_eq!(out.iter().map(|e| e.kind.as_str()).collect::<Vec<_>>(), ["a", "b"]);
+    }
+
+    /// An endless stream of events that counts how often it is read.
+    struct Flood(Arc<AtomicUsize>);
+
+    impl Read for Flood {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            let line = b"{\"kind\":\"x\"}\n";
+            let n = buf.len() / line.len() * line.len();
+            buf[..n].chunks_mut(line.len()).for_each(|c| c.copy_from_slice(line));
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn a_full_inbox_stops_reading_connections() {
+        let reads = Arc::new(AtomicUsize::new(0));
+        let mut flood = Some(Flood(reads.clone()));
+        let accept: Accept =
+            Box::new(move || Ok(flood.take().map(|f| Box::new(f) as Box<dyn Read + Send>)));
+        let mut inbox = spawn_listener(accept, json(), Framing::Lines);
+        let mut events = Vec::new();
+        inbox.read(&mut events, &mut |e| panic!("{e}")).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        let before = reads.load(Ordering::Relaxed);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(reads.load(Ordering::Relaxed), before);
+        assert!(!events.is_empty());
+    }
+
+    #[test]
+    fn file_tail_follows_rotation() {
+        let dir = std::env::temp_dir().join(format!("tail-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("events.log");
+        fs::write(&path, "{\"kind\":\"old\"}\n").unwrap();
+        let mut source = FileTailSource::new(&path, json(), Framing::Lines)
+            .poll_interval(Duration::from_millis(5));
+        let (events, _) = drain(&mut source, 1);
+        assert!(events.is_empty(), "existing content is skipped");
+
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"kind\":\"one\"}\n{\"kind\":\"tw").unwrap();
+        fs::rename(&path, dir.join("events.log.1")).unwrap();
+        file.write_all(b"o\"}\n").unwrap();
+        fs::write(&path, "{\"kind\":\"three\"}\n").unwrap();
+
+        let (events, errors) = drain(&mut source, 3);
+        assert_eq!(errors, 0);
+        assert_eq!(
+            events.iter().map(|e| e.kind.as_str()).collect::<Vec<_>>(),
+            ["one", "two", "three"]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn udp_datagrams_are_events() {
+        let mut source = UdpSource::bind("127.0.0.1:0", json()).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"{\"kind\":\"ping\"}", source.local_addr().unwrap()).unwrap();
+        client.send_to(b"garbage", source.local_addr().unwrap()).unwrap();
+        let (mut events, mut errors) = (Vec::new(), 0);
+        while events.len() + errors < 2 {
+            source.read(&mut events, &mut |_| errors += 1).unwrap();
+        }
+        assert_eq!(events[0].kind, "ping");
+    }
+
+    #[test]
+    fn tcp_source_feeds_pipeline() {
+        let mut source = TcpSource::bind("127.0.0.1:0", json(), Framing::Lines).unwrap();
+        for kind in ["a", "b"] {
+            let mut conn = TcpStream::connect(source.local_addr()).unwrap();
+            writeln!(conn, "{{\"kind\":\"{kind}\"}}").unwrap();
+        }
+        let stop = AtomicBool::new(false);
+        let mut seen = Vec::new();
+        Pipeline::new()
+            .run_source(
+                &mut source,
+                &stop,
+                |out| {
+                    seen.extend(out.into_iter().map(|e| e.kind));
+                    stop.store(seen.len() == 2, Ordering::Relaxed);
+                },
+                |_, e| panic!("{e}"),
+            )
+            .unwrap();
+        seen.sort();
+        assert_eq!(seen, ["a", "b"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_source_accepts_frames() {
+        use std::os::unix::net::UnixStream;
+
+        let path = std::env::temp_dir().join(format!("src-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut source =
+            UnixSource::bind(path.clone(), Arc::new(MessagePack), Framing::LengthPrefixed).unwrap();
+        let mut writer = FramedWriter::new(UnixStream::connect(&path).unwrap(), MessagePack);
+        writer.write(&TelemetryEvent::new("local")).unwrap();
+        drop(writer);
+        let (events, errors) = drain(&mut source, 1);
+        assert_eq!((events[0].kind.as_str(), errors), ("local", 0));
+        drop(source);
+        assert!(!path.exists());
+    }
+}
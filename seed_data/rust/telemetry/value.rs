@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::de::{self, MapAccess, SeqAccess, Unexpected, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// A typed payload value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Map(HashMap<String, Value>),
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Int(_) => "int",
+            Value::Float(_) => "float",
+            Value::Bool(_) => "bool",
+            Value::Bytes(_) => "bytes",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Value::Int(i) => Some(i),
+            _ => None,
+        }
+    }
+
+    /// Floats as-is, integers widened.
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Value::Float(f) => Some(f),
+            Value::Int(i) => Some(i as f64),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Value::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    /// Raw bytes, or the UTF-8 encoding of a string.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(b) => Some(b),
+            Value::String(s) => Some(s.as_bytes()),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[Value]> {
+        match self {
+            Value::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn as_map(&self) -> Option<&HashMap<String, Value>> {
+        match self {
+            Value::Map(m) => Some(m),
+            _ => None,
+        }
+    }
+
+    pub fn as_map_mut(&mut self) -> Option<&mut HashMap<String, Value>> {
+        match self {
+            Value::Map(m) => Some(m),
+            _ => None,
+        }
+    }
+}
+
+/// Strings print bare; bytes print as lowercase hex.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::String(s) => f.write_str(s),
+            Value::Int(i) => write!(f, "{i}"),
+            Value::Float(x) => write!(f, "{x}"),
+            Value::Bool(b) => write!(f, "{b}"),
+            Value::Bytes(b) => b.iter().try_for_each(|byte| write!(f, "{byte:02x}")),
+            Value::List(l) => {
+                f.write_str("[")?;
+                for (i, v) in l.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{v}")?;
+                }
+                f.write_str("]")
+            }
+            Value::Map(m) => {
+                let mut keys: Vec<_> = m.keys().collect();
+                keys.sort();
+                f.write_str("{")?;
+                for (i, k) in keys.into_iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{k}: {}", m[k])?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.to_owned())
+    }
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Self {
+        Value::Int(i)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(i: i32) -> Self {
+        Value::Int(i.into())
+    }
+}
+
+impl From<f64> for Value {
+    fn from(f: f64) -> Self {
+        Value::Float(f)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(b: Vec<u8>) -> Self {
+        Value::Bytes(b)
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(l: Vec<Value>) -> Self {
+        Value::List(l)
+    }
+}
+
+impl From<HashMap<String, Value>> for Value {
+    fn from(m: HashMap<String, Value>) -> Self {
+        Value::Map(m)
+    }
+}
+
+/// Maps onto the serde data model variant by variant. Bytes go out through
+/// `serialize_bytes`, which binary formats keep as a byte string; JSON has no
+/// such type, so they come back from JSON as a list of integers.
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Value::String(v) => s.serialize_str(v),
+            Value::Int(v) => s.serialize_i64(*v),
+            Value::Float(v) => s.serialize_f64(*v),
+            Value::Bool(v) => s.serialize_bool(*v),
+            Value::Bytes(v) => s.serialize_bytes(v),
+            Value::List(v) => v.serialize(s),
+            Value::Map(v) => v.serialize(s),
+        }
+    }
+}
+
+/// A `null` has no value of its own: inside a map or list it is read as
+/// absent and left out, and on its own it is an error.
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        d.deserialize_any(ValueVisitor)
+    }
+}
+
+struct ValueVisitor;
+
+/// Most entries preallocated from a length prefix. Binary formats take the
+/// prefix from the input, so trusting it lets five bytes ask for gigabytes.
+const MAX_PREALLOC: usize = 4096;
+
+fn cautious(hint: Option<usize>) -> usize {
+    hint.unwrap_or(0).min(MAX_PREALLOC)
+}
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a string, number, bool, byte string, list or map")
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<Value, E> {
+        Ok(Value::Bool(v))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Value, E> {
+        Ok(Value::Int(v))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Value, E> {
+        i64::try_from(v)
+            .map(Value::Int)
+            .map_err(|_| E::invalid_value(Unexpected::Unsigned(v), &self))
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Value, E> {
+        Ok(Value::Float(v))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Value, E> {
+        Ok(Value::String(v.to_owned()))
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<Value, E> {
+        Ok(Value::String(v))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Value, E> {
+        Ok(Value::Bytes(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Value, E> {
+        Ok(Value::Bytes(v))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut list = Vec::with_capacity(cautious(seq.size_hint()));
+        while let Some(v) = seq.next_element::<Option<Value>>()? {
+            list.extend(v);
+        }
+        Ok(Value::List(list))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        let mut out = HashMap::with_capacity(cautious(map.size_hint()));
+        while let Some((k, v)) = map.next_entry::<String, Option<Value>>()? {
+            if let Some(v) = v {
+                out.insert(k, v);
+            }
+        }
+        Ok(Value::Map(out))
+    }
+}
+
+/// Reads an event payload the way [`Value`] reads a map: `null` entries, or
+/// a `null` payload, are left out.
+pub(crate) fn deserialize_payload<'de, D: Deserializer<'de>>(
+    d: D,
+) -> Result<HashMap<String, Value>, D::Error> {
+    let payload = Option::<HashMap<String, Option<Value>>>::deserialize(d)?;
+    Ok(payload.into_iter().flatten().filter_map(|(k, v)| Some((k, v?))).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::telemetry::TelemetryEvent;
+
+    fn sample() -> Value {
+        let nested = HashMap::from([("x".to_owned(), Value::Float(-0.5))]);
+        Value::Map(HashMap::from([
+            ("s".to_owned(), "text".into()),
+            ("i".to_owned(), (-7).into()),
+            ("f".to_owned(), 1.25.into()),
+            ("b".to_owned(), true.into()),
+            ("bytes".to_owned(), vec![0u8, 255].into()),
+            ("list".to_owned(), vec![Value::Int(1), "two".into()].into()),
+            ("map".to_owned(), nested.into()),
+        ]))
+    }
+
+    #[test]
+    fn conversions_and_accessors() {
+        assert_eq!(Value::from("a"), Value::String("a".into()));
+        assert_eq!(Value::from(3i32), Value::Int(3));
+        assert_eq!(Value::from(vec![1u8]), Value::Bytes(vec![1]));
+
+        assert_eq!(Value::from("a").as_str(), Some("a"));
+        assert_eq!(Value::from(3).as_i64(), Some(3));
+        assert_eq!(Value::from(3).as_f64(), Some(3.0));
+        assert_eq!(Value::from(2.5).as_i64(), None);
+        assert_eq!(Value::from(false).as_bool(), Some(false));
+        assert_eq!(Value::from("ab").as_bytes(), Some(&b"ab"[..]));
+        assert_eq!(Value::from(1).as_str(), None);
+        assert_eq!(Value::from(vec![Value::Int(1)]).as_list(), Some(&[Value::Int(1)][..]));
+
+        let mut map = sample();
+        assert_eq!(map.type_name(), "map");
+        map.as_map_mut().unwrap().insert("new".into(), 1.into());
+        assert_eq!(map.as_map().unwrap()["new"], Value::Int(1));
+        assert_eq!(Value::from("x").as_map(), None);
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(Value::from("bare").to_string(), "bare");
+        assert_eq!(Value::from(vec![0x0au8, 0xff]).to_string(), "0aff");
+        assert_eq!(
+            sample().to_string(),
+            "{b: true, bytes: 00ff, f: 1.25, i: -7, list: [1, two], map: {x: -0.5}, s: text}"
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        let value = sample();
+        let msgpack = rmp_serde::to_vec(&value).unwrap();
+        assert_eq!(rmp_serde::from_slice::<Value>(&msgpack).unwrap(), value);
+        let mut cbor = Vec::new();
+        ciborium::into_writer(&value, &mut cbor).unwrap();
+        assert_eq!(ciborium::from_reader::<Value, _>(&cbor[..]).unwrap(), value);
+
+        // JSON has no byte strings, so bytes come back as a list of integers.
+        let json: Value = serde_json::from_str(&serde_json::to_string(&value).unwrap()).unwrap();
+        assert_eq!(json.as_map().unwrap()["bytes"], vec![Value::Int(0), Value::Int(255)].into());
+        assert_eq!(json.as_map().unwrap()["map"], value.as_map().unwrap()["map"]);
+
+        assert!(serde_json::from_str::<Value>("18446744073709551615").is_err());
+    }
+
+    #[test]
+    fn null_is_absent() {
+        let v: Value = serde_json::from_str(r#"{"a": null, "b": [1, null, 2]}"#).unwrap();
+        let expected = HashMap::from([("b".to_owned(), vec![Value::Int(1), Value::Int(2)].into())]);
+        assert_eq!(v, Value::Map(expected));
+        assert!(serde_json::from_str::<Value>("null").is_err());
+
+        let evt: TelemetryEvent =
+            serde_json::from_str(r#"{"kind": "k", "payload": {"a": null, "b": 1}}"#).unwrap();
+        assert_eq!(evt.get("a"), None);
+        assert_eq!(evt.get("b"), Some(&Value::Int(1)));
+        let evt: TelemetryEvent =
+            serde_json::from_str(r#"{"kind": "k", "payload": null}"#).unwrap();
+        assert!(evt.payload.is_empty());
+
+        let nil = rmp_serde::to_vec(&HashMap::from([("a", None::<i64>)])).unwrap();
+        assert_eq!(rmp_serde::from_slice::<Value>(&nil).unwrap(), Value::Map(HashMap::new()));
+    }
+
+    #[test]
+    fn huge_length_prefixes_do_not_preallocate() {
+        // A MessagePack array32 and map32 header claiming 2^32 - 1 entries.
+        for input in [[0xdd, 0xff, 0xff, 0xff, 0xff], [0xdf, 0xff, 0xff, 0xff, 0xff]] {
+            assert!(rmp_serde::from_slice::<Value>(&input).is_err());
+        }
+        let mut cbor = vec![0x9b];
+        cbor.extend(u64::MAX.to_be_bytes());
+        assert!(ciborium::from_reader::<Value, _>(&cbor[..]).is_err());
+    }
+}
@@ -0,0 +1,382 @@
+//! Processors implemented as sandboxed WebAssembly modules.
+//!
+//! Each event gets a fresh instance of the module, bounded by a fuel budget
+//! and a memory cap, so a module can neither keep state between events nor
+//! take the host down: traps, exhausted fuel and refused allocations all
+//! surface as a [`ProcessError`].
+//!
+//! # Guest interface
+//!
+//! The module exports `memory` and `process: () -> i32`, returning 0 on
+//! success. It may import from the `telemetry` namespace, where pointers and
+//! lengths refer to its own memory and field values are JSON text:
+//!
+//! | import | signature | |
+//! |---|---|---|
+//! | `get_kind` | `(buf, cap) -> len` | copies the kind if it fits in `cap`; always returns its length |
+//! | `set_kind` | `(ptr, len)` | |
+//! | `get_field` | `(key, key_len, buf, cap) -> len` | like `get_kind`; -1 if absent |
+//! | `set_field` | `(key, key_len, val, val_len) -> i32` | 0, or -1 if the value is not JSON |
+//! | `remove_field` | `(key, key_len) -> i32` | 1 if it was present |
+//! | `drop_event` | `()` | discard the event once `process` returns |
+//! | `fail` | `(msg, len)` | make `process` fail with this message |
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use wasmtime::{
+    Caller, Config, Engine, Extern, InstancePre, Linker, Memory, Module, Store, StoreLimits,
+    StoreLimitsBuilder, Trap,
+};
+
+use super::{Output, ProcessError, Processor, TelemetryEvent, Value};
+
+pub const DEFAULT_FUEL: u64 = 10_000_000;
+pub const DEFAULT_MAX_MEMORY: usize = 16 << 20;
+/// Most elements any one table may grow to.
+pub const MAX_TABLE_ELEMENTS: usize = 10_000;
+
+const IMPORT_MODULE: &str = "telemetry";
+
+#[derive(Debug)]
+pub enum WasmError {
+    Io(io::Error),
+    /// The module did not compile, or does not fit the guest interface.
+    Invalid(String),
+}
+
+impl fmt::Display for WasmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WasmError::Io(e) => write!(f, "i/o error: {e}"),
+            WasmError::Invalid(msg) => write!(f, "invalid module: {msg}"),
+        }
+    }
+}
+
+impl Error for WasmError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WasmError::Io(e) => Some(e),
+            WasmError::Invalid(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for WasmError {
+    fn from(e: io::Error) -> Self {
+        WasmError::Io(e)
+    }
+}
+
+/// What one instance sees and leaves behind.
+struct Guest {
+    event: TelemetryEvent,
+    dropped: bool,
+    failure: Option<String>,
+    limits: StoreLimits,
+}
+
+pub struct WasmProcessor {
+    name: String,
+    engine: Engine,
+    pre: InstancePre<Guest>,
+    fuel: u64,
+    max_memory: usize,
+}
+
+impl WasmProcessor {
+    /// Compiles a module from its binary (or, for tests, text) form.
+    pub fn new(name: impl Into<String>, bytes: &[u8]) -> Result<Self, WasmError> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).map_err(|e| WasmError::Invalid(e.to_string()))?;
+        let module =
+            Module::new(&engine, bytes).map_err(|e| WasmError::Invalid(format!("{e:#}")))?;
+        for (export, what) in [("memory", "a `memory`"), ("process", "a `process` function")] {
+            if module.get_export(export).is_none() {
+                return Err(WasmError::Invalid(format!("module does not export {what}")));
+            }
+        }
+        let pre = linker(&engine)
+            .and_then(|l| l.instantiate_pre(&module))
+            .map_err(|e| WasmError::Invalid(format!("{e:#}")))?;
+        Ok(Self {
+            name: name.into(),
+            engine,
+            pre,
+            fuel: DEFAULT_FUEL,
+            max_memory: DEFAULT_MAX_MEMORY,
+        })
+    }
+
+    /// Names the processor after the file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, WasmError> {
+        let path = path.as_ref();
+        Self::new(format!("wasm:{}", path.display()), &fs::read(path)?)
+    }
+
+    /// Instructions (roughly) each event may spend.
+    pub fn fuel(mut self, fuel: u64) -> Self {
+        self.fuel = fuel;
+        self
+    }
+
+    /// Bytes of linear memory an instance may hold.
+    pub fn max_memory(mut self, bytes: usize) -> Self {
+        self.max_memory = bytes;
+        self
+    }
+
+    fn run(&self, event: TelemetryEvent) -> Result<Guest, ProcessError> {
+        let fail = |msg: &str, e: wasmtime::Error| {
+            let msg = match e.downcast_ref::<Trap>() {
+                Some(Trap::OutOfFuel) => format!("wasm module `{}` ran out of fuel", self.name),
+                _ => format!("wasm module `{}` {msg}", self.name),
+            };
+            ProcessError::with_source(msg, e)
+        };
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(self.max_memory)
+            .table_elements(MAX_TABLE_ELEMENTS)
+            .instances(1)
+            .build();
+        let guest = Guest { event, dropped: false, failure: None, limits };
+        let mut store = Store::new(&self.engine, guest);
+        store.limiter(|g| &mut g.limits);
+        store.set_fuel(self.fuel).map_err(|e| fail("could not be fuelled", e))?;
+        let instance =
+            self.pre.instantiate(&mut store).map_err(|e| fail("failed to instantiate", e))?;
+        let process = instance
+            .get_typed_func::<(), i32>(&mut store, "process")
+            .map_err(|e| fail("has a mistyped `process`", e))?;
+        let status = process.call(&mut store, ()).map_err(|e| fail("trapped", e))?;
+        let guest = store.into_data();
+        if let Some(msg) = guest.failure {
+            return Err(ProcessError::new(msg));
+        }
+        if status != 0 {
+            return Err(ProcessError::new(format!(
+                "wasm module `{}` returned status {status}",
+                self.name
+            )));
+        }
+        Ok(guest)
+    }
+}
+
+impl Processor for WasmProcessor {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn process(&self, input: TelemetryEvent) -> Result<Output, ProcessError> {
+        let guest = self.run(input)?;
+        Ok(if guest.dropped { Output::Drop } else { Output::One(guest.event) })
+    }
+}
+
+fn memory(caller: &mut Caller<'_, Guest>) -> wasmtime::Result<Memory> {
+    match caller.get_export("memory") {
+        Some(Extern::Memory(m)) => Ok(m),
+        _ => Err(wasmtime::Error::msg("module does not export `memory`")),
+    }
+}
+
+/// Copies a guest buffer out, checking its bounds before allocating, so a
+/// bogus length cannot make the host reserve gigabytes.
+fn read(caller: &mut Caller<'_, Guest>, ptr: i32, len: i32) -> wasmtime::Result<Vec<u8>> {
+    let memory = memory(caller)?;
+    let start = ptr as u32 as usize;
+    let end = start.saturating_add(len as u32 as usize);
+    match memory.data(&caller).get(start..end) {
+        Some(bytes) => Ok(bytes.to_vec()),
+        None => Err(wasmtime::Error::msg("guest buffer is out of bounds")),
+    }
+}
+
+fn read_str(caller: &mut Caller<'_, Guest>, ptr: i32, len: i32) -> wasmtime::Result<String> {
+    Ok(String::from_utf8(read(caller, ptr, len)?)?)
+}
+
+/// Copies `bytes` to the guest if they fit in `cap`; returns their length.
+fn copy_out(
+    caller: &mut Caller<'_, Guest>,
+    bytes: &[u8],
+    buf: i32,
+    cap: i32,
+) -> wasmtime::Result<i32> {
+    let len = i32::try_from(bytes.len())?;
+    if len <= cap {
+        memory(caller)?.write(caller, buf as u32 as usize, bytes)?;
+    }
+    Ok(len)
+}
+
+fn linker(engine: &Engine) -> wasmtime::Result<Linker<Guest>> {
+    let mut l = Linker::new(engine);
+    l.func_wrap(IMPORT_MODULE, "get_kind", |mut c: Caller<'_, Guest>, buf: i32, cap: i32| {
+        let kind = c.data().event.kind.clone();
+        copy_out(&mut c, kind.as_bytes(), buf, cap)
+    })?;
+    l.func_wrap(IMPORT_MODULE, "set_kind", |mut c: Caller<'_, Guest>, ptr: i32, len: i32| {
+        c.data_mut().event.kind = read_str(&mut c, ptr, len)?;
+        Ok(())
+    })?;
+    l.func_wrap(
+        IMPORT_MODULE,
+        "get_field",
+        |mut c: Caller<'_, Guest>, key: i32, key_len: i32, buf: i32, cap: i32| {
+            let key = read_str(&mut c, key, key_len)?;
+            let Some(value) = c.data().event.get(&key) else { return Ok(-1) };
+            let json = serde_json::to_vec(value)?;
+            copy_out(&mut c, &json, buf, cap)
+        },
+    )?;
+    l.func_wrap(
+        IMPORT_MODULE,
+        "set_field",
+        |mut c: Caller<'_, Guest>, key: i32, key_len: i32, val: i32, val_len: i32| {
+            let key = read_str(&mut c, key, key_len)?;
+            let Ok(value) = serde_json::from_slice::<Value>(&read(&mut c, val, val_len)?) else {
+                return Ok(-1);
+            };
+            c.data_mut().event.insert(key, value);
+            Ok(0)
+        },
+    )?;
+    l.func_wrap(IMPORT_MODULE, "remove_field", |mut c: Caller<'_, Guest>, key: i32, len: i32| {
+        let key = read_str(&mut c, key, len)?;
+        Ok(c.data_mut().event.payload.remove(&key).is_some() as i32)
+    })?;
+    l.func_wrap(IMPORT_MODULE, "drop_event", |mut c: Caller<'_, Guest>| {
+        c.data_mut().dropped = true;
+    })?;
+    l.func_wrap(IMPORT_MODULE, "fail", |mut c: Caller<'_, Guest>, ptr: i32, len: i32| {
+        let msg = String::from_utf8_lossy(&read(&mut c, ptr, len)?).into_owned();
+        c.data_mut().failure = Some(msg);
+        Ok(())
+    })?;
+    Ok(l)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IMPORTS: &str = r#"
+        (import "telemetry" "get_kind" (func $get_kind (param i32 i32) (result i32)))
+        (import "telemetry" "set_kind" (func $set_kind (param i32 i32)))
+        (import "telemetry" "get_field" (func $get_field (param i32 i32 i32 i32) (result i32)))
+        (import "telemetry" "set_field" (func $set_field (param i32 i32 i32 i32) (result i32)))
+        (import "telemetry" "remove_field" (func $remove_field (param i32 i32) (result i32)))
+        (import "telemetry" "drop_event" (func $drop_event))
+        (import "telemetry" "fail" (func $fail (param i32 i32)))
+        (memory (export "memory") 1)
+    "#;
+
+    fn module(body: &str) -> WasmProcessor {
+        let wat = format!("(module {IMPORTS} {body})");
+        WasmProcessor::new("test", wat.as_bytes()).unwrap()
+    }
+
+    fn event() -> TelemetryEvent {
+        let mut evt = TelemetryEvent::new("login");
+        evt.insert("user", "ada");
+        evt.insert("secret", "hunter2");
+        evt
+    }
+
+    #[test]
+    fn reads_and_writes_fields() {
+        let p = module(
+            r#"
+            (data (i32.const 0) "user")
+            (data (i32.const 16) "owner")
+            (data (i32.const 32) "secret")
+            (data (i32.const 48) "audited")
+            (func (export "process") (result i32)
+                ;; owner = user, copied as JSON through offset 256
+                (call $set_field (i32.const 16) (i32.const 5) (i32.const 256)
+                    (call $get_field (i32.const 0) (i32.const 4) (i32.const 256) (i32.const 64)))
+                drop
+                (drop (call $remove_field (i32.const 32) (i32.const 6)))
+                ;; kind becomes itself with its first letter dropped
+                (call $set_kind (i32.const 513)
+                    (i32.sub (call $get_kind (i32.const 512) (i32.const 64)) (i32.const 1)))
+                (i32.const 0))
+            "#,
+        );
+        let Output::One(out) = p.process(event()).unwrap() else { panic!("expected one event") };
+        assert_eq!(out.kind, "ogin");
+        assert_eq!(out.get_str("owner"), Some("ada"));
+        assert_eq!(out.get("secret"), None);
+    }
+
+    #[test]
+    fn drop_and_fail() {
+        let dropper =
+            module(r#"(func (export "process") (result i32) (call $drop_event) (i32.const 0))"#);
+        assert!(matches!(dropper.process(event()), Ok(Output::Drop)));
+
+        let failer = module(
+            r#"(data (i32.const 0) "no thanks")
+               (func (export "process") (result i32) (call $fail (i32.const 0) (i32.const 9)) (i32.const 0))"#,
+        );
+        assert_eq!(failer.process(event()).err().unwrap().message(), "no thanks");
+
+        let status = module(r#"(func (export "process") (result i32) (i32.const 3))"#);
+        let err = status.process(event()).err().unwrap();
+        assert_eq!(err.message(), "wasm module `test` returned status 3");
+    }
+
+    #[test]
+    fn misbehaving_modules_are_contained() {
+        let spin =
+            module(r#"(func (export "process") (result i32) (loop $l (br $l)) (i32.const 0))"#)
+                .fuel(10_000);
+        assert_eq!(
+            spin.process(event()).err().unwrap().message(),
+            "wasm module `test` ran out of fuel"
+        );
+
+        let greedy = module(
+            r#"(func (export "process") (result i32)
+                 (if (i32.eq (memory.grow (i32.const 64)) (i32.const -1)) (then unreachable))
+                 (i32.const 0))"#,
+        );
+        assert!(greedy.process(event()).is_ok());
+        let greedy = greedy.max_memory(2 << 16);
+        let err = greedy.process(event()).err().unwrap();
+        assert_eq!(err.message(), "wasm module `test` trapped");
+
+        let oob = module(
+            r#"(func (export "process") (result i32) (call $set_kind (i32.const 65530) (i32.const 64)) (i32.const 0))"#,
+        );
+        assert!(oob.process(event()).is_err());
+        let huge = module(
+            r#"(func (export "process") (result i32) (call $set_kind (i32.const 0) (i32.const -1)) (i32.const 0))"#,
+        );
+        assert!(huge.process(event()).is_err());
+
+        let tables = module(
+            r#"(table 1 funcref)
+               (func (export "process") (result i32)
+                 (if (i32.eq (table.grow (ref.null func) (i32.const 1000000)) (i32.const -1))
+                   (then unreachable))
+                 (i32.const 0))"#,
+        );
+        assert!(tables.process(event()).is_err());
+
+        assert!(matches!(WasmProcessor::new("bad", b"(module)"), Err(WasmError::Invalid(_))));
+        let unknown = r#"(module (import "telemetry" "nope" (func)) (memory (export "memory") 1)
+                          (func (export "process") (result i32) (i32.const 0)))"#;
+        assert!(matches!(
+            WasmProcessor::new("bad", unknown.as_bytes()),
+            Err(WasmError::Invalid(_))
+        ));
+    }
+}